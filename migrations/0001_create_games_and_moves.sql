@@ -0,0 +1,18 @@
+create table if not exists games (
+    id blob primary key,
+    taken text,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+create table if not exists moves (
+    id blob primary key,
+    game_id blob not null,
+    from_column integer not null,
+    from_row integer not null,
+    to_column integer not null,
+    to_row integer not null,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,26 @@
+-- who is sitting where, how the game ended, and what it started from
+alter table games add column white_player text;
+alter table games add column black_player text;
+-- "1-0", "0-1" or "1/2-1/2"; null while the game is in progress
+alter table games add column result text;
+-- e.g. "checkmate", "resignation", "timeout"
+alter table games add column termination text;
+-- "<initial seconds>+<increment seconds>"; null means untimed
+alter table games add column time_control text;
+alter table games add column starting_fen text not null default 'rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1';
+
+-- lowercase piece letter ("q", "r", "b", "n") when the move is a promotion
+alter table moves add column promotion text;
+alter table moves add column move_number integer;
+
+-- number the moves that were stored before this column existed
+update moves set move_number = (
+    select (count(*) + 1) / 2
+    from moves as earlier
+    where earlier.game_id = moves.game_id
+    and (
+        earlier.inserted_at < moves.inserted_at
+        or (earlier.inserted_at = moves.inserted_at and earlier.rowid <= moves.rowid)
+    )
+);
+
@@ -0,0 +1,47 @@
+-- moves are replayed by ply instead of by insertion time, which only has
+-- one second resolution, and a game can never have two moves with the same ply.
+-- sqlite can't change a primary key in place, so the table is rebuilt.
+create table moves_by_ply (
+    id integer primary key,
+    game_id blob not null,
+    ply integer not null,
+    from_column integer not null,
+    from_row integer not null,
+    to_column integer not null,
+    to_row integer not null,
+    promotion text,
+    move_number integer not null,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(game_id) references games(id),
+    unique(game_id, ply)
+);
+
+insert into moves_by_ply (
+    game_id,
+    ply,
+    from_column,
+    from_row,
+    to_column,
+    to_row,
+    promotion,
+    move_number,
+    inserted_at,
+    updated_at
+)
+select
+    game_id,
+    row_number() over (partition by game_id order by inserted_at asc, rowid asc),
+    from_column,
+    from_row,
+    to_column,
+    to_row,
+    promotion,
+    move_number,
+    inserted_at,
+    updated_at
+from moves;
+
+drop table moves;
+
+alter table moves_by_ply rename to moves;
@@ -0,0 +1,3 @@
+-- how much time the mover had left after the move, in milliseconds.
+-- null for untimed games.
+alter table moves add column clock_ms integer;
@@ -0,0 +1,39 @@
+-- players who picked a name. the id is the same id as in the player cookie.
+create table users (
+    id text primary key,
+    name text not null unique collate nocase,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the current glicko-2 rating of a user in a time control category
+create table ratings (
+    user_id text not null,
+    category text not null,
+    rating real not null,
+    deviation real not null,
+    volatility real not null,
+    games integer not null default 0,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    primary key(user_id, category),
+    foreign key(user_id) references users(id)
+);
+
+-- every rating a user has had, one row per rated game
+create table rating_history (
+    id integer primary key,
+    user_id text not null,
+    category text not null,
+    game_id blob not null,
+    rating real not null,
+    deviation real not null,
+    volatility real not null,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(user_id) references users(id),
+    foreign key(game_id) references games(id)
+);
+
+create index rating_history_user_id on rating_history(user_id, category);
+
+alter table games add column rated boolean not null default false;
@@ -0,0 +1,3 @@
+-- the level of the built-in engine playing the seat that has no player,
+-- null when two people are playing
+alter table games add column computer text;
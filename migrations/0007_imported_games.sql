@@ -0,0 +1,4 @@
+-- the names of players in games imported from PGN,
+-- who aren't chez players and so have no row in users
+alter table games add column white_name text;
+alter table games add column black_name text;
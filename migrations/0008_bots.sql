@@ -0,0 +1,14 @@
+-- bots are users who play through the API with a token instead of a cookie
+alter table users add column bot boolean not null default false;
+
+-- only a hash of each token is kept
+create table api_tokens (
+    token_hash text primary key,
+    user_id text not null,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(user_id) references users(id)
+);
+
+-- the user a challenge was sent to, the only one who can take the open seat.
+-- null for challenges anyone with the link can take.
+alter table games add column challenged text;
@@ -0,0 +1,4 @@
+-- the rules each game is played by,
+-- and which of the 960 starting positions a Chess960 game started from
+alter table games add column variant text not null default 'standard';
+alter table games add column chess960_position integer;
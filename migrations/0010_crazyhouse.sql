@@ -0,0 +1 @@
+alter table moves add column dropped text;
@@ -0,0 +1,22 @@
+-- the lines a player tries out while analysing a finished game, kept apart
+-- from the moves that were actually played. each is a move from the position
+-- after its parent, or from the game's starting position if it has no parent.
+-- of the moves from the same position, the one with the lowest rank is the main line.
+create table variations (
+    id integer primary key,
+    game_id blob not null,
+    player_id text not null,
+    parent_id integer,
+    rank integer not null,
+    from_column integer not null,
+    from_row integer not null,
+    to_column integer not null,
+    to_row integer not null,
+    promotion text,
+    dropped text,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(game_id) references games(id),
+    foreign key(parent_id) references variations(id)
+);
+
+create index variations_game_id on variations(game_id, player_id);
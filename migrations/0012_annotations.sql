@@ -0,0 +1,12 @@
+-- notes on the moves of a game, like a coach's: a numeric annotation glyph
+-- (1 for "!", 2 for "?" and so on), a comment, or both
+create table annotations (
+    game_id blob not null,
+    ply integer not null,
+    nag integer,
+    comment text,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    primary key(game_id, ply),
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,16 @@
+-- what the engine made of every position of a finished game, from the
+-- starting position at ply 0 to the final one. a game's evaluations are
+-- written all at once, so a game either has all of them or none.
+create table evaluations (
+    game_id blob not null,
+    ply integer not null,
+    -- from white's point of view, forced mates counting as a big advantage
+    centipawns integer not null,
+    -- moves to a forced mate, negative when black mates, 0 when the game is over
+    mate integer,
+    -- the engine's choice, in UCI notation, null once there are no moves left
+    best_move text,
+    inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    primary key(game_id, ply),
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,7 @@
+-- games imported from PGN are records of games played elsewhere,
+-- with no seats anyone can take even when they have no result.
+-- every game created on chez has at least one player sitting in it.
+alter table games add column imported boolean not null default false;
+
+update games set imported = true
+where white_player is null and black_player is null;
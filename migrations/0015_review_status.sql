@@ -0,0 +1,11 @@
+-- where the review of a finished game is up to, until its evaluations are written.
+-- a game with evaluations has no row here.
+create table reviews (
+    game_id blob primary key not null,
+    -- 'pending' while it waits for or is being gone over by the engine,
+    -- 'failed' if that didn't work out, which isn't tried again on its own
+    status text not null,
+    error text,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    foreign key(game_id) references games(id)
+);
@@ -0,0 +1,581 @@
+use crate::board::{Board, Move};
+use crate::game::GameState;
+use crate::pgn;
+use crate::piece::{Color, Piece, PieceKind, Position};
+use crate::player::PlayerId;
+use crate::uci::{self, Limit};
+use crate::variant::Variant;
+use crate::variation::{AnalysisPgn, Token, Tree};
+use crate::{
+    AppError, AppState, BadRequest, SquareColor, background_color, board_grid, hx_location, layout,
+    linked_square, read_only_square,
+};
+use axum::Extension;
+use axum::extract::{Form, Path, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use maud::{Markup, html};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct AnalysisParams {
+    /// the id of the move to look at, 0 for the starting position
+    /// and the end of the main line if missing
+    node: Option<i64>,
+    /// the square of the piece picked up to try a move with, e.g. "e2"
+    from: Option<String>,
+    /// where a pawn picked up is going to promote, to pick what it becomes
+    to: Option<String>,
+    /// the letter of the piece picked out of the pocket to drop, e.g. "n"
+    drop: Option<String>,
+}
+
+/// a finished game with the lines a player has tried out from it,
+/// at one of their moves, with what the engine thinks of it
+pub async fn analysis(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Query(params): Query<AnalysisParams>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Markup, AppError> {
+    let mut state = state.lock().await;
+
+    let has_engine = state.engine.is_some();
+
+    let lines = Lines::load(&mut state, game_id, player, params.node).await?;
+
+    let orientation = state
+        .game(game_id)
+        .await?
+        .seat(player)
+        .unwrap_or(Color::White);
+
+    let line = lines.tree.line(lines.node);
+
+    let (board, to_move) = replay(lines.variant, &lines.starting_fen, &line)?;
+
+    let tokens = lines.tree.tokens(lines.variant, &lines.starting_fen)?;
+
+    let node_param = lines.node.unwrap_or(0);
+
+    let href = |node: Option<i64>| format!("/games/{game_id}/analysis?node={}", node.unwrap_or(0));
+
+    // the moves the piece picked up, or picked out of the pocket, can make
+    let from = params.from.as_deref().and_then(Position::parse);
+
+    let drop = params
+        .drop
+        .as_deref()
+        .and_then(|letter| letter.chars().next())
+        .and_then(PieceKind::parse);
+
+    let targets: Vec<Move> = board
+        .all_legal_moves(to_move)
+        .into_iter()
+        .filter(|m| match drop {
+            Some(kind) => m.drop == Some(kind),
+            None => Some(m.from) == from && m.drop.is_none(),
+        })
+        .collect();
+
+    // a pawn on its way to the last row waits for what it becomes to be picked
+    let promoting_to = params.to.as_deref().and_then(Position::parse);
+
+    let promotions: Vec<Move> = targets
+        .iter()
+        .filter(|m| Some(m.to) == promoting_to && m.promotion.is_some())
+        .copied()
+        .collect();
+
+    let mut pocket = board.pocket(to_move).to_vec();
+    pocket.sort_by_key(|kind| kind.letter());
+    pocket.dedup();
+
+    let picked = |from: Position| format!("{}&from={}", href(lines.node), from.name());
+
+    let last_move = line.last().copied();
+
+    let parent = lines
+        .node
+        .and_then(|id| lines.tree.get(id))
+        .map(|node| node.parent);
+
+    let next = lines.tree.children(lines.node).first().map(|node| node.id);
+
+    Ok(layout! {
+        html! {
+            div class="p-4 flex justify-between" {
+                a href=(format!("/games/{game_id}/play")) class="underline" { "Back to the game" }
+                a href=(format!("/games/{game_id}/analysis/pgn")) class="underline" { "Download PGN" }
+            }
+            (board_grid(&board, orientation, |position, color, body| {
+                if drop.is_none() && from == Some(*position) {
+                    linked_square(&href(lines.node), position, SquareColor::Highlighted, body)
+                } else if let Some(m) = targets.iter().find(|m| m.to == *position) {
+                    // promoting takes another click, to pick the piece
+                    if let Some(from) = from.filter(|_| m.promotion.is_some()) {
+                        let color = if promoting_to == Some(*position) { SquareColor::Highlighted } else { color };
+
+                        return linked_square(
+                            &format!("{}&to={}", picked(from), position.name()),
+                            position,
+                            color,
+                            body,
+                        );
+                    }
+
+                    html! {
+                        button
+                            id=(format!("square-{}{}", position.column, position.row))
+                            class=(background_color(color))
+                            hx-post=(format!("/games/{game_id}/analysis/moves"))
+                            hx-vals=(format!(r#"{{"node": {node_param}, "move": "{}"}}"#, m.uci()))
+                        {
+                            (body)
+                        }
+                    }
+                } else {
+                    let played = from.is_none()
+                        && last_move.is_some_and(|m| m.from == *position || m.to == *position);
+
+                    let color = if played { SquareColor::Highlighted } else { color };
+
+                    if board.get_piece(position).is_some_and(|piece| piece.color == to_move) {
+                        linked_square(&picked(*position), position, color, body)
+                    } else {
+                        read_only_square(position, color, body)
+                    }
+                }
+            }))
+            @if !promotions.is_empty() {
+                div class="p-4 flex gap-4" {
+                    "Promote to:"
+                    @for m in &promotions {
+                        @if let Some(kind) = m.promotion {
+                            button
+                                class="underline"
+                                hx-post=(format!("/games/{game_id}/analysis/moves"))
+                                hx-vals=(format!(r#"{{"node": {node_param}, "move": "{}"}}"#, m.uci()))
+                            {
+                                (Piece::new(kind, to_move, m.to).repr())
+                            }
+                        }
+                    }
+                }
+            }
+            @if lines.variant.has_pockets() {
+                div class="p-4 flex gap-2" {
+                    "Pocket:"
+                    @for kind in &pocket {
+                        @let glyph = Piece::new(*kind, to_move, Position::new(0, 0)).repr().to_string();
+                        @if drop == Some(*kind) {
+                            a href=(href(lines.node)) class="bg-yellow-200" { (glyph) }
+                        } @else {
+                            a href=(format!("{}&drop={}", href(lines.node), kind.letter())) { (glyph) }
+                        }
+                    }
+                }
+            }
+            div class="p-4 flex gap-4" {
+                @if let Some(parent) = parent {
+                    a href=(href(parent)) class="underline" { "Previous" }
+                }
+                @if let Some(next) = next {
+                    a href=(href(Some(next))) class="underline" { "Next" }
+                }
+                @if let Some(node) = lines.node && !lines.tree.is_main_line(node) {
+                    button
+                        class="underline"
+                        hx-post=(format!("/games/{game_id}/analysis/promote"))
+                        hx-vals=(format!(r#"{{"node": {node}}}"#))
+                    {
+                        "Make main line"
+                    }
+                }
+                span { (match to_move {
+                    Color::White => "white to move",
+                    Color::Black => "black to move",
+                }) }
+            }
+            div class="p-4 flex flex-wrap gap-x-1" {
+                a href=(href(None)) class=(if lines.node.is_none() { "px-1 bg-yellow-200" } else { "px-1 underline" }) {
+                    "Start"
+                }
+                (move_list(&mut tokens.into_iter(), &href, lines.node))
+            }
+            div class="p-4" {
+                @if has_engine {
+                    div
+                        hx-get=(format!("/games/{game_id}/analysis/engine?node={node_param}"))
+                        hx-trigger="load"
+                        hx-swap="outerHTML"
+                    {
+                        "The engine is thinking…"
+                    }
+                } @else {
+                    "There is no engine to analyse with."
+                }
+                p class="text-sm" { (board.fen(to_move)) }
+            }
+        }
+    })
+}
+
+/// the moves up to the end of the sideline they start in,
+/// with the sidelines inside them indented under the move they replace
+fn move_list(
+    tokens: &mut impl Iterator<Item = Token>,
+    href: &impl Fn(Option<i64>) -> String,
+    current: Option<i64>,
+) -> Markup {
+    let mut items = vec![];
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Move { id, number, san } => items.push(html! {
+                @if let Some(number) = number {
+                    span { (number) }
+                }
+                a
+                    href=(href(Some(id)))
+                    class=(if current == Some(id) { "px-1 bg-yellow-200" } else { "px-1 underline" })
+                {
+                    (san)
+                }
+            }),
+            Token::Open => items.push(html! {
+                div class="basis-full pl-4 text-sm flex flex-wrap gap-x-1" {
+                    "(" (move_list(tokens, href, current)) ")"
+                }
+            }),
+            Token::Close => break,
+        }
+    }
+
+    html! {
+        @for item in items {
+            (item)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnalysisMoveParams {
+    /// the id of the move this one answers, 0 for the starting position
+    node: i64,
+    /// in UCI notation
+    #[serde(rename = "move")]
+    m: String,
+}
+
+/// try a move out, going on to the position after it
+pub async fn analysis_move(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<AnalysisMoveParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let lines = Lines::load(&mut state, game_id, player, Some(params.node)).await?;
+
+    let (board, to_move) = replay(
+        lines.variant,
+        &lines.starting_fen,
+        &lines.tree.line(lines.node),
+    )?;
+
+    let m = Move::parse_uci(params.m.trim())
+        .filter(|m| board.all_legal_moves(to_move).contains(m))
+        .ok_or_else(|| BadRequest(format!("{} isn't a legal move here", params.m)))?;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let id = lines
+        .tree
+        .add(&mut conn, game_id, player, lines.node, &m)
+        .await?;
+
+    Ok(hx_location(&format!("/games/{game_id}/analysis?node={id}")))
+}
+
+#[derive(Deserialize)]
+pub struct PromoteParams {
+    node: i64,
+}
+
+/// make the line up to a move the main line
+pub async fn analysis_promote(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<PromoteParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let lines = Lines::load(&mut state, game_id, player, Some(params.node)).await?;
+
+    let mut conn = state.pool.acquire().await?;
+
+    if let Some(node) = lines.node {
+        lines.tree.promote(&mut conn, game_id, player, node).await?;
+    }
+
+    Ok(hx_location(&format!(
+        "/games/{game_id}/analysis?node={}",
+        params.node
+    )))
+}
+
+/// the game as PGN with the lines a player has tried out from it,
+/// each sideline in parentheses after the move it replaces
+pub async fn analysis_pgn(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let lines = Lines::load(&mut state, game_id, player, None).await?;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let pgn = pgn::export(&mut conn, game_id).await?;
+
+    let movetext = lines
+        .tree
+        .movetext(lines.variant, &lines.starting_fen, &pgn.result)?;
+
+    Ok((
+        [(CONTENT_TYPE, "application/x-chess-pgn; charset=utf-8")],
+        AnalysisPgn { pgn, movetext }.to_string(),
+    ))
+}
+
+/// what the engine thinks of the position, loaded separately
+/// because the engine takes a while
+pub async fn analysis_engine(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Query(params): Query<AnalysisParams>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Markup, AppError> {
+    // the engine is asked without holding on to the state
+    let (engine, lines) = {
+        let mut state = state.lock().await;
+
+        let engine = state
+            .engine
+            .clone()
+            .ok_or_else(|| BadRequest("there is no engine to analyse with".into()))?;
+
+        let lines = Lines::load(&mut state, game_id, player, params.node).await?;
+
+        (engine, lines)
+    };
+
+    let moves = lines.tree.line(lines.node);
+
+    let (_, to_move) = replay(lines.variant, &lines.starting_fen, &moves)?;
+
+    let analysis = uci::search(
+        &engine,
+        uci::Position {
+            starting_fen: &lines.starting_fen,
+            moves: &moves,
+            variant: lines.variant,
+        },
+        Limit::MoveTime(uci::MOVE_TIME),
+        &[],
+    )
+    .await?;
+
+    Ok(engine_view(&analysis, to_move))
+}
+
+/// the lines a player has tried out from a finished game, and the move they are at
+struct Lines {
+    variant: Variant,
+    starting_fen: String,
+    tree: Tree,
+    /// `None` for the starting position
+    node: Option<i64>,
+}
+
+impl Lines {
+    /// the lines, at move `node`: 0 for the starting position,
+    /// and the end of the main line if missing
+    async fn load(
+        state: &mut AppState,
+        game_id: Uuid,
+        player: PlayerId,
+        node: Option<i64>,
+    ) -> Result<Self, AppError> {
+        let mut conn = state.pool.acquire().await?;
+
+        let game_state = state.game(game_id).await?;
+
+        let (starting_fen, moves) = finished_moves(game_id, game_state)?;
+
+        let variant = game_state.variant;
+
+        let tree = Tree::load(&mut conn, game_id, player, &moves).await?;
+
+        let node = match node {
+            None => tree.main_line_end(),
+            Some(0) => None,
+            Some(id) => Some(
+                tree.get(id)
+                    .ok_or_else(|| BadRequest(format!("there is no move {id} in this analysis")))?
+                    .id,
+            ),
+        };
+
+        Ok(Self {
+            variant,
+            starting_fen,
+            tree,
+            node,
+        })
+    }
+}
+
+/// a position on its own, like one set up in the board editor
+#[derive(Deserialize)]
+pub struct PositionParams {
+    fen: String,
+}
+
+/// a position that isn't from any game, with what the engine thinks of it
+pub async fn position_analysis(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<PositionParams>,
+) -> Result<Markup, AppError> {
+    let has_engine = state.lock().await.engine.is_some();
+
+    let fen = params.fen.trim();
+
+    let (board, to_move) =
+        Board::from_fen(fen).ok_or_else(|| BadRequest(format!("{fen} isn't a FEN")))?;
+
+    let fen_param = fen.replace(' ', "+");
+
+    Ok(layout! {
+        html! {
+            div class="p-4 flex justify-between" {
+                a href=(format!("/editor?fen={fen_param}")) class="underline" { "Back to the editor" }
+                span { (match to_move {
+                    Color::White => "White to move",
+                    Color::Black => "Black to move",
+                }) }
+            }
+            (crate::board_grid(&board, Color::White, crate::read_only_square))
+            div class="p-4" {
+                @if has_engine {
+                    div
+                        hx-get=(format!("/analysis/engine?fen={fen_param}"))
+                        hx-trigger="load"
+                        hx-swap="outerHTML"
+                    {
+                        "The engine is thinking…"
+                    }
+                } @else {
+                    "There is no engine to analyse with."
+                }
+                p class="text-sm" { (fen) }
+            }
+        }
+    })
+}
+
+/// what the engine thinks of a position that isn't from any game
+pub async fn position_analysis_engine(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<PositionParams>,
+) -> Result<Markup, AppError> {
+    let engine = state
+        .lock()
+        .await
+        .engine
+        .clone()
+        .ok_or_else(|| BadRequest("there is no engine to analyse with".into()))?;
+
+    let fen = params.fen.trim();
+
+    let (_, to_move) =
+        Board::from_fen(fen).ok_or_else(|| BadRequest(format!("{fen} isn't a FEN")))?;
+
+    let analysis = uci::search(
+        &engine,
+        uci::Position {
+            starting_fen: fen,
+            moves: &[],
+            variant: Variant::Standard,
+        },
+        Limit::MoveTime(uci::MOVE_TIME),
+        &[],
+    )
+    .await?;
+
+    Ok(engine_view(&analysis, to_move))
+}
+
+/// the engine's score, best move and the line it expects
+fn engine_view(analysis: &uci::Analysis, to_move: Color) -> Markup {
+    html! {
+        div {
+            @if let Some(score) = analysis.score {
+                p class="text-xl" {
+                    (score.label(to_move))
+                    @if let Some(depth) = analysis.depth {
+                        span class="text-sm" { " depth " (depth) }
+                    }
+                }
+            }
+            @if let Some(best_move) = analysis.best_move {
+                p { "Best move " (best_move.uci()) }
+            } @else {
+                p { "No moves left" }
+            }
+            @if !analysis.pv.is_empty() {
+                p {
+                    @for m in &analysis.pv {
+                        (m.uci()) " "
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// the moves of a game that is over.
+/// games still being played aren't analysed, so nobody gets help with them.
+fn finished_moves(game_id: Uuid, game_state: &GameState) -> Result<(String, Vec<Move>), AppError> {
+    if game_state.result.is_none() {
+        return Err(BadRequest(format!("game {game_id} can be analysed once it is over")).into());
+    }
+
+    Ok((game_state.starting_fen.clone(), game_state.moves.clone()))
+}
+
+/// the board after `moves`, and whose move it is
+pub fn replay(
+    variant: Variant,
+    starting_fen: &str,
+    moves: &[Move],
+) -> anyhow::Result<(Board, Color)> {
+    let (mut board, mut to_move) = Board::from_fen(starting_fen)
+        .ok_or_else(|| anyhow::anyhow!("bad starting position {starting_fen}"))?;
+
+    board.variant = variant;
+
+    for m in moves {
+        board.make_move(m);
+        to_move = to_move.invert();
+    }
+
+    Ok((board, to_move))
+}
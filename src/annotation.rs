@@ -0,0 +1,281 @@
+use crate::game::GameState;
+use crate::piece::Color;
+use crate::player::PlayerId;
+use crate::{AppError, AppState, BadRequest, hx_location};
+use axum::Extension;
+use axum::extract::{Form, Path, State};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// a numeric annotation glyph, PGN's way of saying what a move was like
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Nag {
+    Good,
+    Mistake,
+    Brilliant,
+    Blunder,
+    Interesting,
+    Dubious,
+}
+
+impl Nag {
+    pub const ALL: [Nag; 6] = [
+        Nag::Good,
+        Nag::Mistake,
+        Nag::Brilliant,
+        Nag::Blunder,
+        Nag::Interesting,
+        Nag::Dubious,
+    ];
+
+    /// the glyph's number, as it is stored in the db and written in PGN after a "$"
+    pub fn number(&self) -> u8 {
+        match self {
+            Nag::Good => 1,
+            Nag::Mistake => 2,
+            Nag::Brilliant => 3,
+            Nag::Blunder => 4,
+            Nag::Interesting => 5,
+            Nag::Dubious => 6,
+        }
+    }
+
+    pub fn from_number(number: u8) -> Option<Self> {
+        Nag::ALL.into_iter().find(|nag| nag.number() == number)
+    }
+
+    /// the glyph as it goes after a move, "!" for a good one
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Nag::Good => "!",
+            Nag::Mistake => "?",
+            Nag::Brilliant => "!!",
+            Nag::Blunder => "??",
+            Nag::Interesting => "!?",
+            Nag::Dubious => "?!",
+        }
+    }
+
+    pub fn parse(nag: &str) -> Option<Self> {
+        Nag::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == nag)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Nag::Good => "Good move",
+            Nag::Mistake => "Mistake",
+            Nag::Brilliant => "Brilliant move",
+            Nag::Blunder => "Blunder",
+            Nag::Interesting => "Interesting move",
+            Nag::Dubious => "Dubious move",
+        }
+    }
+}
+
+/// what someone had to say about a move
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Annotation {
+    pub nag: Option<Nag>,
+    pub comment: Option<String>,
+}
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        self.nag.is_none() && self.comment.is_none()
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AnnotationRow {
+    ply: i64,
+    nag: Option<u8>,
+    comment: Option<String>,
+}
+
+/// the annotations of a game, by the ply of the move they are on
+pub async fn load(
+    conn: &mut SqliteConnection,
+    game_id: Uuid,
+) -> anyhow::Result<BTreeMap<i64, Annotation>> {
+    let rows: Vec<AnnotationRow> = sqlx::query_as(
+        "
+    select ply, nag, comment
+    from annotations
+    where game_id = ?;
+    ",
+    )
+    .bind(game_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.ply,
+                Annotation {
+                    nag: row.nag.and_then(Nag::from_number),
+                    comment: row.comment,
+                },
+            )
+        })
+        .collect())
+}
+
+/// annotate the move at `ply`, replacing whatever was there.
+/// an empty annotation takes it off.
+pub async fn save(
+    conn: &mut SqliteConnection,
+    game_id: Uuid,
+    ply: i64,
+    annotation: &Annotation,
+) -> anyhow::Result<()> {
+    if annotation.is_empty() {
+        sqlx::query("delete from annotations where game_id = ? and ply = ?;")
+            .bind(game_id)
+            .bind(ply)
+            .execute(&mut *conn)
+            .await?;
+    } else {
+        sqlx::query(
+            "
+    insert into annotations (game_id, ply, nag, comment)
+    values (?, ?, ?, ?)
+    on conflict (game_id, ply) do update set
+        nag = excluded.nag,
+        comment = excluded.comment,
+        updated_at = CURRENT_TIMESTAMP;
+    ",
+        )
+        .bind(game_id)
+        .bind(ply)
+        .bind(annotation.nag.map(|nag| nag.number()))
+        .bind(&annotation.comment)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// whether `player` can annotate the game: once it is over, its players can,
+/// and anyone can annotate games nobody played here, like imported ones
+pub fn can_annotate(game_state: &GameState, player: PlayerId) -> bool {
+    game_state.result.is_some()
+        && (game_state.seat(player).is_some()
+            || (!game_state.is_seated(Color::White) && !game_state.is_seated(Color::Black)))
+}
+
+#[derive(Deserialize)]
+pub struct AnnotateParams {
+    ply: i64,
+    /// the glyph, "!" or "??" and so on, empty for none
+    nag: String,
+    comment: String,
+}
+
+/// annotate a move of a finished game, going back to it in the replay
+pub async fn annotate(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<AnnotateParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let game_state = state.game(game_id).await?;
+
+    if !can_annotate(game_state, player) {
+        return Err(
+            BadRequest("only the players can annotate a game, once it is over".into()).into(),
+        );
+    }
+
+    if params.ply < 1 || params.ply > game_state.moves.len() as i64 {
+        return Err(BadRequest(format!("there is no move {} in this game", params.ply)).into());
+    }
+
+    let nag = match params.nag.trim() {
+        "" => None,
+        nag => {
+            Some(Nag::parse(nag).ok_or_else(|| BadRequest(format!("{nag} isn't an annotation")))?)
+        }
+    };
+
+    let comment = params.comment.trim();
+
+    let annotation = Annotation {
+        nag,
+        comment: (!comment.is_empty()).then(|| comment.to_string()),
+    };
+
+    save(&mut conn, game_id, params.ply, &annotation).await?;
+
+    Ok(hx_location(&format!(
+        "/games/{game_id}/replay?ply={}",
+        params.ply
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, SqliteConnection};
+
+    #[test]
+    fn glyphs_read_back_from_their_numbers_and_symbols() {
+        for nag in Nag::ALL {
+            assert_eq!(Nag::from_number(nag.number()), Some(nag));
+            assert_eq!(Nag::parse(nag.as_str()), Some(nag));
+        }
+
+        assert_eq!(Nag::from_number(0), None);
+        assert_eq!(Nag::parse("!!!"), None);
+    }
+
+    #[tokio::test]
+    async fn annotations_are_replaced_and_taken_off() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&mut conn).await.unwrap();
+
+        let game_id = Uuid::new_v4();
+
+        sqlx::query("insert into games (id) values (?);")
+            .bind(game_id)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let annotation = Annotation {
+            nag: Some(Nag::Blunder),
+            comment: Some("hangs the queen".into()),
+        };
+
+        save(&mut conn, game_id, 3, &annotation).await.unwrap();
+        save(&mut conn, game_id, 5, &annotation).await.unwrap();
+
+        let better = Annotation {
+            nag: Some(Nag::Mistake),
+            comment: None,
+        };
+
+        save(&mut conn, game_id, 3, &better).await.unwrap();
+        save(&mut conn, game_id, 5, &Annotation::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            load(&mut conn, game_id).await.unwrap(),
+            BTreeMap::from([(3, better)])
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use crate::board::Board;
+use crate::game::{GameState, Outcome};
+use crate::pgn::{self, Pgn};
+use crate::piece::{Color, PieceKind};
+use crate::player::PlayerId;
+use crate::variant::Variant;
+use crate::{AppError, AppState, GamesCreateParams, MoveParams};
+use axum::extract::{Path, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// a game as the API has it
+#[derive(Serialize)]
+pub struct Game {
+    id: Uuid,
+    variant: Variant,
+    /// which of the 960 starting positions a Chess960 game started from
+    chess960_position: Option<u16>,
+    starting_fen: String,
+    fen: String,
+    to_move: Color,
+    /// every move so far, in UCI notation
+    moves: Vec<String>,
+    /// the same moves in standard algebraic notation
+    san: Vec<String>,
+    white: Seat,
+    black: Seat,
+    rated: bool,
+    /// how many times each side has given check, in variants that count them
+    checks: Option<Checks>,
+    /// the pieces each side can drop, in variants with pockets
+    pockets: Option<Pockets>,
+    clock: Option<ClockState>,
+    /// `None` while the game is in progress
+    result: Option<ResultState>,
+    /// the seat of whoever asked, if they are playing
+    you: Option<Color>,
+    board: Board,
+}
+
+#[derive(Serialize)]
+struct Seat {
+    /// whether anyone, or the computer, is sitting here
+    seated: bool,
+    name: Option<String>,
+    rating: Option<f64>,
+    /// the level of the computer, if it is playing this seat
+    computer: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    white: u8,
+    black: u8,
+}
+
+#[derive(Serialize)]
+struct Pockets {
+    white: Vec<PieceKind>,
+    black: Vec<PieceKind>,
+}
+
+#[derive(Serialize)]
+struct ClockState {
+    initial_ms: u128,
+    increment_ms: u128,
+    white_ms: u128,
+    black_ms: u128,
+    running: Option<Color>,
+}
+
+#[derive(Serialize)]
+struct ResultState {
+    /// "1-0", "0-1" or "1/2-1/2"
+    result: &'static str,
+    winner: Option<Color>,
+    termination: &'static str,
+    description: String,
+}
+
+impl Game {
+    pub fn new(game_id: Uuid, game_state: &GameState, player: PlayerId) -> Self {
+        let seat = |color: Color| {
+            let profile = game_state.profile(color);
+
+            let computer = game_state
+                .computer
+                .filter(|computer| computer.color == color)
+                .map(|computer| computer.engine.as_str());
+
+            Seat {
+                seated: game_state.is_seated(color),
+                name: profile.name.clone(),
+                rating: profile.rating.map(|rating| rating.rating),
+                computer,
+            }
+        };
+
+        Self {
+            id: game_id,
+            variant: game_state.variant,
+            chess960_position: game_state.chess960_position,
+            starting_fen: game_state.starting_fen.clone(),
+            fen: game_state.board.fen(game_state.to_move),
+            to_move: game_state.to_move,
+            moves: game_state.moves.iter().map(|m| m.uci()).collect(),
+            san: Pgn::of(game_state).moves,
+            white: seat(Color::White),
+            black: seat(Color::Black),
+            rated: game_state.rated,
+            checks: game_state.variant.counts_checks().then(|| Checks {
+                white: game_state.board.checks(Color::White),
+                black: game_state.board.checks(Color::Black),
+            }),
+            pockets: game_state.variant.has_pockets().then(|| Pockets {
+                white: game_state.board.pocket(Color::White).to_vec(),
+                black: game_state.board.pocket(Color::Black).to_vec(),
+            }),
+            clock: game_state.clock.as_ref().map(|clock| ClockState {
+                initial_ms: clock.initial.as_millis(),
+                increment_ms: clock.increment.as_millis(),
+                white_ms: clock.remaining(Color::White).as_millis(),
+                black_ms: clock.remaining(Color::Black).as_millis(),
+                running: clock.running(),
+            }),
+            result: game_state.result.map(|result| ResultState {
+                result: result.outcome.as_str(),
+                winner: match result.outcome {
+                    Outcome::Win(color) => Some(color),
+                    Outcome::Draw => None,
+                },
+                termination: result.termination.as_str(),
+                description: result.description(),
+            }),
+            you: game_state.seat(player),
+            board: game_state.board.clone(),
+        }
+    }
+}
+
+/// create a game, the same as the new game form
+pub async fn games_create(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+    Json(params): Json<GamesCreateParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let shared_state = Arc::clone(&state);
+
+    let mut state = state.lock().await;
+
+    let game_id = crate::create_game(&shared_state, &mut state, player, &params).await?;
+
+    let game_state = state.game(game_id).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Game::new(game_id, game_state, player)),
+    ))
+}
+
+pub async fn games_show(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Json<Game>, AppError> {
+    let mut state = state.lock().await;
+
+    let game_state = state.game(game_id).await?;
+
+    Ok(Json(Game::new(game_id, game_state, player)))
+}
+
+/// play a move, returning the game after it
+pub async fn moves_create(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Json(params): Json<MoveParams>,
+) -> Result<Json<Game>, AppError> {
+    let shared_state = Arc::clone(&state);
+
+    let mut state = state.lock().await;
+
+    crate::submit_move(&shared_state, &mut state, game_id, player, &params.m).await?;
+
+    let game_state = state.game(game_id).await?;
+
+    Ok(Json(Game::new(game_id, game_state, player)))
+}
+
+#[derive(Serialize)]
+pub struct LegalMove {
+    uci: String,
+    san: String,
+}
+
+/// the moves the player to move can make, none once the game is over
+pub async fn moves_legal(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+) -> Result<Json<Vec<LegalMove>>, AppError> {
+    let mut state = state.lock().await;
+
+    let game_state = state.game(game_id).await?;
+
+    if game_state.result.is_some() {
+        return Ok(Json(vec![]));
+    }
+
+    let moves = game_state
+        .board
+        .all_legal_moves(game_state.to_move)
+        .iter()
+        .map(|m| LegalMove {
+            uci: m.uci(),
+            san: pgn::san(&game_state.board, game_state.to_move, m),
+        })
+        .collect();
+
+    Ok(Json(moves))
+}
+
+/// turn the plain text errors the handlers share with the html side
+/// into `{"error": ...}`, so API clients always get json back
+pub async fn json_errors(response: Response) -> Response {
+    let is_error = response.status().is_client_error() || response.status().is_server_error();
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+
+    if !is_error || is_json {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+
+    let message = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+        _ => parts
+            .status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_string(),
+    };
+
+    (parts.status, Json(json!({ "error": message }))).into_response()
+}
+
+pub async fn not_found() -> StatusCode {
+    StatusCode::NOT_FOUND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppError;
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_come_back_as_json() {
+        let error = AppError::from(crate::BadRequest("that isn't your move".into()));
+
+        let response = json_errors(error.into_response()).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body(response).await,
+            json!({ "error": "that isn't your move" })
+        );
+
+        let response = json_errors(not_found().await.into_response()).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(response).await, json!({ "error": "Not Found" }));
+    }
+}
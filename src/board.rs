@@ -1,11 +1,165 @@
+use serde::Serialize;
 use std::collections::HashSet;
+use std::fmt;
 
 use crate::piece::Color::{self, Black, White};
 use crate::piece::PieceKind::{self, *};
 use crate::piece::{Piece, Position};
+use crate::variant::Variant;
 
+/// what a pawn reaching the last row can become, the usual choice first
+pub const PROMOTIONS: [PieceKind; 4] = [Queen, Rook, Bishop, Knight];
+
+/// the standard starting position
+pub const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// a move from one square to another,
+/// or in Crazyhouse a piece from the pocket put on a square
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move {
+    /// the same as `to` for drops
+    pub from: Position,
+    pub to: Position,
+    /// what a pawn reaching the last row became
+    pub promotion: Option<PieceKind>,
+    /// the kind of piece dropped from the pocket
+    pub drop: Option<PieceKind>,
+}
+
+impl Move {
+    /// `kind` from the pocket, put on `to`
+    pub fn drop(kind: PieceKind, to: Position) -> Self {
+        Self {
+            from: to,
+            to,
+            promotion: None,
+            drop: Some(kind),
+        }
+    }
+
+    /// the move in the long algebraic notation UCI uses, e.g. "e2e4", "e7e8q" or "N@f3"
+    pub fn uci(&self) -> String {
+        if let Some(kind) = self.drop {
+            return format!("{}@{}", kind.letter().to_ascii_uppercase(), self.to.name());
+        }
+
+        let mut uci = format!("{}{}", self.from.name(), self.to.name());
+
+        if let Some(promotion) = self.promotion {
+            uci.push(promotion.letter());
+        }
+
+        uci
+    }
+
+    pub fn parse_uci(uci: &str) -> Option<Self> {
+        if let Some((kind, to)) = uci.split_once('@') {
+            let kind = match kind {
+                // pawns can be dropped without saying so
+                "" => Pawn,
+                kind => PieceKind::parse(kind.chars().next()?).filter(|kind| *kind != King)?,
+            };
+
+            return Some(Self::drop(kind, Position::parse(to)?));
+        }
+
+        let from = Position::parse(uci.get(0..2)?)?;
+        let to = Position::parse(uci.get(2..4)?)?;
+
+        let promotion = match uci.get(4..)? {
+            "" => None,
+            promotion => Some(
+                promotion
+                    .chars()
+                    .next()
+                    .and_then(PieceKind::parse)
+                    // kings only in variants where they aren't royal, which the board checks
+                    .filter(|kind| *kind != Pawn)?,
+            ),
+        };
+
+        Some(Self {
+            from,
+            to,
+            promotion,
+            drop: None,
+        })
+    }
+}
+
+/// how pieces are drawn in a terminal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Glyphs {
+    /// the chess symbols the web board uses
+    #[default]
+    Unicode,
+    /// FEN letters, for terminals without the chess symbols
+    Ascii,
+}
+
+/// a board drawn as text, with ranks down the side and files along the bottom
+pub struct Diagram<'a> {
+    board: &'a Board,
+    glyphs: Glyphs,
+    /// whose side of the board is at the bottom
+    from: Color,
+}
+
+impl fmt::Display for Diagram<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (rows, columns): (Vec<i8>, Vec<i8>) = match self.from {
+            White => ((0..8).rev().collect(), (0..8).collect()),
+            Black => ((0..8).collect(), (0..8).rev().collect()),
+        };
+
+        for row in rows {
+            write!(f, "{}", row + 1)?;
+
+            for column in &columns {
+                let square = match self.board.get_piece(&(*column, row).into()) {
+                    Some(piece) => match self.glyphs {
+                        Glyphs::Unicode => piece.repr().to_string(),
+                        Glyphs::Ascii => piece.letter().to_string(),
+                    },
+                    None => ".".to_string(),
+                };
+
+                write!(f, " {square}")?;
+            }
+
+            writeln!(f)?;
+        }
+
+        write!(f, " ")?;
+
+        for column in columns {
+            write!(f, " {}", (b'a' + column as u8) as char)?;
+        }
+
+        writeln!(f)
+    }
+}
+
+#[derive(Clone, Serialize)]
 pub struct Board {
     pieces: Vec<Piece>,
+    /// the rules the pieces move by
+    #[serde(skip)]
+    pub variant: Variant,
+    /// how many times each color has given check, for variants that count them
+    #[serde(skip)]
+    checks: [u8; 2],
+    /// the pieces each color has taken and can drop back on the board,
+    /// for variants with pockets
+    #[serde(skip)]
+    pockets: [Vec<PieceKind>; 2],
+    /// whose move it is, which tells whose pocket a drop comes from
+    #[serde(skip)]
+    to_move: Color,
+    /// the square a pawn skipped over moving two squares on the last move,
+    /// which a pawn next to it can take it on
+    #[serde(skip)]
+    en_passant: Option<Position>,
 }
 
 impl Board {
@@ -56,6 +210,517 @@ impl Board {
                 Piece::new(Pawn, Black, (6, 6).into()),
                 Piece::new(Pawn, Black, (7, 6).into()),
             ],
+            variant: Variant::Standard,
+            checks: [0; 2],
+            pockets: Default::default(),
+            to_move: White,
+            en_passant: None,
+        }
+    }
+
+    /// the Chess960 starting position numbered `id`, from 0 to 959.
+    /// the numbering is the usual one, where 518 is the standard position.
+    pub fn chess960(id: u16) -> Option<Self> {
+        if id >= 960 {
+            return None;
+        }
+
+        let mut back_rank = [None; 8];
+        let mut n = id as usize;
+
+        // one bishop on a light square, the other on a dark square
+        back_rank[(n % 4) * 2 + 1] = Some(Bishop);
+        n /= 4;
+        back_rank[(n % 4) * 2] = Some(Bishop);
+        n /= 4;
+
+        // everything else goes on the nth square still empty
+        let mut place = |kind: PieceKind, nth: usize| {
+            if let Some(column) = (0..8).filter(|c| back_rank[*c].is_none()).nth(nth) {
+                back_rank[column] = Some(kind);
+            }
+        };
+
+        place(Queen, n % 6);
+        n /= 6;
+
+        let (first, second) = [
+            (0, 1),
+            (0, 2),
+            (0, 3),
+            (0, 4),
+            (1, 2),
+            (1, 3),
+            (1, 4),
+            (2, 3),
+            (2, 4),
+            (3, 4),
+        ][n];
+
+        // the second knight first, so the first one's square doesn't shift
+        place(Knight, second);
+        place(Knight, first);
+
+        // the king always ends up between the rooks
+        place(Rook, 0);
+        place(King, 0);
+        place(Rook, 0);
+
+        let mut pieces = vec![];
+
+        for (column, kind) in back_rank.into_iter().enumerate() {
+            let column = column as i8;
+            let kind = kind?;
+
+            pieces.push(Piece::new(kind, White, (column, 0).into()));
+            pieces.push(Piece::new(Pawn, White, (column, 1).into()));
+            pieces.push(Piece::new(kind, Black, (column, 7).into()));
+            pieces.push(Piece::new(Pawn, Black, (column, 6).into()));
+        }
+
+        Some(Self {
+            pieces,
+            variant: Variant::Chess960,
+            checks: [0; 2],
+            pockets: Default::default(),
+            to_move: White,
+            en_passant: None,
+        })
+    }
+
+    /// which Chess960 starting position `fen` is, if it is one
+    pub fn chess960_id(fen: &str) -> Option<u16> {
+        let (board, to_move) = Self::from_fen(fen)?;
+
+        let fen = board.fen(to_move);
+
+        (0..960).find(|id| Self::chess960(*id).is_some_and(|start| start.fen(White) == fen))
+    }
+
+    /// a position in Forsyth-Edwards Notation, and whose move it is.
+    /// castling rights are kept as whether the king and rooks have moved,
+    /// and can be written as X-FEN or Shredder-FEN for Chess960.
+    /// the move counters are ignored.
+    pub fn from_fen(fen: &str) -> Option<(Self, Color)> {
+        let mut fields = fen.split_whitespace();
+
+        let placement = fields.next()?;
+
+        // crazyhouse FENs put the pockets after the placement, as in `...R[Nbp]`
+        let (placement, pockets) = match placement.split_once('[') {
+            Some((placement, pocketed)) => {
+                let mut pockets: [Vec<PieceKind>; 2] = Default::default();
+
+                for c in pocketed.strip_suffix(']')?.chars() {
+                    let color = if c.is_ascii_uppercase() { White } else { Black };
+                    pockets[color as usize].push(PieceKind::parse(c)?);
+                }
+
+                (placement, pockets)
+            }
+            None => (placement, Default::default()),
+        };
+
+        let to_move = match fields.next().unwrap_or("w") {
+            "w" => White,
+            "b" => Black,
+            _ => return None,
+        };
+
+        let castling = fields.next().unwrap_or("-");
+
+        // only a square behind a pawn that just moved two squares makes sense
+        let en_passant = fields
+            .next()
+            .and_then(Position::parse)
+            .filter(|square| square.row == if to_move == White { 5 } else { 2 });
+
+        let ranks: Vec<&str> = placement.split('/').collect();
+
+        if ranks.len() != 8 {
+            return None;
+        }
+
+        let mut pieces: Vec<Piece> = vec![];
+
+        for (i, rank) in ranks.into_iter().enumerate() {
+            let row = 7 - i as i8;
+            let mut column = 0;
+
+            for c in rank.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    column += empty as i8;
+                    continue;
+                }
+
+                // a promoted piece, in crazyhouse FENs
+                if c == '~' {
+                    pieces.last_mut()?.promoted = true;
+                    continue;
+                }
+
+                let kind = PieceKind::parse(c)?;
+
+                let color = if c.is_ascii_uppercase() { White } else { Black };
+
+                let mut piece = Piece::new(kind, color, (column, row).into());
+
+                // pawns can only move two squares from where they started,
+                // kings and rooks can castle only if the castling field says so
+                piece.has_moved = match kind {
+                    Pawn => row != if color == White { 1 } else { 6 },
+                    King | Rook => true,
+                    _ => false,
+                };
+
+                if !piece.position.is_on_board() {
+                    return None;
+                }
+
+                pieces.push(piece);
+                column += 1;
+            }
+
+            if column != 8 {
+                return None;
+            }
+        }
+
+        let mut board = Self {
+            pieces,
+            variant: Variant::Standard,
+            checks: [0; 2],
+            pockets,
+            to_move,
+            en_passant,
+        };
+
+        for right in castling.chars().filter(|c| *c != '-') {
+            let color = if right.is_ascii_uppercase() {
+                White
+            } else {
+                Black
+            };
+
+            let row = back_row(color);
+
+            let Some(king) = board
+                .get_pieces(color)
+                .find(|piece| piece.kind == King && piece.position.row == row)
+                .map(|king| king.position.column)
+            else {
+                continue;
+            };
+
+            let rooks: Vec<i8> = board
+                .get_pieces(color)
+                .filter(|piece| piece.kind == Rook && piece.position.row == row)
+                .map(|rook| rook.position.column)
+                .collect();
+
+            // K and Q are the outermost rook on that side,
+            // and a file is the rook on that file
+            let rook = match right.to_ascii_lowercase() {
+                'k' => rooks.into_iter().filter(|column| *column > king).max(),
+                'q' => rooks.into_iter().filter(|column| *column < king).min(),
+                file @ 'a'..='h' => Some(file as i8 - b'a' as i8),
+                _ => return None,
+            };
+
+            for column in [Some(king), rook].into_iter().flatten() {
+                if let Some(piece) = board.get_piece_mut(&(column, row).into())
+                    && piece.color == color
+                    && [King, Rook].contains(&piece.kind)
+                {
+                    piece.has_moved = false;
+                }
+            }
+        }
+
+        Some((board, to_move))
+    }
+
+    /// the position in Forsyth-Edwards Notation with `to_move` to move.
+    /// the en passant square is only written when a pawn is there to take,
+    /// and the board doesn't keep move counters, so those are always 0 and 1.
+    pub fn fen(&self, to_move: Color) -> String {
+        let mut placement = String::new();
+
+        for row in (0..8).rev() {
+            let mut empty = 0;
+
+            for column in 0..8 {
+                match self.get_piece(&(column, row).into()) {
+                    Some(piece) => {
+                        if empty > 0 {
+                            placement.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+
+                        placement.push(piece.letter());
+
+                        if piece.promoted && self.variant.has_pockets() {
+                            placement.push('~');
+                        }
+                    }
+                    None => empty += 1,
+                }
+            }
+
+            if empty > 0 {
+                placement.push_str(&empty.to_string());
+            }
+
+            if row > 0 {
+                placement.push('/');
+            }
+        }
+
+        if self.variant.has_pockets() {
+            placement.push('[');
+
+            for color in [White, Black] {
+                for kind in self.pocket(color) {
+                    let letter = kind.letter();
+
+                    placement.push(match color {
+                        White => letter.to_ascii_uppercase(),
+                        Black => letter,
+                    });
+                }
+            }
+
+            placement.push(']');
+        }
+
+        let mut castling = String::new();
+
+        for color in [White, Black] {
+            let Some(king) = self.unmoved_king(color) else {
+                continue;
+            };
+
+            let mut rooks = self.castling_rooks(color);
+
+            // kingside first, outermost first
+            rooks.sort_by_key(|rook| {
+                (
+                    rook.column < king.column,
+                    std::cmp::Reverse(rook.column.abs_diff(king.column)),
+                )
+            });
+
+            for rook in rooks {
+                let kingside = rook.column > king.column;
+
+                // a rook with another one further out is written as its file, as in X-FEN
+                let outermost = !self.get_pieces(color).any(|other| {
+                    other.kind == Rook
+                        && other.position.row == rook.row
+                        && if kingside {
+                            other.position.column > rook.column
+                        } else {
+                            other.position.column < rook.column
+                        }
+                });
+
+                let right = match (outermost, kingside) {
+                    (true, true) => 'K',
+                    (true, false) => 'Q',
+                    (false, _) => (b'A' + rook.column as u8) as char,
+                };
+
+                castling.push(match color {
+                    White => right,
+                    Black => right.to_ascii_lowercase(),
+                });
+            }
+        }
+
+        if castling.is_empty() {
+            castling.push('-');
+        }
+
+        let en_passant = self
+            .en_passant
+            .filter(|square| {
+                self.get_pieces(to_move)
+                    .any(|piece| piece.kind == Pawn && piece.possible_moves(self).contains(square))
+            })
+            .map_or("-".to_string(), |square| square.name());
+
+        let to_move = match to_move {
+            White => "w",
+            Black => "b",
+        };
+
+        format!("{placement} {to_move} {castling} {en_passant} 0 1")
+    }
+
+    /// the board drawn as text, with `from`'s pieces at the bottom
+    pub fn diagram(&self, glyphs: Glyphs, from: Color) -> Diagram<'_> {
+        Diagram {
+            board: self,
+            glyphs,
+            from,
+        }
+    }
+
+    /// play a move that came from somewhere else, like a UCI GUI.
+    /// unlike `move_piece` this knows about castling, en passant
+    /// and promoting to something other than a queen.
+    pub fn make_move(&mut self, m: &Move) -> Option<Piece> {
+        // only the move right after a pawn's two square move can take it en passant
+        let en_passant_square = self.en_passant.take();
+
+        if let Some(kind) = m.drop {
+            let color = self.to_move;
+
+            let pocket = &mut self.pockets[color as usize];
+
+            if let Some(i) = pocket.iter().position(|pocketed| *pocketed == kind) {
+                pocket.remove(i);
+            }
+
+            let mut piece = Piece::new(kind, color, m.to);
+
+            // a dropped pawn can move two squares from its own second row,
+            // a dropped rook never castles
+            piece.has_moved = match kind {
+                Pawn => m.to.row != if color == White { 1 } else { 6 },
+                _ => true,
+            };
+
+            self.pieces.push(piece);
+
+            self.count_check(color);
+            self.to_move = color.invert();
+
+            return None;
+        }
+
+        let piece = *self.get_piece(&m.from)?;
+
+        if piece.kind == King
+            && let Some(rook) = self.castling_rook(&piece, &m.to)
+        {
+            let (king_to, rook_to) = castled_columns(rook.column > m.from.column);
+
+            // both come off the board first, in Chess960 either might land where the other was
+            let castled = [(m.from, king_to), (rook, rook_to)]
+                .map(|(from, column)| self.take_piece_at(&from).map(|piece| (piece, column)));
+
+            for (mut piece, column) in castled.into_iter().flatten() {
+                piece.position = (column, m.from.row).into();
+                piece.has_moved = true;
+                self.pieces.push(piece);
+            }
+
+            self.count_check(piece.color);
+            self.to_move = piece.color.invert();
+
+            return None;
+        }
+
+        // a pawn moving onto the square the other pawn skipped over takes it there
+        let en_passant = (piece.kind == Pawn && en_passant_square == Some(m.to))
+            .then(|| self.take_piece_at(&(m.to.column, m.from.row).into()))
+            .flatten();
+
+        let taken = self.move_piece(&m.from, &m.to).or(en_passant);
+
+        if en_passant.is_some() && self.variant.explodes() {
+            self.explode(&m.to);
+        }
+
+        if let Some(promotion) = m.promotion
+            && let Some(promoted) = self.get_piece_mut(&m.to)
+        {
+            promoted.kind = promotion;
+            promoted.promoted = true;
+        }
+
+        // a promoted piece goes back to being a pawn in the pocket
+        if self.variant.has_pockets()
+            && let Some(taken) = taken
+        {
+            self.pockets[piece.color as usize].push(if taken.promoted { Pawn } else { taken.kind });
+        }
+
+        if piece.kind == Pawn && (m.to.row - m.from.row).abs() == 2 {
+            self.en_passant = Some((m.from.column, (m.from.row + m.to.row) / 2).into());
+        }
+
+        self.count_check(piece.color);
+        self.to_move = piece.color.invert();
+
+        taken
+    }
+
+    /// the square a pawn can take en passant on, if the last move allows it
+    pub fn en_passant(&self) -> Option<Position> {
+        self.en_passant
+    }
+
+    /// whether `piece` moving to `to` takes something, en passant included
+    fn is_capture(&self, piece: &Piece, to: &Position) -> bool {
+        self.get_piece(to).is_some() || (piece.kind == Pawn && self.en_passant == Some(*to))
+    }
+
+    /// the pieces `color` can drop, in variants with pockets
+    pub fn pocket(&self, color: Color) -> &[PieceKind] {
+        &self.pockets[color as usize]
+    }
+
+    /// the squares `color` can drop a `kind` from their pocket on:
+    /// any empty square, but for pawns on the first and last rows,
+    /// as long as it doesn't leave their king in check
+    pub fn legal_drops(&self, color: Color, kind: PieceKind) -> Vec<Position> {
+        if !self.pocket(color).contains(&kind) {
+            return vec![];
+        }
+
+        // dropping a piece can't expose the king, only block a check
+        let in_check = self.is_in_check(color);
+
+        (0..8)
+            .flat_map(|column| (0..8).map(move |row| Position::from((column, row))))
+            .filter(|to| self.get_piece(to).is_none())
+            .filter(|to| kind != Pawn || ![0, 7].contains(&to.row))
+            .filter(|to| {
+                !in_check || {
+                    let mut after = self.clone();
+                    after.pieces.push(Piece::new(kind, color, *to));
+                    !after.is_in_check(color)
+                }
+            })
+            .collect()
+    }
+
+    /// every drop `color` can make
+    fn all_legal_drops(&self, color: Color) -> Vec<Move> {
+        let mut kinds = self.pocket(color).to_vec();
+        kinds.sort_by_key(|kind| kind.letter());
+        kinds.dedup();
+
+        kinds
+            .into_iter()
+            .flat_map(|kind| {
+                self.legal_drops(color, kind)
+                    .into_iter()
+                    .map(move |to| Move::drop(kind, to))
+            })
+            .collect()
+    }
+
+    /// how many times `color` has given check, in variants that count them
+    pub fn checks(&self, color: Color) -> u8 {
+        self.checks[color as usize]
+    }
+
+    fn count_check(&mut self, mover: Color) {
+        if self.variant.counts_checks() && self.is_in_check(mover.invert()) {
+            self.checks[mover as usize] += 1;
         }
     }
 
@@ -69,12 +734,80 @@ impl Board {
         piece_to_move.has_moved = true;
 
         if piece_to_move.kind == PieceKind::Pawn && [0, 7].contains(&to.row) {
-            piece_to_move.kind = PieceKind::Queen
+            piece_to_move.kind = PieceKind::Queen;
+            piece_to_move.promoted = true;
+        }
+
+        if taken_piece.is_some() && self.variant.explodes() {
+            self.explode(to);
         }
 
         taken_piece
     }
 
+    /// blow up the piece that just captured on `at`,
+    /// along with every piece but pawns on the squares around it
+    fn explode(&mut self, at: &Position) {
+        self.pieces.retain(|piece| {
+            let distance = (piece.position.column - at.column)
+                .abs()
+                .max((piece.position.row - at.row).abs());
+
+            distance > 1 || (distance == 1 && piece.kind == Pawn)
+        });
+    }
+
+    /// put `piece` on the board in place of whatever was on its square, for setting up positions
+    pub fn put_piece(&mut self, piece: Piece) -> Option<Piece> {
+        let replaced = self.take_piece_at(&piece.position);
+
+        self.pieces.push(piece);
+
+        replaced
+    }
+
+    /// what's wrong with the position, if anything, that means it can't come up
+    /// in a game with `to_move` to move
+    pub fn problems(&self, to_move: Color) -> Vec<String> {
+        let mut problems = vec![];
+
+        // without a royal king, any number of kings will do
+        for color in [White, Black]
+            .into_iter()
+            .filter(|_| self.variant.has_royal_king())
+        {
+            let kings = self
+                .get_pieces(color)
+                .filter(|piece| piece.kind == King)
+                .count();
+
+            if kings != 1 {
+                problems.push(format!(
+                    "{} needs exactly one king, not {kings}",
+                    color.as_str()
+                ));
+            }
+        }
+
+        if self
+            .pieces
+            .iter()
+            .any(|piece| piece.kind == Pawn && [0, 7].contains(&piece.position.row))
+        {
+            problems.push("pawns can't be on the first or last rank".to_string());
+        }
+
+        if self.is_in_check(to_move.invert()) {
+            problems.push(format!(
+                "{} is in check, but it's {}'s move",
+                to_move.invert().as_str(),
+                to_move.as_str()
+            ));
+        }
+
+        problems
+    }
+
     /// get what piece is at position
     pub fn get_piece(&self, position: &Position) -> Option<&Piece> {
         self.pieces.iter().find(|piece| piece.position == *position)
@@ -101,13 +834,255 @@ impl Board {
         attacks
     }
 
+    /// whether `color`'s king is attacked
+    pub fn is_in_check(&self, color: Color) -> bool {
+        if !self.variant.has_royal_king() {
+            return false;
+        }
+
+        let Some(king) = self.get_pieces(color).find(|piece| piece.kind == King) else {
+            return false;
+        };
+
+        // kings next to each other can't take each other in Atomic,
+        // it would blow up both of them
+        if self.variant.explodes()
+            && self.get_pieces(color.invert()).any(|other| {
+                other.kind == King
+                    && (other.position.column - king.position.column).abs() <= 1
+                    && (other.position.row - king.position.row).abs() <= 1
+            })
+        {
+            return false;
+        }
+
+        self.all_attacks(color.invert()).contains(&king.position)
+    }
+
+    /// whether `color` can leave the board like this after their own move:
+    /// their king isn't attacked, or in Atomic, it's still there
+    /// and either safe or the other king has been blown up
+    fn is_safe_for(&self, color: Color) -> bool {
+        if self.variant.explodes() {
+            let has_king = |color| self.get_pieces(color).any(|piece| piece.kind == King);
+
+            return has_king(color) && (!has_king(color.invert()) || !self.is_in_check(color));
+        }
+
+        !self.is_in_check(color)
+    }
+
+    /// whether any of `color`'s pieces can take something
+    fn can_capture(&self, color: Color) -> bool {
+        self.get_pieces(color).any(|piece| {
+            piece
+                .possible_moves(self)
+                .iter()
+                .any(|to| self.is_capture(piece, to))
+        })
+    }
+
+    /// the moves `piece` can make that don't leave its own king in check
+    pub fn legal_moves(&self, piece: &Piece) -> Vec<Position> {
+        let mut moves: Vec<Position> = piece
+            .possible_moves(self)
+            .into_iter()
+            .filter(|to| {
+                // taking en passant takes a pawn off a square the move doesn't land on,
+                // which can uncover an attack on the king
+                let mut after = self.clone();
+                after.make_move(&Move {
+                    from: piece.position,
+                    to: *to,
+                    promotion: None,
+                    drop: None,
+                });
+                after.is_safe_for(piece.color)
+            })
+            .collect();
+
+        if piece.kind == King {
+            moves.extend(self.castles(piece));
+        }
+
+        // in Antichess, if anything can capture, only captures are allowed
+        if self.variant.must_capture() && self.can_capture(piece.color) {
+            moves.retain(|to| self.is_capture(piece, to));
+        }
+
+        moves
+    }
+
+    /// where `king` can move to castle.
+    /// in standard chess that's two squares towards the rook.
+    /// in Chess960 the king might move one square or none at all,
+    /// so it's written as the king taking its own rook.
+    fn castles(&self, king: &Piece) -> Vec<Position> {
+        if self.unmoved_king(king.color) != Some(king.position) || self.is_in_check(king.color) {
+            return vec![];
+        }
+
+        let row = king.position.row;
+
+        let attacked = self.all_attacks(king.color.invert());
+
+        let between = |a: i8, b: i8| a.min(b)..=a.max(b);
+
+        self.castling_rooks(king.color)
+            .into_iter()
+            .filter_map(|rook| {
+                let (king_to, rook_to) = castled_columns(rook.column > king.position.column);
+
+                // everything the king and rook pass over is empty, but for the two of them
+                let clear = between(king.position.column, king_to)
+                    .chain(between(rook.column, rook_to))
+                    .all(|column| {
+                        let square = (column, row).into();
+                        square == king.position
+                            || square == rook
+                            || self.get_piece(&square).is_none()
+                    });
+
+                // and the king doesn't pass through check
+                let safe = between(king.position.column, king_to)
+                    .all(|column| !attacked.contains(&(column, row).into()));
+
+                if !clear || !safe {
+                    return None;
+                }
+
+                let to = if !self.variant.castles_onto_rook()
+                    && (king_to - king.position.column).abs() == 2
+                {
+                    (king_to, row).into()
+                } else {
+                    rook
+                };
+
+                // the rook moving away can uncover an attack along the back rank
+                let mut after = self.clone();
+                after.make_move(&Move {
+                    from: king.position,
+                    to,
+                    promotion: None,
+                    drop: None,
+                });
+
+                after.is_safe_for(king.color).then_some(to)
+            })
+            .collect()
+    }
+
+    /// where `color`'s king is, if it is still where it started
+    fn unmoved_king(&self, color: Color) -> Option<Position> {
+        // there's no castling when the king is just another piece
+        if !self.variant.has_royal_king() {
+            return None;
+        }
+
+        self.get_pieces(color)
+            .find(|piece| {
+                piece.kind == King && !piece.has_moved && piece.position.row == back_row(color)
+            })
+            .map(|king| king.position)
+    }
+
+    /// the rooks `color` can still castle with
+    fn castling_rooks(&self, color: Color) -> Vec<Position> {
+        if self.unmoved_king(color).is_none() {
+            return vec![];
+        }
+
+        self.get_pieces(color)
+            .filter(|piece| {
+                piece.kind == Rook && !piece.has_moved && piece.position.row == back_row(color)
+            })
+            .map(|rook| rook.position)
+            .collect()
+    }
+
+    /// the rook `king` castles with by moving to `to`, if that is castling:
+    /// either the king taking its own rook, or moving two squares towards one
+    pub fn castling_rook(&self, king: &Piece, to: &Position) -> Option<Position> {
+        if to.row != king.position.row {
+            return None;
+        }
+
+        if self
+            .get_piece(to)
+            .is_some_and(|piece| piece.kind == Rook && piece.color == king.color)
+        {
+            return Some(*to);
+        }
+
+        if (to.column - king.position.column).abs() != 2 {
+            return None;
+        }
+
+        let kingside = to.column > king.position.column;
+
+        // the outermost rook on that side, one that hasn't moved if there is one
+        self.get_pieces(king.color)
+            .filter(|piece| {
+                piece.kind == Rook
+                    && piece.position.row == king.position.row
+                    && (piece.position.column > king.position.column) == kingside
+            })
+            .max_by_key(|rook| {
+                (
+                    !rook.has_moved,
+                    rook.position.column.abs_diff(king.position.column),
+                )
+            })
+            .map(|rook| rook.position)
+    }
+
+    /// every move `color` can make.
+    /// a pawn reaching the last row can become any of the pieces it can promote to,
+    /// queen first.
+    pub fn all_legal_moves(&self, color: Color) -> Vec<Move> {
+        let promotions = self.variant.promotions();
+
+        self.get_pieces(color)
+            .flat_map(|piece| {
+                let (from, kind) = (piece.position, piece.kind);
+                let promotions = &promotions;
+
+                self.legal_moves(piece).into_iter().flat_map(move |to| {
+                    let promotions = if kind == Pawn && [0, 7].contains(&to.row) {
+                        promotions.iter().copied().map(Some).collect()
+                    } else {
+                        vec![None]
+                    };
+
+                    promotions.into_iter().map(move |promotion| Move {
+                        from,
+                        to,
+                        promotion,
+                        drop: None,
+                    })
+                })
+            })
+            .chain(self.all_legal_drops(color))
+            .collect()
+    }
+
+    /// whether `color` can make any move at all.
+    /// if they can't, they are either checkmated or stalemated.
+    pub fn has_legal_moves(&self, color: Color) -> bool {
+        self.get_pieces(color)
+            .any(|piece| !self.legal_moves(piece).is_empty())
+            || !self.all_legal_drops(color).is_empty()
+    }
+
     fn get_piece_mut(&mut self, position: &Position) -> Option<&mut Piece> {
         self.pieces
             .iter_mut()
             .find(|piece| piece.position == *position)
     }
 
-    fn take_piece_at(&mut self, position: &Position) -> Option<Piece> {
+    /// take whatever is on `position` off the board
+    pub fn take_piece_at(&mut self, position: &Position) -> Option<Piece> {
         let i = self
             .pieces
             .iter()
@@ -120,3 +1095,213 @@ impl Board {
         }
     }
 }
+
+/// the row `color`'s pieces start on
+fn back_row(color: Color) -> i8 {
+    match color {
+        White => 0,
+        Black => 7,
+    }
+}
+
+/// the columns the king and rook end up on after castling,
+/// which are the same in Chess960 as in standard chess
+fn castled_columns(kingside: bool) -> (i8, i8) {
+    if kingside { (6, 5) } else { (2, 3) }
+}
+
+/// the board from white's side, for debugging
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.diagram(Glyphs::Unicode, White))
+    }
+}
+
+impl fmt::Debug for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\n{}", self.diagram(Glyphs::Ascii, White))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn king_moves(board: &Board, at: &str) -> Vec<String> {
+        let king = board.get_piece(&Position::parse(at).unwrap()).unwrap();
+
+        let mut moves: Vec<String> = board.legal_moves(king).iter().map(|to| to.name()).collect();
+        moves.sort();
+        moves
+    }
+
+    #[test]
+    fn castles_when_nothing_is_in_the_way() {
+        let (board, _) = Board::from_fen("4k3/8/8/8/8/8/8/R3K2R w KQ - 0 1").unwrap();
+
+        let moves = king_moves(&board, "e1");
+
+        assert!(moves.contains(&"g1".to_string()));
+        assert!(moves.contains(&"c1".to_string()));
+    }
+
+    #[test]
+    fn cannot_castle_through_a_square_a_pawn_attacks() {
+        // the pawn on e2 covers d1 and f1 without standing on either
+        let (board, _) = Board::from_fen("4k3/8/8/8/8/8/4p3/R3K2R w KQ - 0 1").unwrap();
+
+        let moves = king_moves(&board, "e1");
+
+        assert!(!moves.contains(&"g1".to_string()));
+        assert!(!moves.contains(&"c1".to_string()));
+        assert!(!moves.contains(&"d1".to_string()));
+        assert!(!moves.contains(&"f1".to_string()));
+    }
+
+    #[test]
+    fn chess960_castling_lands_on_the_usual_squares() {
+        let (mut board, _) = Board::from_fen("1k6/8/8/8/8/8/8/RK5R w KQ - 0 1").unwrap();
+        board.variant = Variant::Chess960;
+
+        let moves = king_moves(&board, "b1");
+        assert!(moves.contains(&"a1".to_string()));
+        assert!(moves.contains(&"h1".to_string()));
+
+        board.make_move(&Move::parse_uci("b1a1").unwrap());
+
+        assert_eq!(board.fen(Black), "1k6/8/8/8/8/8/8/2KR3R b - - 0 1");
+    }
+
+    #[test]
+    fn chess960_cannot_castle_through_a_square_a_pawn_attacks() {
+        let (mut board, _) = Board::from_fen("1k6/8/8/8/8/8/3p4/RK5R w KQ - 0 1").unwrap();
+        board.variant = Variant::Chess960;
+
+        // the king would pass over c1 and e1 on its way to g1
+        let moves = king_moves(&board, "b1");
+        assert!(!moves.contains(&"h1".to_string()));
+    }
+
+    #[test]
+    fn chess960_ids_round_trip() {
+        assert_eq!(Board::chess960(518).unwrap().fen(White), STARTING_FEN);
+        assert_eq!(
+            Board::chess960(0).unwrap().fen(White).split('/').next(),
+            Some("bbqnnrkr")
+        );
+        assert!(Board::chess960(960).is_none());
+
+        for id in [0, 1, 100, 518, 959] {
+            let fen = Board::chess960(id).unwrap().fen(White);
+            assert_eq!(Board::chess960_id(&fen), Some(id));
+        }
+    }
+
+    #[test]
+    fn pawns_can_promote_to_any_piece() {
+        let (board, to_move) = Board::from_fen("8/P6k/8/8/8/8/8/K7 w - - 0 1").unwrap();
+
+        let promotions: Vec<String> = board
+            .all_legal_moves(to_move)
+            .iter()
+            .filter(|m| m.from == Position::parse("a7").unwrap())
+            .map(Move::uci)
+            .collect();
+
+        assert_eq!(promotions, ["a7a8q", "a7a8r", "a7a8b", "a7a8n"]);
+
+        let mut board = board;
+        board.make_move(&Move::parse_uci("a7a8n").unwrap());
+
+        assert_eq!(board.fen(Black), "N7/7k/8/8/8/8/8/K7 b - - 0 1");
+    }
+
+    #[test]
+    fn fen_round_trips() {
+        for fen in [
+            STARTING_FEN,
+            "r3k2r/8/8/8/8/8/8/R3K2R b Kq - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+        ] {
+            let (board, to_move) = Board::from_fen(fen).unwrap();
+            assert_eq!(board.fen(to_move), fen);
+        }
+    }
+
+    #[test]
+    fn pawns_take_en_passant_right_after_a_two_square_move() {
+        let (mut board, _) = Board::from_fen("4k3/3p4/8/4P3/8/8/8/4K3 b - - 0 1").unwrap();
+
+        board.make_move(&Move::parse_uci("d7d5").unwrap());
+
+        assert_eq!(board.en_passant(), Position::parse("d6"));
+        assert_eq!(board.fen(White), "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1");
+
+        let mut taken = board.clone();
+        taken.make_move(&Move::parse_uci("e5d6").unwrap());
+
+        assert_eq!(taken.fen(Black), "4k3/8/3P4/8/8/8/8/4K3 b - - 0 1");
+
+        // a move later it's too late
+        board.make_move(&Move::parse_uci("e1d1").unwrap());
+        board.make_move(&Move::parse_uci("e8d8").unwrap());
+
+        let pawn = board.get_piece(&Position::parse("e5").unwrap()).unwrap();
+        assert_eq!(board.legal_moves(pawn), [Position::parse("e6").unwrap()]);
+    }
+
+    #[test]
+    fn en_passant_is_read_from_fen() {
+        let fen = "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1";
+        let (board, to_move) = Board::from_fen(fen).unwrap();
+
+        assert_eq!(board.fen(to_move), fen);
+
+        let pawn = board.get_piece(&Position::parse("e5").unwrap()).unwrap();
+        assert!(
+            board
+                .legal_moves(pawn)
+                .contains(&Position::parse("d6").unwrap())
+        );
+
+        // with nothing to take it, the square isn't written
+        let (board, to_move) = Board::from_fen("4k3/8/8/3p4/8/8/8/4K3 w - d6 0 1").unwrap();
+        assert_eq!(board.fen(to_move), "4k3/8/8/3p4/8/8/8/4K3 w - - 0 1");
+    }
+
+    #[test]
+    fn cannot_take_en_passant_when_it_uncovers_a_check() {
+        // both pawns leave the fifth rank, and the rook sees the king
+        let (board, _) = Board::from_fen("8/8/8/K2pP2r/8/8/8/4k3 w - d6 0 1").unwrap();
+
+        let pawn = board.get_piece(&Position::parse("e5").unwrap()).unwrap();
+        assert_eq!(board.legal_moves(pawn), [Position::parse("e6").unwrap()]);
+    }
+
+    #[test]
+    fn set_up_positions_that_cant_be_played_say_why() {
+        let problems = |fen: &str, variant: Variant| {
+            let (mut board, to_move) = Board::from_fen(fen).unwrap();
+            board.variant = variant;
+            board.problems(to_move)
+        };
+
+        assert!(problems(STARTING_FEN, Variant::Standard).is_empty());
+
+        assert_eq!(
+            problems("4k3/8/8/8/8/8/8/8 w - - 0 1", Variant::Standard),
+            ["white needs exactly one king, not 0"]
+        );
+        assert_eq!(
+            problems("4k3/8/8/8/8/8/8/P3K3 w - - 0 1", Variant::Standard),
+            ["pawns can't be on the first or last rank"]
+        );
+        assert_eq!(
+            problems("4k3/8/8/8/8/8/8/4R1K1 w - - 0 1", Variant::Standard),
+            ["black is in check, but it's white's move"]
+        );
+
+        // Antichess kings are just pieces
+        assert!(problems("8/8/8/8/8/8/8/KK6 w - - 0 1", Variant::Antichess).is_empty());
+    }
+}
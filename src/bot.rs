@@ -0,0 +1,266 @@
+use crate::api::Game;
+use crate::player::{self, PlayerEvent, PlayerId};
+use crate::{AppError, AppState, BadRequest};
+use anyhow::bail;
+use axum::Extension;
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_stream::wrappers::{BroadcastStream, IntervalStream};
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+use uuid::fmt::Hyphenated;
+
+/// streams are newline-delimited JSON, one event per line
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// how often an empty line is sent down a quiet stream,
+/// so the bot can tell the connection is still alive
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// register a bot called `name`, returning its API token
+pub async fn create(conn: &mut SqliteConnection, name: &str) -> anyhow::Result<String> {
+    if !player::valid_name(name) {
+        bail!("names are 3 to 20 letters, digits, - or _");
+    }
+
+    if player::by_name(conn, name).await?.is_some() {
+        bail!("{name} is taken");
+    }
+
+    let bot = PlayerId(Uuid::new_v4());
+
+    sqlx::query("insert into users (id, name, bot) values (?, ?, true);")
+        .bind(bot.0.hyphenated())
+        .bind(name)
+        .execute(&mut *conn)
+        .await?;
+
+    issue_token(conn, bot).await
+}
+
+/// a new API token for `player`.
+/// only its hash is stored, so this is the only time anyone sees it.
+async fn issue_token(conn: &mut SqliteConnection, player: PlayerId) -> anyhow::Result<String> {
+    let token = format!(
+        "chez_{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+
+    sqlx::query("insert into api_tokens (token_hash, user_id) values (?, ?);")
+        .bind(hash(&token))
+        .bind(player.0.hyphenated())
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(token)
+}
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// whose token this is, if it is anyone's
+pub async fn token_player(pool: &Pool<Sqlite>, token: &str) -> anyhow::Result<Option<PlayerId>> {
+    let player: Option<(Hyphenated,)> =
+        sqlx::query_as("select user_id from api_tokens where token_hash = ?;")
+            .bind(hash(token.trim()))
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(player.map(|(id,)| PlayerId(id.into_uuid())))
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Challenge { game: Game },
+    GameStart { game: Game },
+}
+
+/// what happens to the player as NDJSON: challenges sent to them, and their games starting.
+/// challenges still waiting for an answer and games still being played are sent first,
+/// so a bot that reconnects can pick up where it left off.
+pub async fn stream_events(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Response, AppError> {
+    let (waiting, events) = {
+        let state = state.lock().await;
+
+        let mut conn = state.pool.acquire().await?;
+
+        let challenges: Vec<Uuid> = sqlx::query_scalar(
+            "
+    select id from games
+    where challenged = ?
+    and result is null
+    and (white_player is null or black_player is null)
+    order by inserted_at asc;
+    ",
+        )
+        .bind(player.0.hyphenated())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let playing: Vec<Uuid> = sqlx::query_scalar(
+            "
+    select id from games
+    where (white_player = ? or black_player = ?)
+    and result is null
+    and (computer is not null or (white_player is not null and black_player is not null))
+    order by inserted_at asc;
+    ",
+        )
+        .bind(player.0.hyphenated())
+        .bind(player.0.hyphenated())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let waiting: Vec<PlayerEvent> = challenges
+            .into_iter()
+            .map(PlayerEvent::Challenge)
+            .chain(playing.into_iter().map(PlayerEvent::GameStart))
+            .collect();
+
+        (waiting, state.player_events.subscribe())
+    };
+
+    let events = tokio_stream::iter(waiting).chain(
+        BroadcastStream::new(events)
+            .filter_map(Result::ok)
+            .filter(move |(to, _)| *to == player)
+            .map(|(_, event)| event),
+    );
+
+    let lines = events
+        .then(move |event| {
+            let state = Arc::clone(&state);
+
+            async move {
+                let mut state = state.lock().await;
+
+                let (game_id, event): (Uuid, fn(Game) -> Event) = match event {
+                    PlayerEvent::Challenge(game_id) => (game_id, |game| Event::Challenge { game }),
+                    PlayerEvent::GameStart(game_id) => (game_id, |game| Event::GameStart { game }),
+                };
+
+                // the game may have gone away since, like a declined challenge
+                let game_state = state.game(game_id).await.ok()?;
+
+                Some(line(&event(Game::new(game_id, game_state, player))))
+            }
+        })
+        .filter_map(|line| line);
+
+    Ok(ndjson(lines))
+}
+
+/// a game as NDJSON: all of it now, and again every time anything happens to it
+pub async fn stream_game(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Response, AppError> {
+    let events = state.lock().await.game(game_id).await?.events.subscribe();
+
+    let changes = tokio_stream::once(()).chain(BroadcastStream::new(events).map(|_| ()));
+
+    let lines = changes
+        .then(move |_| {
+            let state = Arc::clone(&state);
+
+            async move {
+                let mut state = state.lock().await;
+
+                let game_state = state.game(game_id).await.ok()?;
+
+                Some(line(&Game::new(game_id, game_state, player)))
+            }
+        })
+        .filter_map(|line| line);
+
+    Ok(ndjson(lines))
+}
+
+fn line(event: &impl Serialize) -> String {
+    let mut line = serde_json::to_string(event).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// a streaming response of `lines`, with empty lines in between when there's nothing to say
+fn ndjson(lines: impl Stream<Item = String> + Send + 'static) -> Response {
+    let keep_alive = IntervalStream::new(tokio::time::interval_at(
+        tokio::time::Instant::now() + KEEP_ALIVE,
+        KEEP_ALIVE,
+    ))
+    .map(|_| "\n".to_string());
+
+    let body = lines.merge(keep_alive).map(Ok::<_, Infallible>);
+
+    ([(CONTENT_TYPE, NDJSON)], Body::from_stream(body)).into_response()
+}
+
+/// accept a challenge by taking the open seat
+pub async fn join(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<axum::Json<Game>, AppError> {
+    let mut state = state.lock().await;
+
+    crate::join_game(&mut state, game_id, player).await?;
+
+    let game_state = state.game(game_id).await?;
+
+    if game_state.seat(player).is_none() {
+        return Err(BadRequest("the game has no seat for you".into()).into());
+    }
+
+    Ok(axum::Json(Game::new(game_id, game_state, player)))
+}
+
+/// turn down a challenge sent to the player, which deletes it
+pub async fn decline(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<StatusCode, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let game_state = state.game(game_id).await?;
+
+    if game_state.challenged != Some(player) || game_state.can_join(player).is_none() {
+        return Err(BadRequest("there is no challenge to decline".into()).into());
+    }
+
+    sqlx::query(
+        "
+    delete from games
+    where id = ?
+    and result is null
+    and not exists (select 1 from moves where moves.game_id = games.id);
+    ",
+    )
+    .bind(game_id)
+    .execute(&mut *conn)
+    .await?;
+
+    // everyone following the game is let go when its events go away
+    state.games.remove(&game_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,244 @@
+use crate::board::{Board, Glyphs};
+use crate::bot;
+use crate::game::GameState;
+use crate::pgn;
+use crate::piece::Color;
+use crate::variant::Variant;
+use anyhow::{Context, anyhow};
+use sqlx::{Pool, Sqlite};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// open the db, creating it and bringing its tables up to date if need be
+pub async fn connect(database: &str) -> anyhow::Result<Pool<Sqlite>> {
+    let opts = sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite://{database}"))?
+        .busy_timeout(std::time::Duration::from_secs(5))
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+        .create_if_missing(true)
+        .foreign_keys(true);
+
+    let pool = sqlx::SqlitePool::connect_with(opts).await?;
+
+    sqlx::migrate!().run(&pool).await?;
+
+    Ok(pool)
+}
+
+pub async fn migrate(database: &str) -> anyhow::Result<()> {
+    let pool = connect(database).await?;
+
+    let (version,): (i64,) =
+        sqlx::query_as("select coalesce(max(version), 0) from _sqlx_migrations where success")
+            .fetch_one(&pool)
+            .await?;
+
+    println!("{database} is at migration {version}");
+
+    Ok(())
+}
+
+/// store every game in a PGN file as a finished (or abandoned) game
+pub async fn import_pgn(database: &str, file: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(file)
+        .with_context(|| format!("could not read {}", file.display()))?;
+
+    let games = pgn::parse(&text)?;
+
+    let pool = connect(database).await?;
+
+    let mut conn = pool.acquire().await?;
+
+    for (i, game) in games.iter().enumerate() {
+        let game_id = pgn::import(&mut conn, game)
+            .await
+            .with_context(|| format!("could not import game {} of {}", i + 1, file.display()))?;
+
+        println!("{game_id}");
+    }
+
+    eprintln!("imported {} games", games.len());
+
+    Ok(())
+}
+
+/// write games to stdout as PGN, one game or every game in the db
+pub async fn export_pgn(database: &str, game_id: Option<Uuid>) -> anyhow::Result<()> {
+    let pool = connect(database).await?;
+
+    let mut conn = pool.acquire().await?;
+
+    let game_ids = match game_id {
+        Some(game_id) => vec![game_id],
+        None => {
+            sqlx::query_scalar("select id from games order by inserted_at asc, rowid asc")
+                .fetch_all(&mut *conn)
+                .await?
+        }
+    };
+
+    for (i, game_id) in game_ids.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        print!("{}", pgn::export(&mut conn, game_id).await?);
+    }
+
+    Ok(())
+}
+
+/// the token is only ever shown here, so it is printed on its own for scripts
+pub async fn create_bot(database: &str, name: &str) -> anyhow::Result<()> {
+    let pool = connect(database).await?;
+
+    let mut conn = pool.acquire().await?;
+
+    let token = bot::create(&mut conn, name).await?;
+
+    eprintln!("created bot {name}, which authenticates with this API token:");
+    println!("{token}");
+
+    Ok(())
+}
+
+/// count the positions `depth` moves from `fen` under `variant`'s rules, per first move,
+/// to compare with the numbers other move generators give
+pub fn perft(fen: &str, depth: u8, variant: Variant) -> anyhow::Result<()> {
+    let (mut board, to_move) = Board::from_fen(fen).ok_or_else(|| anyhow!("bad FEN {fen}"))?;
+
+    board.variant = variant;
+
+    let mut total = 0;
+
+    for m in board.all_legal_moves(to_move) {
+        let mut after = board.clone();
+        after.make_move(&m);
+
+        let nodes = count_positions(&after, to_move.invert(), depth.saturating_sub(1));
+
+        println!("{}: {nodes}", m.uci());
+
+        total += nodes;
+    }
+
+    println!();
+    println!("Nodes searched: {total}");
+
+    Ok(())
+}
+
+fn count_positions(board: &Board, to_move: Color, depth: u8) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let moves = board.all_legal_moves(to_move);
+
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+
+    moves
+        .iter()
+        .map(|m| {
+            let mut after = board.clone();
+            after.make_move(m);
+            count_positions(&after, to_move.invert(), depth - 1)
+        })
+        .sum()
+}
+
+/// who played, how it ended, the moves and where they left the board
+pub async fn show(
+    database: &str,
+    game_id: Uuid,
+    glyphs: Glyphs,
+    from: Color,
+) -> anyhow::Result<()> {
+    let pool = connect(database).await?;
+
+    let mut conn = pool.acquire().await?;
+
+    let game_state = GameState::load(&mut conn, game_id)
+        .await?
+        .ok_or_else(|| anyhow!("no game with id {game_id}"))?;
+
+    let player = |color: Color| match game_state.computer {
+        Some(computer) if computer.color == color => {
+            format!("Computer ({})", computer.engine.label())
+        }
+        _ => game_state
+            .profile(color)
+            .name
+            .clone()
+            .unwrap_or_else(|| "Anonymous".to_string()),
+    };
+
+    println!("{} vs {}", player(Color::White), player(Color::Black));
+
+    match &game_state.result {
+        Some(result) => println!("{}", result.description()),
+        None => println!("In progress"),
+    }
+
+    let pgn = pgn::Pgn::of(&game_state);
+
+    println!("{}", pgn.movetext());
+
+    println!();
+    print!("{}", game_state.board.diagram(glyphs, from));
+    println!();
+
+    println!("{}", game_state.board.fen(game_state.to_move));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::STARTING_FEN;
+
+    fn count(fen: &str, depth: u8) -> u64 {
+        let (board, to_move) = Board::from_fen(fen).unwrap();
+
+        count_positions(&board, to_move, depth)
+    }
+
+    // the usual test positions and their counts, from the chess programming wiki,
+    // kept shallow enough for a debug build
+
+    #[test]
+    fn perft_from_the_start() {
+        assert_eq!(count(STARTING_FEN, 3), 8902);
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        let fen = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+        assert_eq!(count(fen, 1), 48);
+        assert_eq!(count(fen, 2), 2039);
+    }
+
+    #[test]
+    fn perft_position_3() {
+        // pawns taking en passant, some of which would uncover a check along the rank
+        assert_eq!(count("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 4), 43238);
+    }
+
+    #[test]
+    fn perft_position_4() {
+        let fen = "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1";
+
+        assert_eq!(count(fen, 3), 9467);
+    }
+
+    #[test]
+    fn perft_position_5() {
+        let fen = "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8";
+
+        assert_eq!(count(fen, 2), 1486);
+    }
+}
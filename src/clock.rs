@@ -0,0 +1,126 @@
+use crate::piece::Color;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// a chess clock for a time control of `initial` time
+/// for each player plus `increment` for every move they make
+pub struct Clock {
+    pub initial: Duration,
+    pub increment: Duration,
+    white: Duration,
+    black: Duration,
+    /// whose time is running, and since when
+    running: Option<(Color, Instant)>,
+}
+
+impl Clock {
+    pub fn new(initial: Duration, increment: Duration) -> Self {
+        Self {
+            initial,
+            increment,
+            white: initial,
+            black: initial,
+            running: None,
+        }
+    }
+
+    /// parse a time control as it is stored in the db,
+    /// "<initial seconds>+<increment seconds>", e.g. "300+3"
+    pub fn parse(time_control: &str) -> Option<Self> {
+        let (initial, increment) = time_control.split_once('+')?;
+
+        let initial = Duration::from_secs(initial.trim().parse().ok()?);
+        let increment = Duration::from_secs(increment.trim().parse().ok()?);
+
+        if initial.is_zero() {
+            return None;
+        }
+
+        Some(Self::new(initial, increment))
+    }
+
+    /// the time control as it is stored in the db
+    pub fn time_control(&self) -> String {
+        format!("{}+{}", self.initial.as_secs(), self.increment.as_secs())
+    }
+
+    /// the time control the way players talk about it, e.g. "5+3"
+    pub fn label(&self) -> String {
+        let initial = self.initial.as_secs();
+
+        if initial.is_multiple_of(60) {
+            format!("{}+{}", initial / 60, self.increment.as_secs())
+        } else {
+            format!("{initial}s+{}", self.increment.as_secs())
+        }
+    }
+
+    /// how much time `color` has left right now
+    pub fn remaining(&self, color: Color) -> Duration {
+        let stored = match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        };
+
+        match self.running {
+            Some((running, since)) if running == color => stored.saturating_sub(since.elapsed()),
+            _ => stored,
+        }
+    }
+
+    pub fn set_remaining(&mut self, color: Color, remaining: Duration) {
+        match color {
+            Color::White => self.white = remaining,
+            Color::Black => self.black = remaining,
+        }
+    }
+
+    /// whose time is running, if anyone's
+    pub fn running(&self) -> Option<Color> {
+        self.running.map(|(color, _)| color)
+    }
+
+    pub fn start(&mut self, color: Color, since: Instant) {
+        self.running = Some((color, since));
+    }
+
+    /// freeze both clocks where they are
+    pub fn stop(&mut self) {
+        if let Some((color, _)) = self.running {
+            self.set_remaining(color, self.remaining(color));
+        }
+
+        self.running = None;
+    }
+
+    /// stop the mover's time and start their opponent's.
+    /// the mover only gets the increment if their time was running,
+    /// which it isn't for the first move of the game.
+    /// returns how much time the mover has left.
+    pub fn punch(&mut self, mover: Color) -> Duration {
+        let mut remaining = self.remaining(mover);
+
+        if self.running() == Some(mover) {
+            remaining += self.increment;
+        }
+
+        self.set_remaining(mover, remaining);
+
+        self.start(mover.invert(), Instant::now());
+
+        remaining
+    }
+
+    /// the player whose time ran out, if anyone's did
+    pub fn flagged(&self) -> Option<Color> {
+        self.running()
+            .filter(|color| self.remaining(*color).is_zero())
+    }
+}
+
+/// "mm:ss", the way a clock shows the time
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
@@ -0,0 +1,419 @@
+use crate::board::{Board, STARTING_FEN};
+use crate::piece::{Color, Piece, PieceKind, Position};
+use crate::{
+    AppError, AppState, BadRequest, SquareColor, board_grid, computer_select, layout,
+    linked_square, time_control_select,
+};
+use axum::extract::{Query, State};
+use maud::{Markup, html};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// a board with nothing but the two kings on it
+const KINGS_ONLY_FEN: &str = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+
+#[derive(Deserialize)]
+pub struct EditorParams {
+    /// the position so far, the usual starting position if missing
+    fen: Option<String>,
+    /// what clicking a square does, moving pieces if missing
+    tool: Option<String>,
+    /// the square of the piece being moved, e.g. "e2"
+    from: Option<String>,
+}
+
+/// what clicking a square in the editor does
+#[derive(Clone, Copy, PartialEq)]
+enum Tool {
+    /// pick a piece up, then put it down somewhere else
+    Move,
+    /// take whatever is on the square off the board
+    Remove,
+    /// put a piece down, in place of whatever was there
+    Put(PieceKind, Color),
+}
+
+impl Tool {
+    fn all() -> Vec<Tool> {
+        let kinds = [
+            PieceKind::King,
+            PieceKind::Queen,
+            PieceKind::Rook,
+            PieceKind::Bishop,
+            PieceKind::Knight,
+            PieceKind::Pawn,
+        ];
+
+        [Tool::Move, Tool::Remove]
+            .into_iter()
+            .chain(
+                [Color::White, Color::Black]
+                    .into_iter()
+                    .flat_map(|color| kinds.map(|kind| Tool::Put(kind, color))),
+            )
+            .collect()
+    }
+
+    /// the tool as the query string has it: "move", "remove",
+    /// or the piece's FEN letter, uppercase for white
+    fn as_str(&self) -> String {
+        match self {
+            Tool::Move => "move".to_string(),
+            Tool::Remove => "remove".to_string(),
+            Tool::Put(kind, color) => Piece::new(*kind, *color, Position::new(0, 0))
+                .letter()
+                .to_string(),
+        }
+    }
+
+    fn parse(tool: &str) -> Option<Self> {
+        match tool {
+            "move" => Some(Tool::Move),
+            "remove" => Some(Tool::Remove),
+            _ => {
+                let mut letters = tool.chars();
+
+                let letter = letters.next()?;
+
+                if letters.next().is_some() {
+                    return None;
+                }
+
+                let color = if letter.is_ascii_uppercase() {
+                    Color::White
+                } else {
+                    Color::Black
+                };
+
+                Some(Tool::Put(PieceKind::parse(letter)?, color))
+            }
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Tool::Move => "Move".to_string(),
+            Tool::Remove => "Remove".to_string(),
+            Tool::Put(kind, color) => Piece::new(*kind, *color, Position::new(0, 0))
+                .repr()
+                .to_string(),
+        }
+    }
+}
+
+/// a position being set up: where the pieces are, whose move it is,
+/// and who can still castle, which the board keeps as which kings and rooks haven't moved
+struct Setup {
+    board: Board,
+    to_move: Color,
+}
+
+impl Setup {
+    fn parse(fen: &str) -> Option<Self> {
+        let (board, to_move) = Board::from_fen(fen)?;
+
+        Some(Self { board, to_move })
+    }
+
+    fn fen(&self) -> String {
+        self.board.fen(self.to_move)
+    }
+
+    /// the editor with this position, `tool` picked and `from` picked up
+    fn href(&self, tool: Tool, from: Option<Position>) -> String {
+        let mut href = format!(
+            "/editor?fen={}&tool={}",
+            self.fen().replace(' ', "+"),
+            tool.as_str()
+        );
+
+        if let Some(from) = from {
+            href.push_str(&format!("&from={}", from.name()));
+        }
+
+        href
+    }
+
+    /// the position after `edit`, read back from its FEN
+    /// so that pawns and castling rights come out the way a FEN would have them
+    fn edited(&self, edit: impl FnOnce(&mut Board)) -> Self {
+        let mut board = self.board.clone();
+
+        edit(&mut board);
+
+        Self::parse(&board.fen(self.to_move)).unwrap_or(Self {
+            board,
+            to_move: self.to_move,
+        })
+    }
+
+    /// where clicking `position` with `tool` goes, when `from` has been picked up
+    fn clicked(&self, tool: Tool, from: Option<Position>, position: &Position) -> String {
+        match (tool, from) {
+            // put it back down
+            (Tool::Move, Some(from)) if from == *position => self.href(tool, None),
+            (Tool::Move, Some(from)) => self
+                .edited(|board| {
+                    if let Some(mut piece) = board.take_piece_at(&from) {
+                        piece.position = *position;
+                        // a king or rook that has been moved around can't castle
+                        piece.has_moved = true;
+                        board.put_piece(piece);
+                    }
+                })
+                .href(tool, None),
+            (Tool::Move, None) if self.board.get_piece(position).is_some() => {
+                self.href(tool, Some(*position))
+            }
+            (Tool::Move, None) => self.href(tool, None),
+            (Tool::Remove, _) => self
+                .edited(|board| {
+                    board.take_piece_at(position);
+                })
+                .href(tool, None),
+            (Tool::Put(kind, color), _) => self
+                .edited(|board| {
+                    let mut piece = Piece::new(kind, color, *position);
+                    piece.has_moved = true;
+                    board.put_piece(piece);
+                })
+                .href(tool, None),
+        }
+    }
+
+    /// the position with `right` to castle, one of K, Q, k or q, turned on or off.
+    /// rights the kings and rooks aren't placed for don't stick.
+    fn toggle_castling(&self, right: char) -> Self {
+        let fen = self.fen();
+
+        let mut fields: Vec<&str> = fen.split_whitespace().collect();
+
+        let castling: String = if fields[2].contains(right) {
+            fields[2].chars().filter(|c| *c != right).collect()
+        } else {
+            fields[2]
+                .chars()
+                .filter(|c| *c != '-')
+                .chain([right])
+                .collect()
+        };
+
+        fields[2] = if castling.is_empty() { "-" } else { &castling };
+
+        Self::parse(&fields.join(" ")).unwrap_or(Self {
+            board: self.board.clone(),
+            to_move: self.to_move,
+        })
+    }
+}
+
+/// set up a position by putting pieces down, taking them off and moving them around,
+/// to play or analyse from
+pub async fn editor(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<EditorParams>,
+) -> Result<Markup, AppError> {
+    let has_engine = state.lock().await.engine.is_some();
+
+    let fen = params.fen.as_deref().map_or(STARTING_FEN, str::trim);
+
+    let setup = Setup::parse(fen).ok_or_else(|| BadRequest(format!("{fen} isn't a FEN")))?;
+
+    let tool = params
+        .tool
+        .as_deref()
+        .and_then(Tool::parse)
+        .unwrap_or(Tool::Move);
+
+    let from = params
+        .from
+        .as_deref()
+        .and_then(Position::parse)
+        .filter(|from| tool == Tool::Move && setup.board.get_piece(from).is_some());
+
+    let fen = setup.fen();
+
+    let castling = fen.split_whitespace().nth(2).unwrap_or("-").to_string();
+
+    let problems = setup.board.problems(setup.to_move);
+
+    Ok(layout! {
+        html! {
+            div class="p-4 flex justify-between" {
+                h1 class="text-2xl" { "Board editor" }
+                a href="/games/new" class="underline" { "New game" }
+            }
+            (board_grid(&setup.board, Color::White, |position, color, body| {
+                let color = if from == Some(*position) { SquareColor::Highlighted } else { color };
+                linked_square(&setup.clicked(tool, from, position), position, color, body)
+            }))
+            div class="p-4 flex flex-col gap-2" {
+                div class="flex flex-wrap gap-2 items-center" {
+                    @for other in Tool::all() {
+                        a
+                            href=(setup.href(other, None))
+                            class=(if other == tool { "px-1 bg-pink-400" } else { "px-1 underline" })
+                        {
+                            (other.label())
+                        }
+                    }
+                }
+                div class="flex gap-2" {
+                    "To move:"
+                    @for color in [Color::White, Color::Black] {
+                        @if color == setup.to_move {
+                            span class="font-bold" { (color.as_str()) }
+                        } @else {
+                            a
+                                href=(Setup { board: setup.board.clone(), to_move: color }.href(tool, from))
+                                class="underline"
+                            {
+                                (color.as_str())
+                            }
+                        }
+                    }
+                }
+                div class="flex gap-2" {
+                    "Castling:"
+                    @for right in ['K', 'Q', 'k', 'q'] {
+                        @let toggled = setup.toggle_castling(right);
+                        @if toggled.fen() == fen {
+                            // the king and rook aren't where they'd need to be
+                            span class="text-gray-400" { (right) }
+                        } @else {
+                            a
+                                href=(toggled.href(tool, from))
+                                class=(if castling.contains(right) { "font-bold underline" } else { "underline" })
+                            {
+                                (right)
+                            }
+                        }
+                    }
+                }
+                div class="flex gap-4" {
+                    a href=(format!("/editor?fen={}", STARTING_FEN.replace(' ', "+"))) class="underline" { "Starting position" }
+                    a href=(format!("/editor?fen={}", KINGS_ONLY_FEN.replace(' ', "+"))) class="underline" { "Kings only" }
+                }
+                form action="/editor" method="get" class="flex gap-2" {
+                    input type="text" name="fen" value=(fen) class="grow";
+                    button { "Load FEN" }
+                }
+                @if problems.is_empty() {
+                    a href=(format!("/analysis?fen={}", fen.replace(' ', "+"))) class="underline" { "Analyse" }
+                    h2 class="text-xl" { "Play the computer from here" }
+                    form hx-post="/games/create" hx-target="body" hx-push-url="true" {
+                        input type="hidden" name="fen" value=(fen);
+                        (time_control_select())
+                        (computer_select(has_engine))
+                        ("Play as:")
+                        div {
+                            button name="playing_as" value="black" { "Black" }
+                            button name="playing_as" value="white" { "White" }
+                        }
+                    }
+                    h2 class="text-xl" { "Play a friend from here" }
+                    form hx-post="/games/create" hx-target="body" hx-push-url="true" {
+                        input type="hidden" name="fen" value=(fen);
+                        (time_control_select())
+                        div {
+                            ("Opponent:")
+                            input type="text" name="opponent" placeholder="anyone with the link";
+                        }
+                        ("Play as:")
+                        div {
+                            button name="playing_as" value="black" { "Black" }
+                            button name="playing_as" value="white" { "White" }
+                        }
+                    }
+                } @else {
+                    p { "This position can't be played or analysed yet:" }
+                    ul class="list-disc pl-4" {
+                        @for problem in &problems {
+                            li { (problem) }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(fen: &str) -> Setup {
+        Setup::parse(fen).unwrap()
+    }
+
+    /// the position a link in the editor goes to
+    fn fen_of(href: &str) -> String {
+        let fen = href.split("fen=").nth(1).unwrap();
+
+        fen.split('&').next().unwrap().replace('+', " ")
+    }
+
+    fn square(name: &str) -> Position {
+        Position::parse(name).unwrap()
+    }
+
+    #[test]
+    fn tools_read_back_from_the_query_string() {
+        for tool in Tool::all() {
+            assert!(Tool::parse(&tool.as_str()) == Some(tool));
+        }
+
+        assert!(Tool::parse("Qq").is_none());
+        assert!(Tool::parse("x").is_none());
+    }
+
+    #[test]
+    fn pieces_are_picked_up_put_down_and_taken_off() {
+        let start = setup(STARTING_FEN);
+
+        let picked_up = start.clicked(Tool::Move, None, &square("g1"));
+        assert!(picked_up.ends_with("&from=g1"));
+
+        let moved = start.clicked(Tool::Move, Some(square("g1")), &square("f3"));
+        assert_eq!(
+            fen_of(&moved),
+            "rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R w KQkq - 0 1"
+        );
+
+        let removed = start.clicked(Tool::Remove, None, &square("d8"));
+        assert_eq!(
+            fen_of(&removed),
+            "rnb1kbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+        );
+
+        let put = setup(KINGS_ONLY_FEN).clicked(
+            Tool::Put(PieceKind::Rook, Color::Black),
+            None,
+            &square("a8"),
+        );
+        assert_eq!(fen_of(&put), "r3k3/8/8/8/8/8/8/4K3 w - - 0 1");
+    }
+
+    #[test]
+    fn a_moved_king_loses_its_castling() {
+        let start = setup(STARTING_FEN);
+
+        let there = start.clicked(Tool::Move, Some(square("e1")), &square("e3"));
+        let back = setup(&fen_of(&there)).clicked(Tool::Move, Some(square("e3")), &square("e1"));
+
+        assert!(fen_of(&back).ends_with(" w kq - 0 1"));
+    }
+
+    #[test]
+    fn castling_is_turned_on_and_off_where_the_pieces_allow() {
+        let setup = setup("r3k2r/8/8/8/8/8/8/4K2R w - - 0 1");
+
+        let on = setup.toggle_castling('K');
+        assert!(on.fen().ends_with(" w K - 0 1"));
+        assert!(on.toggle_castling('K').fen().ends_with(" w - - 0 1"));
+
+        // there's no rook on a1 to castle with
+        assert!(setup.toggle_castling('Q').fen().ends_with(" w - - 0 1"));
+    }
+}
@@ -0,0 +1,530 @@
+use crate::board::{Board, Move};
+use crate::piece::{Color, Piece, PieceKind};
+use crate::variant::Variant;
+
+/// more than any amount of material
+const MATE: i32 = 100_000;
+
+const INFINITY: i32 = 1_000_000;
+
+/// how strong the built-in engine plays, which is how far ahead it looks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Beginner,
+    Casual,
+    Intermediate,
+    Advanced,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [
+        Level::Beginner,
+        Level::Casual,
+        Level::Intermediate,
+        Level::Advanced,
+    ];
+
+    /// how many half-moves the search looks ahead
+    pub fn depth(&self) -> u8 {
+        match self {
+            Level::Beginner => 1,
+            Level::Casual => 2,
+            Level::Intermediate => 3,
+            Level::Advanced => 4,
+        }
+    }
+
+    /// the level as it is stored in the db
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Beginner => "beginner",
+            Level::Casual => "casual",
+            Level::Intermediate => "intermediate",
+            Level::Advanced => "advanced",
+        }
+    }
+
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "beginner" => Some(Level::Beginner),
+            "casual" => Some(Level::Casual),
+            "intermediate" => Some(Level::Intermediate),
+            "advanced" => Some(Level::Advanced),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Level::Beginner => "Beginner",
+            Level::Casual => "Casual",
+            Level::Intermediate => "Intermediate",
+            Level::Advanced => "Advanced",
+        }
+    }
+}
+
+/// what does the computer's thinking
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    BuiltIn(Level),
+    /// the external engine the server was started with
+    Uci,
+}
+
+impl Engine {
+    /// the engine as it is stored in the db
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Engine::BuiltIn(level) => level.as_str(),
+            Engine::Uci => "uci",
+        }
+    }
+
+    pub fn parse(engine: &str) -> Option<Self> {
+        match engine {
+            "uci" => Some(Engine::Uci),
+            level => Level::parse(level).map(Engine::BuiltIn),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Engine::BuiltIn(level) => level.label(),
+            Engine::Uci => "Engine",
+        }
+    }
+}
+
+/// the computer, sitting at one side of the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Computer {
+    pub color: Color,
+    pub engine: Engine,
+}
+
+/// the move `color` should make, or `None` if they have no legal moves
+pub fn best_move(board: &Board, color: Color, depth: u8) -> Option<Move> {
+    search_root(board, color, depth, None, &|| false).map(|(best, _)| best)
+}
+
+/// search one depth deeper at a time, until `max_depth` or until `should_stop`
+/// says to, calling `report` with the depth, score and best move after every
+/// depth that was searched all the way.
+/// returns the best move from the deepest complete search.
+pub fn iterative_deepening(
+    board: &Board,
+    color: Color,
+    max_depth: u8,
+    should_stop: &dyn Fn() -> bool,
+    mut report: impl FnMut(u8, i32, Move),
+) -> Option<Move> {
+    let mut best = None;
+
+    for depth in 1..=max_depth {
+        match search_root(board, color, depth, best, should_stop) {
+            Some((best_move, score)) => {
+                best = Some(best_move);
+                report(depth, score, best_move);
+            }
+            None => break,
+        }
+
+        if should_stop() {
+            break;
+        }
+    }
+
+    // even a search stopped right away has to come up with something
+    best.or_else(|| board.all_legal_moves(color).first().copied())
+}
+
+/// the best move and its score, or `None` if there are no moves
+/// or the search was stopped before it finished.
+/// `first` is tried before anything else, usually the best move of a shallower search.
+fn search_root(
+    board: &Board,
+    color: Color,
+    depth: u8,
+    first: Option<Move>,
+    should_stop: &dyn Fn() -> bool,
+) -> Option<(Move, i32)> {
+    let mut best = None;
+    let mut alpha = -INFINITY;
+
+    let mut moves = board.all_legal_moves(color);
+
+    order_moves(board, &mut moves);
+
+    if let Some(first) = first
+        && let Some(i) = moves.iter().position(|m| *m == first)
+    {
+        moves[..=i].rotate_right(1);
+    }
+
+    for m in moves {
+        let mut after = board.clone();
+        after.make_move(&m);
+
+        let score = -search(
+            &after,
+            color.invert(),
+            depth.saturating_sub(1),
+            -INFINITY,
+            -alpha,
+            should_stop,
+        );
+
+        if should_stop() {
+            return None;
+        }
+
+        if best.is_none() || score > alpha {
+            alpha = score;
+            best = Some(m);
+        }
+    }
+
+    best.map(|best| (best, alpha))
+}
+
+/// the score for `color`, to move, if the game is already over by the variant's own rules,
+/// like a king on the hill or a king blown up in Atomic.
+/// a game decided with more depth left was decided sooner, which is better for the winner.
+fn decided(board: &Board, color: Color, depth: u8) -> Option<i32> {
+    let (winner, _) = board.variant.winner(board)?;
+
+    if winner == color {
+        Some(MATE + depth as i32)
+    } else {
+        Some(-MATE - depth as i32)
+    }
+}
+
+/// how many moves until mate, if `score` from a search `depth` deep is one.
+/// negative if the side to move is the one getting mated.
+pub fn mate_in(score: i32, depth: u8) -> Option<i32> {
+    if score.abs() < MATE {
+        return None;
+    }
+
+    let plies = (depth as i32 - (score.abs() - MATE)).max(1);
+
+    Some(score.signum() * (plies + 1) / 2)
+}
+
+/// negamax with alpha-beta pruning, over the same legal moves the board offers.
+/// the score is from the point of view of `color`, the side to move.
+fn search(
+    board: &Board,
+    color: Color,
+    depth: u8,
+    mut alpha: i32,
+    beta: i32,
+    should_stop: &dyn Fn() -> bool,
+) -> i32 {
+    if should_stop() {
+        return 0;
+    }
+
+    if let Some(score) = decided(board, color, depth) {
+        return score;
+    }
+
+    // no moves at all is checkmate or stalemate,
+    // but in Antichess it's a win for whoever can't move
+    if !board.has_legal_moves(color) {
+        return if board.variant.stalemate_wins() {
+            MATE + depth as i32
+        } else if board.is_in_check(color) {
+            -MATE - depth as i32
+        } else {
+            0
+        };
+    }
+
+    if depth == 0 {
+        return evaluate(board, color);
+    }
+
+    let mut moves = board.all_legal_moves(color);
+
+    order_moves(board, &mut moves);
+
+    let mut best = -INFINITY;
+
+    for m in moves {
+        let mut after = board.clone();
+        after.make_move(&m);
+
+        let score = -search(
+            &after,
+            color.invert(),
+            depth - 1,
+            -beta,
+            -alpha,
+            should_stop,
+        );
+
+        best = best.max(score);
+        alpha = alpha.max(score);
+
+        if alpha >= beta {
+            break;
+        }
+    }
+
+    best
+}
+
+/// look at captures of valuable pieces by cheap pieces first,
+/// which lets alpha-beta cut off much more of the tree
+fn order_moves(board: &Board, moves: &mut [Move]) {
+    moves.sort_by_cached_key(|m| {
+        let victim = board.get_piece(&m.to).map_or(0, |piece| value(piece.kind));
+        let attacker = board
+            .get_piece(&m.from)
+            .map_or(0, |piece| value(piece.kind));
+
+        if victim > 0 {
+            -(victim * 10 - attacker)
+        } else {
+            0
+        }
+    });
+}
+
+/// material and piece placement, from the point of view of `color`.
+/// pieces in a pocket count for what they're worth on the board,
+/// and in Antichess material is something to get rid of.
+pub fn evaluate(board: &Board, color: Color) -> i32 {
+    let score = |color: Color| -> i32 {
+        board
+            .get_pieces(color)
+            .map(|piece| value(piece.kind) + placement(piece))
+            .sum::<i32>()
+            + board
+                .pocket(color)
+                .iter()
+                .map(|kind| value(*kind))
+                .sum::<i32>()
+    };
+
+    let score = score(color) - score(color.invert());
+
+    if board.variant == Variant::Antichess {
+        -score
+    } else {
+        score
+    }
+}
+
+/// in centipawns
+fn value(kind: PieceKind) -> i32 {
+    match kind {
+        PieceKind::Pawn => 100,
+        PieceKind::Knight => 320,
+        PieceKind::Bishop => 330,
+        PieceKind::Rook => 500,
+        PieceKind::Queen => 900,
+        PieceKind::King => 20_000,
+    }
+}
+
+/// how much better or worse a piece is on its square than anywhere else.
+/// the tables are written from white's side, eighth rank first.
+fn placement(piece: &Piece) -> i32 {
+    let table = match piece.kind {
+        PieceKind::Pawn => &PAWN,
+        PieceKind::Knight => &KNIGHT,
+        PieceKind::Bishop => &BISHOP,
+        PieceKind::Rook => &ROOK,
+        PieceKind::Queen => &QUEEN,
+        PieceKind::King => &KING,
+    };
+
+    let row = match piece.color {
+        Color::White => 7 - piece.position.row,
+        Color::Black => piece.position.row,
+    };
+
+    table[row as usize][piece.position.column as usize]
+}
+
+#[rustfmt::skip]
+const PAWN: [[i32; 8]; 8] = [
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+    [ 50,  50,  50,  50,  50,  50,  50,  50],
+    [ 10,  10,  20,  30,  30,  20,  10,  10],
+    [  5,   5,  10,  25,  25,  10,   5,   5],
+    [  0,   0,   0,  20,  20,   0,   0,   0],
+    [  5,  -5, -10,   0,   0, -10,  -5,   5],
+    [  5,  10,  10, -20, -20,  10,  10,   5],
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+];
+
+#[rustfmt::skip]
+const KNIGHT: [[i32; 8]; 8] = [
+    [-50, -40, -30, -30, -30, -30, -40, -50],
+    [-40, -20,   0,   0,   0,   0, -20, -40],
+    [-30,   0,  10,  15,  15,  10,   0, -30],
+    [-30,   5,  15,  20,  20,  15,   5, -30],
+    [-30,   0,  15,  20,  20,  15,   0, -30],
+    [-30,   5,  10,  15,  15,  10,   5, -30],
+    [-40, -20,   0,   5,   5,   0, -20, -40],
+    [-50, -40, -30, -30, -30, -30, -40, -50],
+];
+
+#[rustfmt::skip]
+const BISHOP: [[i32; 8]; 8] = [
+    [-20, -10, -10, -10, -10, -10, -10, -20],
+    [-10,   0,   0,   0,   0,   0,   0, -10],
+    [-10,   0,   5,  10,  10,   5,   0, -10],
+    [-10,   5,   5,  10,  10,   5,   5, -10],
+    [-10,   0,  10,  10,  10,  10,   0, -10],
+    [-10,  10,  10,  10,  10,  10,  10, -10],
+    [-10,   5,   0,   0,   0,   0,   5, -10],
+    [-20, -10, -10, -10, -10, -10, -10, -20],
+];
+
+#[rustfmt::skip]
+const ROOK: [[i32; 8]; 8] = [
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+    [  5,  10,  10,  10,  10,  10,  10,   5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [  0,   0,   0,   5,   5,   0,   0,   0],
+];
+
+#[rustfmt::skip]
+const QUEEN: [[i32; 8]; 8] = [
+    [-20, -10, -10,  -5,  -5, -10, -10, -20],
+    [-10,   0,   0,   0,   0,   0,   0, -10],
+    [-10,   0,   5,   5,   5,   5,   0, -10],
+    [ -5,   0,   5,   5,   5,   5,   0,  -5],
+    [  0,   0,   5,   5,   5,   5,   0,  -5],
+    [-10,   5,   5,   5,   5,   5,   0, -10],
+    [-10,   0,   5,   0,   0,   0,   0, -10],
+    [-20, -10, -10,  -5,  -5, -10, -10, -20],
+];
+
+#[rustfmt::skip]
+const KING: [[i32; 8]; 8] = [
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-20, -30, -30, -40, -40, -30, -30, -20],
+    [-10, -20, -20, -20, -20, -20, -20, -10],
+    [ 20,  20,   0,   0,   0,   0,  20,  20],
+    [ 20,  30,  10,   0,   0,  10,  30,  20],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(fen: &str, variant: Variant) -> (Board, Color) {
+        let (mut board, to_move) = Board::from_fen(fen).unwrap();
+        board.variant = variant;
+        (board, to_move)
+    }
+
+    #[test]
+    fn levels_and_engines_read_back_from_the_db() {
+        for level in Level::ALL {
+            assert_eq!(Level::parse(level.as_str()), Some(level));
+            assert_eq!(
+                Engine::parse(Engine::BuiltIn(level).as_str()),
+                Some(Engine::BuiltIn(level))
+            );
+        }
+
+        assert_eq!(Engine::parse("uci"), Some(Engine::Uci));
+        assert_eq!(Engine::parse("grandmaster"), None);
+    }
+
+    #[test]
+    fn the_starting_position_is_even() {
+        let (board, _) = board(crate::board::STARTING_FEN, Variant::Standard);
+
+        assert_eq!(evaluate(&board, Color::White), 0);
+        assert_eq!(evaluate(&board, Color::Black), 0);
+    }
+
+    #[test]
+    fn takes_a_piece_left_hanging() {
+        let (board, to_move) = board("4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1", Variant::Standard);
+
+        for level in Level::ALL {
+            assert_eq!(
+                best_move(&board, to_move, level.depth()).unwrap().uci(),
+                "d2d5"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_mate_in_two_a_few_moves_deep() {
+        // the rooks take turns up the board, Ra7 then Rb8
+        let (board, to_move) = board("6k1/8/8/8/8/8/R7/1R4K1 w - - 0 1", Variant::Standard);
+
+        let best = best_move(&board, to_move, 3).unwrap();
+        let mut after = board.clone();
+        after.make_move(&best);
+
+        assert!(search(&after, Color::Black, 2, -INFINITY, INFINITY, &|| false) <= -MATE);
+    }
+
+    #[test]
+    fn stalemated_has_no_move() {
+        let (board, to_move) = board("k7/8/1Q6/8/8/8/8/K7 b - - 0 1", Variant::Standard);
+
+        assert_eq!(best_move(&board, to_move, 2), None);
+    }
+
+    #[test]
+    fn finds_mate_in_one() {
+        let (board, to_move) = board("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", Variant::Standard);
+
+        let best = best_move(&board, to_move, 2).unwrap();
+
+        assert_eq!(best.uci(), "a1a8");
+    }
+
+    #[test]
+    fn antichess_only_plays_captures_when_it_can_capture() {
+        let (board, to_move) = board("8/8/8/8/8/3p4/8/3R3K w - - 0 1", Variant::Antichess);
+
+        for depth in 1..=3 {
+            assert_eq!(best_move(&board, to_move, depth).unwrap().uci(), "d1d3");
+        }
+    }
+
+    #[test]
+    fn antichess_no_moves_left_wins() {
+        let (board, to_move) = board("8/8/8/8/8/p7/P7/8 b - - 0 1", Variant::Antichess);
+
+        assert!(search(&board, to_move, 2, -INFINITY, INFINITY, &|| false) >= MATE);
+    }
+
+    #[test]
+    fn antichess_forces_its_last_piece_to_be_taken() {
+        // after Ra2 black has to take, which leaves white with nothing
+        let (board, to_move) = board("8/8/8/8/8/1p6/8/R7 w - - 0 1", Variant::Antichess);
+
+        assert_eq!(best_move(&board, to_move, 2).unwrap().uci(), "a1a2");
+    }
+
+    #[test]
+    fn king_of_the_hill_walks_onto_the_hill() {
+        let (board, to_move) = board("7k/8/8/8/8/4K3/8/8 w - - 0 1", Variant::KingOfTheHill);
+
+        let best = best_move(&board, to_move, 2).unwrap();
+
+        assert!(["e3d4", "e3e4"].contains(&best.uci().as_str()));
+    }
+}
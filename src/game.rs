@@ -0,0 +1,643 @@
+use crate::board::{Board, Move, STARTING_FEN};
+use crate::clock::Clock;
+use crate::engine::{Computer, Engine};
+use crate::pgn;
+use crate::piece::{Color, Piece, PieceKind, Position};
+use crate::player::{self, PlayerId};
+use crate::rating::{self, Category, Rating};
+use crate::variant::Variant;
+use sqlx::{Connection, SqliteConnection};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use uuid::Uuid;
+use uuid::fmt::Hyphenated;
+
+/// what the board shows about whoever is sitting at a color
+#[derive(Clone, Default)]
+pub struct Profile {
+    /// `None` for players who haven't registered
+    pub name: Option<String>,
+    /// `None` until they finish a rated game in the game's category
+    pub rating: Option<Rating>,
+}
+
+/// something happened to a game that everyone looking at it should see
+#[derive(Clone, Copy, Debug)]
+pub enum GameEvent {
+    /// the second player took their seat
+    Joined,
+    Moved,
+    Finished,
+    /// someone started or stopped watching
+    Spectators,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win(Color),
+    Draw,
+}
+
+impl Outcome {
+    /// the result as it is written in the db and in PGN
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Win(Color::White) => "1-0",
+            Outcome::Win(Color::Black) => "0-1",
+            Outcome::Draw => "1/2-1/2",
+        }
+    }
+
+    pub fn parse(result: &str) -> Option<Self> {
+        match result {
+            "1-0" => Some(Outcome::Win(Color::White)),
+            "0-1" => Some(Outcome::Win(Color::Black)),
+            "1/2-1/2" => Some(Outcome::Draw),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    Checkmate,
+    Stalemate,
+    Resignation,
+    Timeout,
+    /// both players agreed to a draw
+    Agreement,
+    /// a king reached the centre
+    KingOfTheHill,
+    /// someone gave their third check
+    ThreeChecks,
+    /// a king was caught in an explosion, in Atomic
+    Explosion,
+    /// someone got rid of every piece they had, in Antichess
+    NoPiecesLeft,
+}
+
+impl Termination {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Termination::Checkmate => "checkmate",
+            Termination::Stalemate => "stalemate",
+            Termination::Resignation => "resignation",
+            Termination::Timeout => "timeout",
+            Termination::Agreement => "agreement",
+            Termination::KingOfTheHill => "king of the hill",
+            Termination::ThreeChecks => "three checks",
+            Termination::Explosion => "explosion",
+            Termination::NoPiecesLeft => "no pieces left",
+        }
+    }
+
+    pub fn parse(termination: &str) -> Option<Self> {
+        match termination {
+            "checkmate" => Some(Termination::Checkmate),
+            "stalemate" => Some(Termination::Stalemate),
+            "resignation" => Some(Termination::Resignation),
+            "timeout" => Some(Termination::Timeout),
+            "agreement" => Some(Termination::Agreement),
+            "king of the hill" => Some(Termination::KingOfTheHill),
+            "three checks" => Some(Termination::ThreeChecks),
+            "explosion" => Some(Termination::Explosion),
+            "no pieces left" => Some(Termination::NoPiecesLeft),
+            _ => None,
+        }
+    }
+}
+
+/// how a game ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameResult {
+    pub outcome: Outcome,
+    pub termination: Termination,
+}
+
+impl GameResult {
+    pub fn description(&self) -> String {
+        let winner = match self.outcome {
+            Outcome::Win(Color::White) => "White wins",
+            Outcome::Win(Color::Black) => "Black wins",
+            Outcome::Draw => "Draw",
+        };
+
+        let how = match self.termination {
+            Termination::Checkmate => "by checkmate",
+            Termination::Stalemate => "by stalemate",
+            Termination::Resignation => "by resignation",
+            Termination::Timeout => "on time",
+            Termination::Agreement => "by agreement",
+            Termination::KingOfTheHill => "by reaching the centre",
+            Termination::ThreeChecks => "by three checks",
+            Termination::Explosion => "by blowing up the king",
+            Termination::NoPiecesLeft => "by losing every piece",
+        };
+
+        format!("{} · {winner} {how}", self.outcome.as_str())
+    }
+}
+
+// TODO figure out how/what to store for each individual game
+// such that we can display the currently selected piece, prospective moves, etc.
+pub struct GameState {
+    pub variant: Variant,
+    /// which of the 960 starting positions a Chess960 game started from
+    pub chess960_position: Option<u16>,
+    /// the position the game started from
+    pub starting_fen: String,
+    /// every move played so far, in order
+    pub moves: Vec<Move>,
+    pub board: Board,
+    pub selected: Option<Position>,
+    /// the pocket piece about to be dropped, in variants with pockets
+    pub selected_drop: Option<PieceKind>,
+    pub possible_moves: Vec<Position>,
+    pub takes: Vec<Piece>,
+    pub to_move: Color,
+    /// number of half-moves played so far
+    pub ply: i64,
+    pub white_player: Option<PlayerId>,
+    pub black_player: Option<PlayerId>,
+    /// the only player who can take the open seat, for challenges sent to someone
+    pub challenged: Option<PlayerId>,
+    pub white_profile: Profile,
+    pub black_profile: Profile,
+    /// the built-in engine, if it is playing one of the seats
+    pub computer: Option<Computer>,
+    /// whether the computer is working out its next move
+    pub computer_thinking: bool,
+    /// `None` for untimed games
+    pub clock: Option<Clock>,
+    /// whether the result counts towards the players' ratings
+    pub rated: bool,
+    /// a game read from PGN, which no one can sit down and carry on
+    pub imported: bool,
+    /// `None` while the game is in progress
+    pub result: Option<GameResult>,
+    pub events: broadcast::Sender<GameEvent>,
+    /// how many people who aren't playing are watching
+    pub spectators: usize,
+    /// when a handler last touched this game,
+    /// used to evict games nobody is looking at
+    pub last_seen: Instant,
+}
+
+impl GameState {
+    pub fn new() -> Self {
+        Self {
+            variant: Variant::Standard,
+            chess960_position: None,
+            starting_fen: STARTING_FEN.to_string(),
+            moves: vec![],
+            board: Board::new(),
+            selected: None,
+            selected_drop: None,
+            possible_moves: vec![],
+            takes: vec![],
+            to_move: Color::White,
+            ply: 0,
+            white_player: None,
+            black_player: None,
+            challenged: None,
+            white_profile: Profile::default(),
+            black_profile: Profile::default(),
+            computer: None,
+            computer_thinking: false,
+            clock: None,
+            rated: false,
+            imported: false,
+            result: None,
+            events: broadcast::channel(16).0,
+            spectators: 0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// which color `player` is playing, if they are playing at all
+    pub fn seat(&self, player: PlayerId) -> Option<Color> {
+        if self.white_player == Some(player) {
+            Some(Color::White)
+        } else if self.black_player == Some(player) {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// the seat still waiting for a player, white first
+    pub fn open_seat(&self) -> Option<Color> {
+        [Color::White, Color::Black]
+            .into_iter()
+            .find(|color| !self.is_seated(*color))
+    }
+
+    /// whether someone, or the computer, is playing `color`
+    pub fn is_seated(&self, color: Color) -> bool {
+        let player = match color {
+            Color::White => self.white_player,
+            Color::Black => self.black_player,
+        };
+
+        player.is_some()
+            || self
+                .computer
+                .is_some_and(|computer| computer.color == color)
+    }
+
+    /// the seat `player` would get by joining, if they can join at all
+    pub fn can_join(&self, player: PlayerId) -> Option<Color> {
+        if self.seat(player).is_some()
+            || self.result.is_some()
+            || self.imported
+            || self
+                .challenged
+                .is_some_and(|challenged| challenged != player)
+        {
+            return None;
+        }
+
+        self.open_seat()
+    }
+
+    /// the computer, if it is the one to move in a game that's still going
+    pub fn computer_to_move(&self) -> Option<Computer> {
+        self.computer
+            .filter(|computer| computer.color == self.to_move && self.result.is_none())
+    }
+
+    pub fn sit(&mut self, color: Color, player: PlayerId) {
+        match color {
+            Color::White => self.white_player = Some(player),
+            Color::Black => self.black_player = Some(player),
+        }
+    }
+
+    pub fn profile(&self, color: Color) -> &Profile {
+        match color {
+            Color::White => &self.white_profile,
+            Color::Black => &self.black_profile,
+        }
+    }
+
+    /// ratings are kept apart by time control
+    pub fn category(&self) -> Category {
+        Category::of(self.clock.as_ref())
+    }
+
+    /// look up the names and ratings of whoever is sitting at the board
+    pub async fn load_profiles(&mut self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        let category = self.category();
+
+        for (player, profile) in [
+            (self.white_player, &mut self.white_profile),
+            (self.black_player, &mut self.black_profile),
+        ] {
+            *profile = match player {
+                Some(player) => Profile {
+                    name: player::name(conn, player).await?,
+                    rating: rating::rating(conn, player, category).await?,
+                },
+                None => Profile::default(),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// the game starts once both seats are taken
+    pub fn has_started(&self) -> bool {
+        self.is_seated(Color::White) && self.is_seated(Color::Black)
+    }
+
+    /// the player whose time ran out in a game that hasn't ended yet
+    pub fn flagged(&self) -> Option<Color> {
+        if self.result.is_some() {
+            return None;
+        }
+
+        self.clock.as_ref().and_then(|clock| clock.flagged())
+    }
+
+    /// the legal move the player to move wrote, in UCI or algebraic notation.
+    /// a pawn reaching the last row without saying what it becomes becomes a queen.
+    pub fn parse_move(&self, written: &str) -> Option<Move> {
+        let written = written.trim();
+
+        let m = Move::parse_uci(written)
+            .or_else(|| pgn::parse_san(&self.board, self.to_move, written))?;
+
+        self.board
+            .all_legal_moves(self.to_move)
+            .into_iter()
+            .find(|legal| {
+                legal.from == m.from
+                    && legal.to == m.to
+                    && legal.drop == m.drop
+                    && m.promotion.is_none_or(|kind| Some(kind) == legal.promotion)
+            })
+    }
+
+    /// the result if the position on the board ends the game:
+    /// a win by the variant's own rules, or the player to move having no moves left
+    pub fn result_on_board(&self) -> Option<GameResult> {
+        if let Some((winner, termination)) = self.variant.winner(&self.board) {
+            Some(GameResult {
+                outcome: Outcome::Win(winner),
+                termination,
+            })
+        } else if self.board.has_legal_moves(self.to_move) {
+            None
+        } else if self.board.is_in_check(self.to_move) {
+            Some(GameResult {
+                outcome: Outcome::Win(self.to_move.invert()),
+                termination: Termination::Checkmate,
+            })
+        } else if self.variant.stalemate_wins() {
+            Some(GameResult {
+                outcome: Outcome::Win(self.to_move),
+                termination: Termination::Stalemate,
+            })
+        } else {
+            Some(GameResult {
+                outcome: Outcome::Draw,
+                termination: Termination::Stalemate,
+            })
+        }
+    }
+
+    /// record how the game ended, in memory and in the db,
+    /// update the players' ratings if the game was rated,
+    /// and tell everyone watching
+    pub async fn finish(
+        &mut self,
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        result: GameResult,
+    ) -> anyhow::Result<()> {
+        let mut tx = conn.begin().await?;
+
+        let finished = sqlx::query(
+            "
+    update games
+    set result = ?, termination = ?, updated_at = CURRENT_TIMESTAMP
+    where id = ? and result is null;
+    ",
+        )
+        .bind(result.outcome.as_str())
+        .bind(result.termination.as_str())
+        .bind(game_id)
+        .execute(&mut *tx)
+        .await?;
+
+        // a game that already had a result doesn't count twice,
+        // and the ratings are for standard chess only
+        if finished.rows_affected() == 1
+            && self.rated
+            && self.variant == Variant::Standard
+            && let (Some(white), Some(black)) = (self.white_player, self.black_player)
+        {
+            rating::record_game(
+                &mut tx,
+                game_id,
+                self.category(),
+                white,
+                black,
+                result.outcome,
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        self.result = Some(result);
+
+        self.load_profiles(conn).await?;
+
+        if let Some(clock) = &mut self.clock {
+            clock.stop();
+        }
+
+        self.selected = None;
+        self.selected_drop = None;
+        self.possible_moves.clear();
+
+        let _ = self.events.send(GameEvent::Finished);
+
+        Ok(())
+    }
+
+    /// rebuild a game by replaying its moves from the db.
+    /// returns `None` if there is no such game.
+    pub async fn load(conn: &mut SqliteConnection, game_id: Uuid) -> anyhow::Result<Option<Self>> {
+        let game: Option<GameRow> = sqlx::query_as(
+            "
+    select
+        white_player,
+        black_player,
+        challenged,
+        time_control,
+        rated,
+        imported,
+        computer,
+        white_name,
+        black_name,
+        result,
+        termination,
+        variant,
+        chess960_position,
+        starting_fen,
+        -- how long the player to move has been thinking
+        cast(
+            (julianday('now') - julianday(
+                (select max(inserted_at) from moves where moves.game_id = games.id)
+            )) * 86400000 as integer
+        ) as thinking_ms
+    from games
+    where id = ?;
+    ",
+        )
+        .bind(game_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        let Some(game) = game else {
+            return Ok(None);
+        };
+
+        let moves: Vec<MoveRow> = sqlx::query_as(
+            "
+    select
+        from_column,
+        from_row,
+        to_column,
+        to_row,
+        promotion,
+        dropped,
+        clock_ms
+    from moves
+    where game_id = ?
+    order by ply asc;
+    ",
+        )
+        .bind(game_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut game_state = Self::new();
+
+        game_state.variant = Variant::parse(&game.variant).unwrap_or_default();
+        game_state.chess960_position = game.chess960_position;
+        game_state.starting_fen = game.starting_fen;
+
+        game_state.white_player = game.white_player.map(|id| PlayerId(id.into_uuid()));
+        game_state.black_player = game.black_player.map(|id| PlayerId(id.into_uuid()));
+        game_state.challenged = game.challenged.map(|id| PlayerId(id.into_uuid()));
+
+        game_state.clock = game.time_control.as_deref().and_then(Clock::parse);
+
+        game_state.rated = game.rated;
+
+        game_state.imported = game.imported;
+
+        // the computer plays whichever seat no one is sitting in
+        game_state.computer = game
+            .computer
+            .as_deref()
+            .and_then(Engine::parse)
+            .zip(game_state.open_seat())
+            .map(|(engine, color)| Computer { color, engine });
+
+        game_state.load_profiles(conn).await?;
+
+        // players of imported games are only known by name
+        for (name, profile) in [
+            (game.white_name, &mut game_state.white_profile),
+            (game.black_name, &mut game_state.black_profile),
+        ] {
+            if profile.name.is_none() {
+                profile.name = name;
+            }
+        }
+
+        game_state.result = game
+            .result
+            .as_deref()
+            .and_then(Outcome::parse)
+            .zip(game.termination.as_deref().and_then(Termination::parse))
+            .map(|(outcome, termination)| GameResult {
+                outcome,
+                termination,
+            });
+
+        game_state.ply = moves.len() as i64;
+
+        let (board, mut mover) = Board::from_fen(&game_state.starting_fen)
+            .ok_or_else(|| anyhow::anyhow!("game {game_id} has a bad starting position"))?;
+
+        game_state.board = board;
+        game_state.board.variant = game_state.variant;
+
+        for row in moves {
+            let m = Move {
+                from: (row.from_column, row.from_row).into(),
+                to: (row.to_column, row.to_row).into(),
+                promotion: row
+                    .promotion
+                    .and_then(|promotion| promotion.chars().next())
+                    .and_then(PieceKind::parse),
+                drop: row
+                    .dropped
+                    .and_then(|dropped| dropped.chars().next())
+                    .and_then(PieceKind::parse),
+            };
+
+            // imported games can have moves the board doesn't offer yet,
+            // like en passant
+            if let Some(take) = game_state.board.make_move(&m) {
+                game_state.takes.push(take);
+            }
+
+            game_state.moves.push(m);
+
+            if let Some(clock) = &mut game_state.clock
+                && let Some(clock_ms) = row.clock_ms
+            {
+                clock.set_remaining(mover, Duration::from_millis(clock_ms as u64));
+            }
+
+            mover = mover.invert();
+        }
+
+        game_state.to_move = mover;
+
+        // the clock starts with the first move,
+        // and has been running for the player to move ever since the last one
+        if let Some(clock) = &mut game_state.clock
+            && game_state.ply > 0
+            && game_state.result.is_none()
+        {
+            let thinking = Duration::from_millis(game.thinking_ms.unwrap_or(0).max(0) as u64);
+
+            clock.start(
+                game_state.to_move,
+                Instant::now()
+                    .checked_sub(thinking)
+                    .unwrap_or_else(Instant::now),
+            );
+        }
+
+        Ok(Some(game_state))
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct GameRow {
+    white_player: Option<Hyphenated>,
+    black_player: Option<Hyphenated>,
+    challenged: Option<Hyphenated>,
+    time_control: Option<String>,
+    rated: bool,
+    imported: bool,
+    computer: Option<String>,
+    white_name: Option<String>,
+    black_name: Option<String>,
+    result: Option<String>,
+    termination: Option<String>,
+    variant: String,
+    chess960_position: Option<u16>,
+    starting_fen: String,
+    thinking_ms: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct MoveRow {
+    from_column: i8,
+    from_row: i8,
+    to_column: i8,
+    to_row: i8,
+    promotion: Option<String>,
+    dropped: Option<String>,
+    clock_ms: Option<i64>,
+}
+
+/// the `games` column holding the player sitting at `color`
+pub fn seat_column(color: Color) -> &'static str {
+    match color {
+        Color::White => "white_player",
+        Color::Black => "black_player",
+    }
+}
+
+#[derive(Debug)]
+pub struct GameNotFound(pub Uuid);
+
+impl fmt::Display for GameNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no game with id {}", self.0)
+    }
+}
+
+impl std::error::Error for GameNotFound {}
@@ -0,0 +1,381 @@
+use crate::clock::Clock;
+use crate::engine::Engine;
+use crate::piece::Color;
+use crate::rating::{Category, Rating};
+use crate::variant::Variant;
+use crate::{AppError, AppState, layout};
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use maud::{Markup, html};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+const PAGE_SIZE: i64 = 20;
+
+/// the furthest page whose offset, and the page after it, still fit in an i64
+const MAX_PAGE: i64 = i64::MAX / PAGE_SIZE;
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GameStatus {
+    /// waiting for an opponent to take the empty seat
+    #[default]
+    Open,
+    /// both seats are taken and there is no result yet
+    Ongoing,
+    /// there is a result
+    Finished,
+}
+
+impl GameStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GameStatus::Open => "open",
+            GameStatus::Ongoing => "ongoing",
+            GameStatus::Finished => "finished",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            GameStatus::Open => "Open",
+            GameStatus::Ongoing => "Playing now",
+            GameStatus::Finished => "Recently finished",
+        }
+    }
+
+    fn filter(&self) -> &'static str {
+        match self {
+            GameStatus::Open => {
+                "games.result is null and not games.imported and games.computer is null and games.challenged is null and (games.white_player is null or games.black_player is null)"
+            }
+            GameStatus::Ongoing => {
+                "games.result is null and (games.computer is not null or (games.white_player is not null and games.black_player is not null))"
+            }
+            GameStatus::Finished => "games.result is not null",
+        }
+    }
+
+    fn order(&self) -> &'static str {
+        match self {
+            GameStatus::Open | GameStatus::Ongoing => "games.inserted_at desc",
+            GameStatus::Finished => "games.updated_at desc",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LobbyParams {
+    #[serde(default)]
+    status: GameStatus,
+    page: Option<i64>,
+    time_control: Option<String>,
+}
+
+impl LobbyParams {
+    /// past the last page there's nothing to show, so a page too far along
+    /// to work out the offset of is as good as the last one that has one
+    fn page(&self) -> i64 {
+        self.page.unwrap_or(1).clamp(1, MAX_PAGE)
+    }
+
+    fn time_control(&self) -> Option<&str> {
+        self.time_control.as_deref().filter(|tc| !tc.is_empty())
+    }
+
+    fn href(&self, path: &str, page: i64) -> String {
+        let mut href = format!("{path}?status={}&page={page}", self.status.as_str());
+
+        if let Some(time_control) = self.time_control() {
+            href.push_str(&format!(
+                "&time_control={}",
+                time_control.replace('+', "%2B")
+            ));
+        }
+
+        href
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct GameRow {
+    id: Uuid,
+    white_player: Option<String>,
+    black_player: Option<String>,
+    white_name: Option<String>,
+    black_name: Option<String>,
+    rated: bool,
+    computer: Option<String>,
+    result: Option<String>,
+    termination: Option<String>,
+    time_control: Option<String>,
+    variant: String,
+    moves: i64,
+    white_rating: Option<f64>,
+    white_deviation: Option<f64>,
+    white_volatility: Option<f64>,
+    black_rating: Option<f64>,
+    black_deviation: Option<f64>,
+    black_volatility: Option<f64>,
+}
+
+impl GameRow {
+    /// the rating of whoever is sitting in `color`'s seat, in the game's category
+    fn rating(&self, color: Color) -> Option<Rating> {
+        let (rating, deviation, volatility) = match color {
+            Color::White => (
+                self.white_rating,
+                self.white_deviation,
+                self.white_volatility,
+            ),
+            Color::Black => (
+                self.black_rating,
+                self.black_deviation,
+                self.black_volatility,
+            ),
+        };
+
+        Some(Rating {
+            rating: rating?,
+            deviation: deviation?,
+            volatility: volatility?,
+        })
+    }
+}
+
+pub async fn games_index(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<LobbyParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let state = state.lock().await;
+
+    let games = games_table(&state, &params).await?;
+
+    Ok(layout! {
+        html! {
+            div class="max-w-3xl mx-auto p-4" {
+                div class="flex justify-between items-center mb-4" {
+                    h1 class="text-2xl" { "Games" }
+                    div class="flex gap-4" {
+                        a href="/account" class="underline" { "Account" }
+                        a href="/games/new" class="underline" { "New game" }
+                    }
+                }
+                nav class="flex gap-4 mb-4" {
+                    @for status in [GameStatus::Open, GameStatus::Ongoing, GameStatus::Finished] {
+                        a
+                            href=(format!("/games?status={}", status.as_str()))
+                            class=(if status == params.status { "font-bold" } else { "underline" })
+                        {
+                            (status.label())
+                        }
+                    }
+                    form action="/games" method="get" class="ml-auto" {
+                        input type="hidden" name="status" value=(params.status.as_str());
+                        input
+                            type="text"
+                            name="time_control"
+                            placeholder="time control, e.g. 300+3"
+                            value=(params.time_control().unwrap_or(""));
+                    }
+                }
+                (games)
+            }
+        }
+    })
+}
+
+pub async fn games_list(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<LobbyParams>,
+) -> Result<Markup, AppError> {
+    let state = state.lock().await;
+
+    games_table(&state, &params).await
+}
+
+/// the list of games for a lobby tab.
+/// it polls itself so the lobby stays up to date without a reload.
+async fn games_table(state: &AppState, params: &LobbyParams) -> Result<Markup, AppError> {
+    let mut conn = state.pool.acquire().await?;
+
+    let page = params.page();
+
+    let mut games: Vec<GameRow> = sqlx::query_as(&format!(
+        "
+    select
+        games.id,
+        white_player,
+        black_player,
+        coalesce(white.name, games.white_name) as white_name,
+        coalesce(black.name, games.black_name) as black_name,
+        rated,
+        computer,
+        result,
+        termination,
+        time_control,
+        variant,
+        (select count(*) from moves where moves.game_id = games.id) as moves,
+        white_rating.rating as white_rating,
+        white_rating.deviation as white_deviation,
+        white_rating.volatility as white_volatility,
+        black_rating.rating as black_rating,
+        black_rating.deviation as black_deviation,
+        black_rating.volatility as black_volatility
+    from games
+    left join users as white on white.id = games.white_player
+    left join users as black on black.id = games.black_player
+    left join ratings as white_rating
+        on white_rating.user_id = games.white_player and white_rating.category = {category}
+    left join ratings as black_rating
+        on black_rating.user_id = games.black_player and black_rating.category = {category}
+    where {}
+    and (? is null or games.time_control = ?)
+    order by {}
+    limit ? offset ?;
+    ",
+        params.status.filter(),
+        params.status.order(),
+        category = Category::sql_of("games.time_control"),
+    ))
+    .bind(params.time_control())
+    .bind(params.time_control())
+    // one extra row tells us whether there is a next page
+    .bind(PAGE_SIZE + 1)
+    .bind((page - 1) * PAGE_SIZE)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let has_next_page = games.len() as i64 > PAGE_SIZE;
+
+    games.truncate(PAGE_SIZE as usize);
+
+    Ok(html! {
+        div
+            id="games-list"
+            hx-get=(params.href("/games/list", page))
+            hx-trigger="every 5s"
+            hx-swap="outerHTML"
+        {
+            @if games.is_empty() {
+                p { "No games here yet." }
+            } @else {
+                table class="w-full" {
+                    thead {
+                        tr class="text-left" {
+                            th { "White" }
+                            th { "Black" }
+                            th { "Time control" }
+                            th {}
+                            th { "Moves" }
+                            th { "Result" }
+                            th {}
+                        }
+                    }
+                    tbody {
+                        @for game in &games {
+                            tr {
+                                td { (seat_label(&game.white_player, &game.white_name, game.rating(Color::White), &game.computer)) }
+                                td { (seat_label(&game.black_player, &game.black_name, game.rating(Color::Black), &game.computer)) }
+                                td {
+                                    @if let Some(clock) = game.time_control.as_deref().and_then(Clock::parse) {
+                                        (clock.label())
+                                    } @else {
+                                        "untimed"
+                                    }
+                                }
+                                td {
+                                    @if game.rated { "rated" } @else { "casual" }
+                                    @if let Some(variant) = Variant::parse(&game.variant).filter(|variant| *variant != Variant::Standard) {
+                                        " · " (variant.label())
+                                    }
+                                }
+                                td { ((game.moves + 1) / 2) }
+                                td {
+                                    @if let Some(result) = &game.result {
+                                        (result)
+                                        @if let Some(termination) = &game.termination {
+                                            " (" (termination) ")"
+                                        }
+                                    }
+                                }
+                                td {
+                                    a
+                                        href=(match params.status {
+                                            GameStatus::Open => format!("/games/{}/join", game.id),
+                                            _ => format!("/games/{}/play", game.id),
+                                        })
+                                        class="underline"
+                                    {
+                                        @match params.status {
+                                            GameStatus::Open => "Play",
+                                            GameStatus::Ongoing => "Watch",
+                                            GameStatus::Finished => "View",
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            div class="flex justify-between mt-4" {
+                @if page > 1 {
+                    a href=(params.href("/games", page - 1)) class="underline" { "Previous" }
+                } @else {
+                    span {}
+                }
+                @if has_next_page {
+                    a href=(params.href("/games", page + 1)) class="underline" { "Next" }
+                }
+            }
+        }
+    })
+}
+
+/// the computer plays whichever seat has no player in games against it
+fn seat_label(
+    player: &Option<String>,
+    name: &Option<String>,
+    rating: Option<Rating>,
+    computer: &Option<String>,
+) -> String {
+    match (player, name) {
+        (Some(_), Some(name)) => format!("{name} {}", rating.unwrap_or_default().label()),
+        // players of imported games
+        (None, Some(name)) => name.clone(),
+        (Some(_), None) => "anonymous".to_string(),
+        (None, None) => match computer.as_deref().and_then(Engine::parse) {
+            Some(engine) => format!("computer ({})", engine.label()),
+            None => "waiting".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: Option<i64>) -> i64 {
+        LobbyParams {
+            status: GameStatus::Open,
+            page,
+            time_control: None,
+        }
+        .page()
+    }
+
+    #[test]
+    fn pages_stay_in_range() {
+        assert_eq!(page(None), 1);
+        assert_eq!(page(Some(-3)), 1);
+        assert_eq!(page(Some(7)), 7);
+        assert_eq!(page(Some(i64::MAX)), MAX_PAGE);
+
+        // the offset and the link to the next page can both be worked out
+        assert!(MAX_PAGE.checked_add(1).is_some());
+        assert!((MAX_PAGE - 1).checked_mul(PAGE_SIZE).is_some());
+    }
+}
@@ -1,39 +1,76 @@
 // TODO
 //
-// - [ ] load game from db
+// - [x] load game from db
 // - [x] store game moves in db
-// - [ ] broadcast move to opponent and spectators
-// - [ ] turns
+// - [x] broadcast move to opponent and spectators
+// - [x] turns
 // - [ ] takes view
 // - [x] regular moves
 // - [x] takes
-// - [ ] special moves (en passant, castling)
+// - [x] castling, including Chess960
+// - [ ] en passant
 // - [ ] users/csrf/magiclinks
-// - [ ] something to interactively update game state and show when
+// - [x] something to interactively update game state and show when
 //       opponent moves, my turn, etc. (sse, polling, etc.)
 // - [ ] fly deploy (dockerfile, fly.toml)
 
-use crate::board::Board;
-use crate::piece::{Color, Piece, Position};
-use axum::Router;
+use crate::board::Move;
+use crate::board::{Board, Glyphs};
+use crate::clock::{Clock, format_duration};
+use crate::engine::{Computer, Engine, Level};
+use crate::game::{
+    GameEvent, GameNotFound, GameResult, GameState, Outcome, Termination, seat_column,
+};
+use crate::piece::{Color, Piece, PieceKind, Position};
+use crate::player::{PlayerEvent, PlayerId};
+use crate::variant::Variant;
 use axum::extract::{Path, Query, State};
 use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post, put};
-use clap::Parser;
+use axum::{Extension, Form, Router};
+use clap::{Args, Parser, Subcommand};
 use maud::{Markup, html};
 use serde::Deserialize;
-use sqlx::{Acquire, Pool, Sqlite};
+use sqlx::{Pool, Sqlite};
 use std::collections::HashMap;
-use std::str::FromStr;
+use std::convert::Infallible;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::time::Instant;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tower_http::compression::Predicate;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType};
 use tracing::level_filters::LevelFilter;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 use uuid::Uuid;
 
+mod analysis;
+mod annotation;
+mod api;
 mod board;
+mod bot;
+mod cli;
+mod clock;
+mod editor;
+mod engine;
+mod game;
+mod lobby;
+mod matchmaking;
+mod pgn;
 mod piece;
+mod player;
+mod rating;
+mod replay;
+mod review;
+mod tui;
+mod uci;
+mod variant;
+mod variation;
 
 macro_rules! layout {
     ($content:expr) => {
@@ -46,6 +83,7 @@ macro_rules! layout {
                     title { "chess" }
                     script src="https://cdn.jsdelivr.net/npm/@tailwindcss/browser@4" {}
                     script src="https://cdn.jsdelivr.net/npm/htmx.org@2.0.6/dist/htmx.min.js" {}
+                    script src="https://cdn.jsdelivr.net/npm/htmx-ext-sse@2.2.2/sse.js" {}
                     style {
                         ".bg-dark {
                             background-color: gray;
@@ -59,6 +97,20 @@ macro_rules! layout {
                         "
 
                     }
+                    // count down whichever clock is running,
+                    // starting from the time the server rendered
+                    script {
+                        (maud::PreEscaped("
+                        setInterval(() => {
+                            for (const clock of document.querySelectorAll('[data-clock-running]')) {
+                                clock.dataset.deadline ??= Date.now() + Number(clock.dataset.clockRunning);
+                                const seconds = Math.max(0, Math.ceil((clock.dataset.deadline - Date.now()) / 1000));
+                                clock.textContent = String(Math.floor(seconds / 60)).padStart(2, '0')
+                                    + ':' + String(seconds % 60).padStart(2, '0');
+                            }
+                        }, 200);
+                        "))
+                    }
                 }
                 ($content)
             }
@@ -66,147 +118,856 @@ macro_rules! layout {
     };
 }
 
-async fn games_new() -> Result<impl IntoResponse, AppError> {
+pub(crate) use layout;
+
+/// the time controls offered when creating a game, as stored in the db
+const TIME_CONTROLS: [&str; 6] = ["60+0", "180+2", "300+3", "600+0", "900+10", "1800+0"];
+
+async fn games_new(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<impl IntoResponse, AppError> {
+    let state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let registered = player::name(&mut conn, player).await?.is_some();
+
     Ok(layout! {
         html! {
-            div {
-                ("Play as:")
-                div {
-                    button
-                        hx-post="/games/create?playing_as=black"
-                        hx-target="body"
-                        hx-push-url="true"
-                    {
-                        "Black"
+            div class="p-4" {
+                h2 class="text-xl" { "Play now" }
+                p { "Get paired with someone near your rating." }
+                form hx-post="/games/seek" hx-target="body" class="mb-4" {
+                    (time_control_select())
+                    (rated_checkbox(registered))
+                    button { "Find an opponent" }
+                }
+                h2 class="text-xl" { "Play the computer" }
+                form hx-post="/games/create" hx-target="body" hx-push-url="true" class="mb-4" {
+                    (time_control_select())
+                    (variant_select())
+                    (computer_select(state.engine.is_some()))
+                    ("Play as:")
+                    div {
+                        button name="playing_as" value="black" {
+                            "Black"
+                        }
+                        button name="playing_as" value="white" {
+                            "White"
+                        }
                     }
-                    button
-                        hx-post="/games/create?playing_as=white"
-                        hx-target="body"
-                        hx-push-url="true"
-                    {
-                        "White"
+                }
+                h2 class="text-xl" { "Challenge" }
+                p { "Create a game and send the link to a friend, or challenge a player or bot by name." }
+                form hx-post="/games/create" hx-target="body" hx-push-url="true" {
+                    (time_control_select())
+                    (variant_select())
+                    (rated_checkbox(registered))
+                    div {
+                        ("Opponent:")
+                        input type="text" name="opponent" placeholder="anyone with the link";
+                    }
+                    ("Play as:")
+                    div {
+                        button name="playing_as" value="black" {
+                            "Black"
+                        }
+                        button name="playing_as" value="white" {
+                            "White"
+                        }
                     }
                 }
+                p class="mt-4" {
+                    a href="/editor" class="underline" { "Set up a position" }
+                    " to play or analyse from."
+                }
             }
         }
     })
 }
 
+/// the built-in levels, and the external engine if the server has one
+fn computer_select(has_engine: bool) -> Markup {
+    html! {
+        div {
+            ("Level:")
+            select name="computer" {
+                @for level in Level::ALL {
+                    option value=(level.as_str()) { (level.label()) }
+                }
+                @if has_engine {
+                    option value=(Engine::Uci.as_str()) { (Engine::Uci.label()) }
+                }
+            }
+        }
+    }
+}
+
+fn time_control_select() -> Markup {
+    html! {
+        div {
+            ("Time control:")
+            select name="time_control" {
+                option value="" { "Untimed" }
+                @for time_control in TIME_CONTROLS {
+                    option value=(time_control) {
+                        (Clock::parse(time_control).unwrap().label())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// the rules to play by, and for Chess960 which starting position
+fn variant_select() -> Markup {
+    html! {
+        div {
+            ("Variant:")
+            select name="variant" {
+                @for variant in Variant::ALL {
+                    option value=(variant.as_str()) { (variant.label()) }
+                }
+            }
+            input
+                type="number"
+                name="chess960_position"
+                min="0"
+                max="959"
+                placeholder="Chess960 position, random if empty";
+        }
+    }
+}
+
+/// only registered players have ratings to play for
+fn rated_checkbox(registered: bool) -> Markup {
+    html! {
+        div {
+            @if registered {
+                label {
+                    input type="checkbox" name="rated" value="true";
+                    " Rated (standard chess only)"
+                }
+            } @else {
+                a href="/account" class="underline" { "Register" }
+                " to play rated games"
+            }
+        }
+    }
+}
+
 #[derive(Deserialize)]
-struct GamesPlayParams {
+struct GamesCreateParams {
     playing_as: Color,
+    time_control: Option<String>,
+    #[serde(default)]
+    rated: bool,
+    /// the level of the computer to play against,
+    /// or nothing for a challenge to another player
+    computer: Option<String>,
+    /// the name of the one player who can take the challenge,
+    /// or nothing for anyone with the link
+    opponent: Option<String>,
+    /// the rules to play by, standard chess if missing
+    variant: Option<String>,
+    /// which of the 960 starting positions to play, a random one if missing
+    chess960_position: Option<String>,
+    /// a position to start from instead of the usual one, like one set up in the board editor
+    fen: Option<String>,
 }
 
+/// create an open challenge with the creator sitting at the color they chose,
+/// or a game against the computer
 async fn games_create(
     State(state): State<Arc<Mutex<AppState>>>,
-    Query(params): Query<GamesPlayParams>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<GamesCreateParams>,
 ) -> Result<impl IntoResponse, AppError> {
+    let shared_state = Arc::clone(&state);
+
     let mut state = state.lock().await;
 
+    let game_id = create_game(&shared_state, &mut state, player, &params).await?;
+
+    Ok(hx_location(&format!("/games/{game_id}/play")))
+}
+
+/// store a new game with `player` in the seat they asked for,
+/// for the web form and the API alike
+async fn create_game(
+    shared_state: &Arc<Mutex<AppState>>,
+    state: &mut AppState,
+    player: PlayerId,
+    params: &GamesCreateParams,
+) -> Result<Uuid, AppError> {
     let mut conn = state.pool.acquire().await?;
 
-    let (game_id,): (Uuid,) = sqlx::query_as(
+    if params.rated && player::name(&mut conn, player).await?.is_none() {
+        return Err(BadRequest("only registered players can play rated games".into()).into());
+    }
+
+    let computer = params
+        .computer
+        .as_deref()
+        .and_then(Engine::parse)
+        .map(|engine| Computer {
+            color: params.playing_as.invert(),
+            engine,
+        });
+
+    if params.rated && computer.is_some() {
+        return Err(BadRequest("games against the computer can't be rated".into()).into());
+    }
+
+    if computer.is_some_and(|computer| computer.engine == Engine::Uci) && state.engine.is_none() {
+        return Err(BadRequest("there is no engine to play against".into()).into());
+    }
+
+    let challenged = match params.opponent.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(_) if computer.is_some() => {
+            return Err(BadRequest("games against the computer have no opponent".into()).into());
+        }
+        Some(name) => match player::by_name(&mut conn, name).await? {
+            Some(opponent) if opponent == player => {
+                return Err(BadRequest("you can't challenge yourself".into()).into());
+            }
+            Some(opponent) => Some(opponent),
+            None => return Err(BadRequest(format!("nobody is called {name}")).into()),
+        },
+    };
+
+    // anything that isn't a time control means an untimed game
+    let clock = params.time_control.as_deref().and_then(Clock::parse);
+
+    let variant = match params.variant.as_deref().map(str::trim) {
+        None | Some("") => Variant::Standard,
+        Some(variant) => match Variant::parse(variant) {
+            Some(variant) => variant,
+            None => return Err(BadRequest(format!("there is no variant called {variant}")).into()),
+        },
+    };
+
+    // ratings are only kept for standard chess
+    if params.rated && variant != Variant::Standard {
+        return Err(BadRequest(format!("{} games can't be rated", variant.label())).into());
+    }
+
+    let (mut board, chess960_position) = match variant {
+        Variant::Chess960 => {
+            let id = match params.chess960_position.as_deref().map(str::trim) {
+                None | Some("") => (Uuid::new_v4().as_u128() % 960) as u16,
+                Some(id) => id.parse().unwrap_or(u16::MAX),
+            };
+
+            match Board::chess960(id) {
+                Some(board) => (board, Some(id)),
+                None => {
+                    return Err(
+                        BadRequest("Chess960 positions are numbered 0 to 959".into()).into(),
+                    );
+                }
+            }
+        }
+        _ => (Board::new(), None),
+    };
+
+    let fen = params
+        .fen
+        .as_deref()
+        .map(str::trim)
+        .filter(|fen| !fen.is_empty());
+
+    let mut to_move = Color::White;
+
+    if let Some(fen) = fen {
+        if chess960_position.is_some() {
+            return Err(BadRequest("Chess960 games start from a numbered position".into()).into());
+        }
+
+        if params.rated {
+            return Err(BadRequest("games from a set up position can't be rated".into()).into());
+        }
+
+        (board, to_move) =
+            Board::from_fen(fen).ok_or_else(|| BadRequest(format!("{fen} isn't a FEN")))?;
+    }
+
+    board.variant = variant;
+
+    // set up positions are only checked once they know their rules
+    if fen.is_some()
+        && let Some(problem) = board.problems(to_move).into_iter().next()
+    {
+        return Err(BadRequest(format!("that position can't be played: {problem}")).into());
+    }
+
+    let starting_fen = board.fen(to_move);
+
+    let (game_id,): (Uuid,) = sqlx::query_as(&format!(
         "
-    insert into games (id) values (?) returning id;
+    insert into games
+    (id, {}, time_control, rated, computer, challenged, variant, chess960_position, starting_fen)
+    values (?, ?, ?, ?, ?, ?, ?, ?, ?)
+    returning id;
     ",
-    )
+        seat_column(params.playing_as)
+    ))
     .bind(Uuid::new_v4())
+    .bind(player.0.hyphenated())
+    .bind(clock.as_ref().map(Clock::time_control))
+    .bind(params.rated)
+    .bind(computer.map(|computer| computer.engine.as_str()))
+    .bind(challenged.map(|challenged| challenged.0.hyphenated()))
+    .bind(variant.as_str())
+    .bind(chess960_position)
+    .bind(&starting_fen)
     .fetch_one(&mut *conn)
     .await?;
 
-    state.games.entry(game_id).or_insert_with(|| GameState {
-        board: Board::new(),
-        selected: None,
-        possible_moves: vec![],
-        takes: vec![],
-        to_move: Color::White,
-    });
+    let mut game_state = GameState::new();
 
-    let mut headers = HeaderMap::new();
+    game_state.variant = variant;
 
-    headers.insert(
-        "HX-Location",
-        format!(
-            "/games/{game_id}/play?playing_as={}",
-            match params.playing_as {
-                Color::Black => "black",
-                Color::White => "white",
-            }
-        )
-        .parse()
-        .unwrap(),
+    game_state.chess960_position = chess960_position;
+
+    game_state.starting_fen = starting_fen;
+
+    game_state.board = board;
+
+    game_state.to_move = to_move;
+
+    game_state.sit(params.playing_as, player);
+
+    game_state.clock = clock;
+
+    game_state.rated = params.rated;
+
+    game_state.computer = computer;
+
+    game_state.challenged = challenged;
+
+    game_state.load_profiles(&mut conn).await?;
+
+    if let Some(challenged) = challenged {
+        let _ = state
+            .player_events
+            .send((challenged, PlayerEvent::Challenge(game_id)));
+    }
+
+    if computer.is_some() {
+        let _ = state
+            .player_events
+            .send((player, PlayerEvent::GameStart(game_id)));
+    }
+
+    // the computer moves first when it plays whoever is to move
+    think(
+        Arc::clone(shared_state),
+        game_id,
+        &mut game_state,
+        state.engine.clone(),
     );
 
-    Ok(headers)
+    state.games.insert(game_id, game_state);
+
+    Ok(game_id)
 }
 
-async fn games_play(
+async fn games_join_page(
     State(state): State<Arc<Mutex<AppState>>>,
     Path(game_id): Path<Uuid>,
-    Query(params): Query<GamesPlayParams>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Response, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let registered = player::name(&mut conn, player).await?.is_some();
+
+    let game_state = state.game(game_id).await?;
+
+    match game_state.can_join(player) {
+        Some(color) => Ok(layout! {
+            html! {
+                div class="p-4" {
+                    p {
+                        "You have been challenged to a "
+                        @if game_state.rated { "rated" } @else { "casual" }
+                        " game."
+                    }
+                    @if game_state.rated && !registered {
+                        a href="/account" class="underline" { "Register" }
+                        " to play rated games"
+                    } @else {
+                        (join_button(game_id, color))
+                    }
+                }
+            }
+        }
+        .into_response()),
+        // the challenge was already taken or sent to someone else, the game is over,
+        // or this is the creator following their own link
+        _ => Ok(Redirect::to(&format!("/games/{game_id}/play")).into_response()),
+    }
+}
+
+/// take the open seat of a challenge.
+/// the first player to get here wins the seat, everyone else ends up watching.
+async fn games_join(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
 ) -> Result<impl IntoResponse, AppError> {
     let mut state = state.lock().await;
 
-    if let Some(game_state) = state.games.get(&game_id) {
-        Ok(layout! {
-            (board(game_id, &game_state.board, params.playing_as))
-        })
-    } else {
-        let mut conn = state.pool.acquire().await?;
+    join_game(&mut state, game_id, player).await?;
 
-        let moves: Vec<(i8, i8, i8, i8)> = sqlx::query_as(
+    Ok(hx_location(&format!("/games/{game_id}/play")))
+}
+
+/// sit `player` in the open seat if they can take it,
+/// for the web page and the API alike
+async fn join_game(state: &mut AppState, game_id: Uuid, player: PlayerId) -> Result<(), AppError> {
+    let mut conn = state.pool.acquire().await?;
+
+    let registered = player::name(&mut conn, player).await?.is_some();
+
+    let player_events = state.player_events.clone();
+
+    let game_state = state.game(game_id).await?;
+
+    if game_state.rated && !registered {
+        return Err(BadRequest("only registered players can play rated games".into()).into());
+    }
+
+    if let Some(color) = game_state.can_join(player) {
+        let joined = sqlx::query(&format!(
             "
-    select
-        from_column,
-        from_row,
-        to_column,
-        to_row
-    from moves
-    where game_id = ?
-    order by inserted_at asc;
+    update games
+    set {seat} = ?, updated_at = CURRENT_TIMESTAMP
+    where id = ? and {seat} is null and not imported;
     ",
-        )
+            seat = seat_column(color)
+        ))
+        .bind(player.0.hyphenated())
         .bind(game_id)
-        .fetch_all(&mut *conn)
+        .execute(&mut *conn)
         .await?;
 
-        let mut board_state = Board::new();
+        if joined.rows_affected() == 1 {
+            game_state.sit(color, player);
 
-        let mut takes = vec![];
+            game_state.load_profiles(&mut conn).await?;
 
-        let to_move = if moves.len() % 2 == 0 {
-            Color::White
-        } else {
-            Color::Black
-        };
+            let _ = game_state.events.send(GameEvent::Joined);
 
-        for (from_column, from_row, to_column, to_row) in moves {
-            if let Some(take) =
-                board_state.move_piece(&(from_column, from_row).into(), &(to_column, to_row).into())
+            for seated in [game_state.white_player, game_state.black_player]
+                .into_iter()
+                .flatten()
             {
-                takes.push(take);
+                let _ = player_events.send((seated, PlayerEvent::GameStart(game_id)));
             }
+        } else {
+            // the seat was taken behind our back, so our copy of the game is stale
+            state.games.remove(&game_id);
         }
+    }
 
-        let game_state = GameState {
-            board: board_state,
-            selected: None,
-            possible_moves: vec![],
-            takes,
-            to_move,
-        };
+    Ok(())
+}
+
+async fn games_resign(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let game_state = state.game(game_id).await?;
+
+    if let Some(color) = game_state.seat(player)
+        && game_state.has_started()
+        && game_state.result.is_none()
+    {
+        game_state
+            .finish(
+                &mut conn,
+                game_id,
+                GameResult {
+                    outcome: Outcome::Win(color.invert()),
+                    termination: Termination::Resignation,
+                },
+            )
+            .await?;
+
+        let _ = state.reviews.send(game_id);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct MoveParams {
+    /// in UCI ("e2e4") or standard algebraic notation ("e4")
+    #[serde(rename = "move")]
+    m: String,
+}
+
+/// play a move written out, instead of clicked on the board.
+/// this is how the terminal client plays.
+async fn games_move(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<MoveParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let shared_state = Arc::clone(&state);
+
+    let mut state = state.lock().await;
+
+    submit_move(&shared_state, &mut state, game_id, player, &params.m).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// play a move `player` wrote out, if it is theirs to play and legal,
+/// for the terminal client and the API alike
+async fn submit_move(
+    shared_state: &Arc<Mutex<AppState>>,
+    state: &mut AppState,
+    game_id: Uuid,
+    player: PlayerId,
+    written: &str,
+) -> Result<(), AppError> {
+    let game_state = state.game(game_id).await?;
+
+    if !game_state.has_started() {
+        return Err(BadRequest("the game hasn't started".into()).into());
+    }
+
+    if game_state.result.is_some() {
+        return Err(BadRequest("the game is over".into()).into());
+    }
+
+    if game_state.seat(player) != Some(game_state.to_move) {
+        return Err(BadRequest("it isn't your move".into()).into());
+    }
+
+    let m = game_state
+        .parse_move(written)
+        .ok_or_else(|| BadRequest(format!("{} isn't a legal move", written.trim())))?;
+
+    // whatever was selected on the board doesn't apply anymore
+    game_state.selected = None;
+    game_state.selected_drop = None;
+    game_state.possible_moves.clear();
+
+    play_move(shared_state, state, game_id, m).await?;
+
+    Ok(())
+}
+
+async fn games_play(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<impl IntoResponse, AppError> {
+    let shared_state = Arc::clone(&state);
+
+    let mut state = state.lock().await;
+
+    let uci_engine = state.engine.clone();
+
+    let game_state = state.game(game_id).await?;
+
+    // the computer might have been waiting on its move since the game was
+    // last loaded
+    think(shared_state, game_id, game_state, uci_engine);
+
+    Ok(layout! {
+        html! {
+            div hx-ext="sse" sse-connect=(format!("/games/{game_id}/events")) {
+                div id="game" sse-swap="game" {
+                    (game_view(game_id, game_state, player))
+                }
+            }
+        }
+    })
+}
+
+/// re-render the game for this player whenever something happens to it
+async fn game_events(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let (events, spectator) = {
+        let mut state_guard = state.lock().await;
+
+        let game_state = state_guard.game(game_id).await?;
+
+        let events = game_state.events.subscribe();
 
-        let out = layout! {
-            (board(game_id, &game_state.board, params.playing_as))
+        let spectator = if game_state.seat(player).is_none() {
+            game_state.spectators += 1;
+
+            let _ = game_state.events.send(GameEvent::Spectators);
+
+            Some(Spectator {
+                state: Arc::clone(&state),
+                game_id,
+            })
+        } else {
+            None
         };
 
-        state.games.insert(game_id, game_state);
+        (events, spectator)
+    };
+
+    let stream = BroadcastStream::new(events).then(move |_| {
+        // keep counting the spectator for as long as the stream is open
+        let _ = &spectator;
+
+        let state = Arc::clone(&state);
+
+        async move {
+            let mut state = state.lock().await;
+
+            let view = match state.game(game_id).await {
+                Ok(game_state) => game_view(game_id, game_state, player),
+                Err(e) => html! { (format!("Something went wrong: {e}")) },
+            };
+
+            Ok(Event::default().event("game").data(view.into_string()))
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// someone watching a game they aren't playing in,
+/// counted for as long as this is alive
+struct Spectator {
+    state: Arc<Mutex<AppState>>,
+    game_id: Uuid,
+}
+
+impl Drop for Spectator {
+    fn drop(&mut self) {
+        let state = Arc::clone(&self.state);
+        let game_id = self.game_id;
+
+        tokio::spawn(async move {
+            let mut state = state.lock().await;
 
-        Ok(out)
+            if let Some(game_state) = state.games.get_mut(&game_id) {
+                game_state.spectators = game_state.spectators.saturating_sub(1);
+
+                let _ = game_state.events.send(GameEvent::Spectators);
+            }
+        });
     }
 }
 
-fn board(game_id: Uuid, board_data: &Board, playing_as: Color) -> Markup {
+/// the game as seen by `player`: who is playing, their clocks,
+/// who is to move and the board from their side.
+/// players who aren't in the game get a board they can't click.
+fn game_view(game_id: Uuid, game_state: &GameState, player: PlayerId) -> Markup {
+    let seat = game_state.seat(player);
+
+    let orientation = seat.unwrap_or(Color::White);
+
+    let interactive = seat.is_some() && game_state.result.is_none();
+
+    html! {
+        div class="p-4 flex justify-between" {
+            span {
+                @if let Some(clock) = &game_state.clock {
+                    (clock.label())
+                } @else {
+                    "Untimed"
+                }
+                @if game_state.rated { " · Rated" } @else { " · Casual" }
+                @if game_state.variant != Variant::Standard {
+                    " · " (game_state.variant.label())
+                    @if let Some(id) = game_state.chess960_position {
+                        " #" (id)
+                    }
+                }
+            }
+            span {
+                (game_state.spectators) " watching"
+                @if !game_state.moves.is_empty() {
+                    a href=(format!("/games/{game_id}/replay")) class="ml-4 underline" { "Replay" }
+                }
+            }
+        }
+        (player_bar(game_id, game_state, orientation.invert(), seat))
+        (board(game_id, &game_state.board, orientation, interactive))
+        (player_bar(game_id, game_state, orientation, seat))
+        div class="p-4" {
+            @if let Some(result) = &game_state.result {
+                (result.description())
+                a href=(format!("/games/{game_id}/analysis")) class="ml-4 underline" { "Analyse" }
+            } @else if game_state.imported {
+                "An imported game that was never finished."
+            } @else if !game_state.has_started() {
+                @if seat.is_some() {
+                    "Waiting for an opponent. Send them this link: "
+                    a href=(format!("/games/{game_id}/join")) class="underline" { "join game" }
+                } @else if let Some(color) = game_state.can_join(player) {
+                    (join_button(game_id, color))
+                }
+            } @else if let Some(color) = seat {
+                @if color == game_state.to_move {
+                    "Your move"
+                } @else if game_state.computer.is_some() {
+                    "The computer is thinking"
+                } @else {
+                    "Waiting for your opponent to move"
+                }
+                button
+                    class="ml-4 underline"
+                    hx-post=(format!("/games/{game_id}/resign"))
+                    hx-swap="none"
+                    hx-confirm="Resign this game?"
+                {
+                    "Resign"
+                }
+            } @else {
+                @match game_state.to_move {
+                    Color::White => "White to move",
+                    Color::Black => "Black to move",
+                }
+            }
+        }
+    }
+}
+
+/// the name, rating and clock of whoever is playing `color`
+fn player_bar(
+    game_id: Uuid,
+    game_state: &GameState,
+    color: Color,
+    viewer_seat: Option<Color>,
+) -> Markup {
+    let seated = game_state.is_seated(color);
+
+    // the player to move can pick a piece out of their pocket to drop
+    let can_drop = viewer_seat == Some(color)
+        && game_state.to_move == color
+        && game_state.has_started()
+        && game_state.result.is_none();
+
+    let profile = game_state.profile(color);
+
+    let computer = game_state
+        .computer
+        .filter(|computer| computer.color == color);
+
+    html! {
+        div class="px-4 flex justify-between" {
+            span {
+                @match color {
+                    Color::White => "White",
+                    Color::Black => "Black",
+                }
+                @if let Some(computer) = computer {
+                    " · Computer (" (computer.engine.label()) ")"
+                } @else if let Some(name) = &profile.name {
+                    " · " (name)
+                    @if seated {
+                        " " (profile.rating.unwrap_or_default().label())
+                    }
+                } @else if seated {
+                    " · anonymous"
+                }
+                @if viewer_seat == Some(color) {
+                    " (you)"
+                } @else if !seated && game_state.result.is_none() {
+                    " (waiting)"
+                }
+                @if game_state.variant.counts_checks() {
+                    " · " (game_state.board.checks(color))
+                    @if game_state.board.checks(color) == 1 { " check" } @else { " checks" }
+                }
+                @if game_state.variant.has_pockets() {
+                    " · "
+                    @for kind in game_state.board.pocket(color) {
+                        @let glyph = Piece::new(*kind, color, Position::new(0, 0)).repr().to_string();
+                        @if can_drop {
+                            button
+                                hx-put=(format!("/games/{game_id}/play/pocket_clicked?kind={}", kind.letter()))
+                                hx-swap="none"
+                                class=(if game_state.selected_drop == Some(*kind) { "bg-yellow-200" } else { "" })
+                            {
+                                (glyph)
+                            }
+                        } @else {
+                            span { (glyph) }
+                        }
+                    }
+                }
+            }
+            @if let Some(clock) = &game_state.clock {
+                @let remaining = clock.remaining(color);
+                @if clock.running() == Some(color) {
+                    span class="font-bold" data-clock-running=(remaining.as_millis()) {
+                        (format_duration(remaining))
+                    }
+                } @else {
+                    span { (format_duration(remaining)) }
+                }
+            }
+        }
+    }
+}
+
+fn join_button(game_id: Uuid, color: Color) -> Markup {
+    html! {
+        button
+            hx-post=(format!("/games/{game_id}/join"))
+            hx-target="body"
+            hx-push-url="true"
+        {
+            @match color {
+                Color::White => "Play as white",
+                Color::Black => "Play as black",
+            }
+        }
+    }
+}
+
+/// tell htmx to navigate to `path`
+fn hx_location(path: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    headers.insert("HX-Location", path.parse().unwrap());
+
+    headers
+}
+
+fn board(game_id: Uuid, board_data: &Board, playing_as: Color, interactive: bool) -> Markup {
+    board_grid(board_data, playing_as, |position, color, body| {
+        if interactive {
+            square(game_id, position, color, body, false)
+        } else {
+            read_only_square(position, color, body)
+        }
+    })
+}
+
+/// the squares of `board_data` as `playing_as` sees them,
+/// each drawn by `render` from its position, color and piece
+fn board_grid(
+    board_data: &Board,
+    playing_as: Color,
+    render: impl Fn(&Position, SquareColor, &str) -> Markup,
+) -> Markup {
     const INCREASING: [i8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
     const DECREASING: [i8; 8] = [7, 6, 5, 4, 3, 2, 1, 0];
 
@@ -221,12 +982,11 @@ fn board(game_id: Uuid, board_data: &Board, playing_as: Color) -> Markup {
                 @for row in row_range.into_iter() {
                     div class="flex"  {
                         @for column in column_range.into_iter() {
-                            (square(
-                                game_id,
-                                &(column, row).into(),
-                                Position::from((column, row)).color().into(),
-                                board_data.get_piece(&(column, row).into()).map_or("", |piece| piece.repr()),
-                                false
+                            @let position = Position::from((column, row));
+                            (render(
+                                &position,
+                                position.color().into(),
+                                board_data.get_piece(&position).map_or("", |piece| piece.repr()),
                             ))
                         }
                     }
@@ -245,16 +1005,58 @@ struct SquareClick {
 async fn square_clicked(
     State(state): State<Arc<Mutex<AppState>>>,
     Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
     Query(params): Query<SquareClick>,
 ) -> Result<impl IntoResponse, AppError> {
+    let shared_state = Arc::clone(&state);
+
     let mut state = state.lock().await;
 
-    let mut conn = state.pool.acquire().await?;
+    let game_state = state.game(game_id).await?;
 
-    let game_state = state.games.get_mut(&game_id).unwrap();
+    if !game_state.has_started()
+        || game_state.result.is_some()
+        || game_state.seat(player) != Some(game_state.to_move)
+    {
+        debug!("not this player's turn");
+        return Ok(html! {});
+    }
 
     let position = (params.column, params.row).into();
 
+    if let Some(kind) = game_state.selected_drop.take() {
+        let unhighlighted = html! {
+            @for m in &game_state.possible_moves {
+                (square(game_id, m, m.color().into(), "", true))
+            }
+        };
+
+        if game_state.possible_moves.contains(&position) {
+            debug!("dropped a piece");
+
+            let piece = Piece::new(kind, game_state.to_move, position);
+
+            game_state.possible_moves.clear();
+
+            play_move(
+                &shared_state,
+                &mut state,
+                game_id,
+                Move::drop(kind, position),
+            )
+            .await?;
+
+            return Ok(html! {
+                (unhighlighted)
+                (square(game_id, &position, position.color().into(), piece.repr(), true))
+            });
+        }
+
+        game_state.possible_moves.clear();
+
+        return Ok(unhighlighted);
+    }
+
     if let Some(selected) = game_state.selected {
         if selected == position {
             game_state.selected = None;
@@ -271,28 +1073,23 @@ async fn square_clicked(
         } else {
             if game_state.possible_moves.contains(&position) {
                 debug!("made a valid move");
-                // do the move
-                //
-                // update board
+
                 let current_piece_location =
                     game_state.board.get_piece(&selected).unwrap().to_owned();
 
-                if let Some(take) = game_state.board.move_piece(&selected, &position) {
-                    game_state.takes.push(take);
-                }
-                // record move in db
-                sqlx::query(
-                    "insert into moves
-                (game_id, from_column, from_row, to_column, to_row)
-                values (?, ?, ?, ?, ?);",
-                )
-                .bind(game_id)
-                .bind(selected.column)
-                .bind(selected.row)
-                .bind(position.column)
-                .bind(position.row)
-                .execute(&mut *conn)
-                .await?;
+                // pawns reaching the last row are always promoted to a queen
+                let m = Move {
+                    from: selected,
+                    to: position,
+                    promotion: (current_piece_location.kind == PieceKind::Pawn
+                        && [0, 7].contains(&position.row))
+                    .then_some(PieceKind::Queen),
+                    drop: None,
+                };
+
+                play_move(&shared_state, &mut state, game_id, m).await?;
+
+                let game_state = state.game(game_id).await?;
 
                 // change render of board,
                 // deselect
@@ -305,9 +1102,9 @@ async fn square_clicked(
 
                     @for m in &game_state.possible_moves {
                         @if let Some(piece_at) = game_state.board.get_piece(m) {
-                            (square(game_id, &m, m.color().into(), piece_at.repr(), true))
+                            (square(game_id, m, m.color().into(), piece_at.repr(), true))
                         } @else {
-                            (square(game_id, &m, m.color().into(), "", true))
+                            (square(game_id, m, m.color().into(), "", true))
                         }
                     }
                 };
@@ -325,24 +1122,27 @@ async fn square_clicked(
                 let out = html! {
                     @for m in &game_state.possible_moves {
                         @if let Some(piece_at) = game_state.board.get_piece(m) {
-                            (square(game_id, &m, m.color().into(), piece_at.repr(), true))
+                            (square(game_id, m, m.color().into(), piece_at.repr(), true))
                         } @else {
-                            (square(game_id, &m, m.color().into(), "", true))
+                            (square(game_id, m, m.color().into(), "", true))
                         }
                     }
                 };
 
                 game_state.selected = None;
                 game_state.possible_moves.clear();
-                game_state.to_move = game_state.to_move.invert();
 
                 Ok(out)
             }
         }
     } else {
-        if let Some(piece) = game_state.board.get_piece(&position) {
+        if let Some(piece) = game_state
+            .board
+            .get_piece(&position)
+            .filter(|piece| piece.color == game_state.to_move)
+        {
             debug!("no piece selected: clicked on a piece: {:?}", &piece);
-            let moves = piece.possible_moves(&game_state.board);
+            let moves = game_state.board.legal_moves(piece);
 
             game_state.possible_moves = moves;
             game_state.selected = Some(position);
@@ -357,7 +1157,7 @@ async fn square_clicked(
                 }
             })
         } else {
-            debug!("no piece selected: clicked on an empty square");
+            debug!("no piece selected: clicked on an empty square or an opponent's piece");
 
             game_state.possible_moves.clear();
 
@@ -366,6 +1166,268 @@ async fn square_clicked(
     }
 }
 
+#[derive(Deserialize)]
+struct PocketClick {
+    kind: char,
+}
+
+/// pick a piece out of the pocket to drop, or put it back if it was already picked.
+/// the squares it can be dropped on light up, and clicking one of them drops it.
+async fn pocket_clicked(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<PlayerId>,
+    Query(params): Query<PocketClick>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let game_state = state.game(game_id).await?;
+
+    if !game_state.has_started()
+        || game_state.result.is_some()
+        || game_state.seat(player) != Some(game_state.to_move)
+    {
+        debug!("not this player's turn");
+        return Ok(html! {});
+    }
+
+    let kind = PieceKind::parse(params.kind)
+        .ok_or_else(|| BadRequest(format!("there is no piece called {}", params.kind)))?;
+
+    // whatever was highlighted before goes back to normal
+    let unhighlighted = html! {
+        @for m in &game_state.possible_moves {
+            @if let Some(piece_at) = game_state.board.get_piece(m) {
+                (square(game_id, m, m.color().into(), piece_at.repr(), true))
+            } @else {
+                (square(game_id, m, m.color().into(), "", true))
+            }
+        }
+    };
+
+    let picked_again = game_state.selected_drop == Some(kind);
+
+    game_state.selected = None;
+    game_state.selected_drop = None;
+    game_state.possible_moves.clear();
+
+    if picked_again {
+        return Ok(unhighlighted);
+    }
+
+    game_state.possible_moves = game_state.board.legal_drops(game_state.to_move, kind);
+    game_state.selected_drop = Some(kind);
+
+    Ok(html! {
+        (unhighlighted)
+        @for position in &game_state.possible_moves {
+            (square(game_id, position, SquareColor::Highlighted, "", true))
+        }
+    })
+}
+
+/// make a move for whoever is to move: punch their clock, record the move
+/// and tell everyone about it. then end the game if that ended it, by checkmate,
+/// stalemate or the variant's own rules, or get the computer thinking if it is its turn.
+async fn play_move(
+    shared_state: &Arc<Mutex<AppState>>,
+    state: &mut AppState,
+    game_id: Uuid,
+    m: Move,
+) -> anyhow::Result<()> {
+    let mut conn = state.pool.acquire().await?;
+
+    let uci_engine = state.engine.clone();
+
+    let game_state = state.game(game_id).await?;
+
+    let ply = game_state.ply + 1;
+
+    let mover = game_state.to_move;
+
+    let clock_ms = game_state
+        .clock
+        .as_mut()
+        .map(|clock| clock.punch(mover).as_millis() as i64);
+
+    // record move in db.
+    // this happens before the board is updated so that a move
+    // that loses the race for this ply leaves the board untouched
+    let inserted = sqlx::query(
+        "insert into moves
+    (game_id, ply, from_column, from_row, to_column, to_row, promotion, dropped, move_number, clock_ms)
+    values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+    )
+    .bind(game_id)
+    .bind(ply)
+    .bind(m.from.column)
+    .bind(m.from.row)
+    .bind(m.to.column)
+    .bind(m.to.row)
+    .bind(m.promotion.map(|kind| kind.letter().to_string()))
+    .bind(m.drop.map(|kind| kind.letter().to_string()))
+    .bind((ply + 1) / 2)
+    .bind(clock_ms)
+    .execute(&mut *conn)
+    .await;
+
+    if let Err(e) = inserted {
+        // the move didn't make it into the db, most likely because
+        // someone else already made the move for this ply.
+        // either way our copy of the game (and its clock) is stale
+        state.games.remove(&game_id);
+
+        return Err(e.into());
+    }
+
+    // update board
+    if let Some(take) = game_state.board.make_move(&m) {
+        game_state.takes.push(take);
+    }
+
+    game_state.moves.push(m);
+
+    game_state.ply = ply;
+
+    game_state.to_move = game_state.to_move.invert();
+
+    let _ = game_state.events.send(GameEvent::Moved);
+
+    if let Some(result) = game_state.result_on_board() {
+        game_state.finish(&mut conn, game_id, result).await?;
+
+        let _ = state.reviews.send(game_id);
+    } else {
+        if let Some(clock) = &game_state.clock {
+            watch_clock(
+                Arc::clone(shared_state),
+                game_id,
+                clock.remaining(mover.invert()),
+            );
+        }
+
+        if game_state.computer_to_move().is_some() {
+            think(Arc::clone(shared_state), game_id, game_state, uci_engine);
+        }
+    }
+
+    Ok(())
+}
+
+/// work out the computer's move away from the state lock,
+/// and play it if the game hasn't moved on in the meantime.
+/// `uci_engine` is the external engine the server was started with, if any.
+fn think(
+    state: Arc<Mutex<AppState>>,
+    game_id: Uuid,
+    game_state: &mut GameState,
+    uci_engine: Option<PathBuf>,
+) {
+    let Some(computer) = game_state.computer_to_move() else {
+        return;
+    };
+
+    if game_state.computer_thinking {
+        return;
+    }
+
+    game_state.computer_thinking = true;
+
+    let board = game_state.board.clone();
+    let ply = game_state.ply;
+    let starting_fen = game_state.starting_fen.clone();
+    let moves = game_state.moves.clone();
+
+    let limit = match &game_state.clock {
+        Some(clock) => uci::Limit::Clock {
+            white: clock.remaining(Color::White),
+            black: clock.remaining(Color::Black),
+            increment: clock.increment,
+        },
+        None => uci::Limit::MoveTime(uci::MOVE_TIME),
+    };
+
+    tokio::spawn(async move {
+        let uci_move = match (computer.engine, uci_engine) {
+            (Engine::Uci, Some(uci_engine)) => {
+                let legal_moves = board.all_legal_moves(computer.color);
+
+                // the engine only gets to pick from the moves this board knows about
+                let position = uci::Position {
+                    starting_fen: &starting_fen,
+                    moves: &moves,
+                    variant: board.variant,
+                };
+
+                match uci::search(&uci_engine, position, limit, &legal_moves).await {
+                    Ok(analysis) => analysis
+                        .best_move
+                        .filter(|best_move| legal_moves.contains(best_move)),
+                    Err(e) => {
+                        error!("the engine failed to move in game {game_id}: {e}");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // the built-in engine stands in if the external one isn't there or fails
+        let best_move = match uci_move {
+            Some(m) => Ok(Some(m)),
+            None => {
+                let level = match computer.engine {
+                    Engine::BuiltIn(level) => level,
+                    Engine::Uci => Level::Intermediate,
+                };
+
+                tokio::task::spawn_blocking(move || {
+                    engine::best_move(&board, computer.color, level.depth())
+                })
+                .await
+            }
+        };
+
+        let shared_state = Arc::clone(&state);
+
+        let mut state = state.lock().await;
+
+        let played = async {
+            let game_state = state.game(game_id).await?;
+
+            game_state.computer_thinking = false;
+
+            if game_state.ply != ply || game_state.result.is_some() {
+                return Ok(());
+            }
+
+            let Some(m) = best_move? else {
+                return Ok(());
+            };
+
+            debug!("computer played {} in game {game_id}", m.uci());
+
+            play_move(&shared_state, &mut state, game_id, m).await
+        };
+
+        if let Err(e) = played.await {
+            error!("the computer failed to move in game {game_id}: {e}");
+        }
+    });
+}
+
+/// look at the game again once the player to move runs out of time,
+/// which ends it on time if they still haven't moved
+fn watch_clock(state: Arc<Mutex<AppState>>, game_id: Uuid, remaining: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(remaining).await;
+
+        if let Err(e) = state.lock().await.game(game_id).await {
+            error!("checking the clock of game {game_id} failed: {e}");
+        }
+    });
+}
+
 enum SquareColor {
     Black,
     White,
@@ -412,6 +1474,38 @@ fn square(
     }
 }
 
+/// a square that goes to `href` when clicked, like the board editor with a piece put down
+fn linked_square(
+    href: &str,
+    position: &Position,
+    square_background_color: SquareColor,
+    body: &str,
+) -> Markup {
+    html! {
+        a
+            id=(format!("square-{}{}", position.column, position.row))
+            href=(href)
+            class=(background_color(square_background_color)) {
+            (body)
+        }
+    }
+}
+
+/// a square that does nothing when clicked, for people watching a game
+fn read_only_square(
+    position: &Position,
+    square_background_color: SquareColor,
+    body: &str,
+) -> Markup {
+    html! {
+        div
+            id=(format!("square-{}{}", position.column, position.row))
+            class=(background_color(square_background_color)) {
+            (body)
+        }
+    }
+}
+
 fn background_color(color: SquareColor) -> &'static str {
     match color {
         SquareColor::Black => {
@@ -429,92 +1523,377 @@ fn background_color(color: SquareColor) -> &'static str {
 struct AppState {
     pool: Pool<Sqlite>,
     games: HashMap<Uuid, GameState>,
+    queue: matchmaking::Queue,
+    /// the UCI engine to play against and analyse with, if there is one
+    engine: Option<PathBuf>,
+    /// what happens to players outside of any one game, for whoever is listening
+    player_events: broadcast::Sender<(PlayerId, PlayerEvent)>,
+    /// finished games waiting for the engine to go over them
+    reviews: mpsc::UnboundedSender<Uuid>,
 }
 
-// TODO figure out how/what to store for each individual game
-// such that we can display the currently selected piece, prospective moves, etc.
-struct GameState {
-    board: Board,
-    selected: Option<Position>,
-    possible_moves: Vec<Position>,
-    takes: Vec<Piece>,
-    to_move: Color,
+impl AppState {
+    /// the in-memory state of a game,
+    /// loading it from the db if it isn't in memory yet
+    async fn game(&mut self, game_id: Uuid) -> anyhow::Result<&mut GameState> {
+        if !self.games.contains_key(&game_id) {
+            let mut conn = self.pool.acquire().await?;
+
+            let game_state = GameState::load(&mut conn, game_id)
+                .await?
+                .ok_or(GameNotFound(game_id))?;
+
+            debug!("loaded game {game_id} from db");
+
+            self.games.insert(game_id, game_state);
+        }
+
+        let game_state = self.games.get_mut(&game_id).unwrap();
+
+        game_state.last_seen = Instant::now();
+
+        // nobody moves after their time runs out, so every time a game is
+        // looked at is a good time to end it on time
+        if let Some(color) = game_state.flagged() {
+            let mut conn = self.pool.acquire().await?;
+
+            game_state
+                .finish(
+                    &mut conn,
+                    game_id,
+                    GameResult {
+                        outcome: Outcome::Win(color.invert()),
+                        termination: Termination::Timeout,
+                    },
+                )
+                .await?;
+
+            let _ = self.reviews.send(game_id);
+        }
+
+        Ok(game_state)
+    }
+
+    /// drop games that haven't been touched in `idle_timeout`.
+    /// they are loaded from the db again the next time someone asks for them.
+    fn evict_idle_games(&mut self, idle_timeout: Duration) {
+        let before = self.games.len();
+
+        // games someone is still watching are never idle
+        self.games.retain(|_, game_state| {
+            game_state.last_seen.elapsed() < idle_timeout || game_state.events.receiver_count() > 0
+        });
+
+        let evicted = before - self.games.len();
+
+        if evicted > 0 {
+            debug!("evicted {evicted} idle games");
+        }
+    }
 }
 
 #[derive(Parser)]
 struct Options {
+    #[arg(short, long, env, default_value = "chez.db", global = true)]
+    database: String,
+    #[command(flatten)]
+    serve: ServeOptions,
+    /// serves the web app if not given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args)]
+struct ServeOptions {
     #[arg(short, long, env, default_value = "8080")]
     port: u16,
-    #[arg(short, long, env, default_value = "chez.db")]
-    database: String,
+    /// seconds a game can go untouched before it is evicted from memory
+    #[arg(long, env, default_value = "1800", value_parser = clap::value_parser!(u64).range(1..))]
+    game_idle_timeout: u64,
+    /// a UCI engine, e.g. stockfish, to play against and analyse games with
+    #[arg(long, env)]
+    engine: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// serve the web app
+    Serve(ServeOptions),
+    /// create the db if it doesn't exist and bring its tables up to date
+    Migrate,
+    /// store the games in a PGN file, printing their ids
+    ImportPgn { file: PathBuf },
+    /// print a game, or every game, as PGN
+    ExportPgn {
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        game_id: Option<Uuid>,
+        #[arg(long)]
+        all: bool,
+    },
+    /// count the positions `depth` moves from a position, to check the move generator
+    Perft {
+        fen: String,
+        depth: u8,
+        /// the rules to generate moves by, e.g. atomic or antichess
+        #[arg(long, default_value = "standard", value_parser = parse_variant)]
+        variant: Variant,
+    },
+    /// print a game's players, result, moves and final position
+    Show {
+        game_id: Uuid,
+        /// draw pieces as letters instead of chess symbols
+        #[arg(long)]
+        ascii: bool,
+        /// draw the board from black's side
+        #[arg(long)]
+        flip: bool,
+    },
+    /// register a bot that plays through the API, printing its API token
+    CreateBot { name: String },
+    /// play in the terminal, on a chez server or right here
+    Play {
+        /// a game on the server to join, or watch if it has no open seat.
+        /// a new game is created if not given
+        #[arg(requires = "server")]
+        game_id: Option<Uuid>,
+        /// the chez server to play on, e.g. http://localhost:8080.
+        /// the game is played in this terminal if not given
+        #[arg(long, env = "CHEZ_SERVER")]
+        server: Option<String>,
+        /// who you are on the server, to pick a game back up
+        #[arg(long, env = "CHEZ_PLAYER")]
+        player: Option<Uuid>,
+        /// play the computer at this level: beginner, casual, intermediate or advanced.
+        /// two people take turns at the keyboard in local games if not given
+        #[arg(long, value_parser = parse_level)]
+        computer: Option<Level>,
+        /// the color to play in a new game
+        #[arg(long = "as", default_value = "white", value_parser = parse_color)]
+        playing_as: Color,
+        /// e.g. 300+3 for five minutes and three seconds a move, untimed if not given
+        #[arg(long, value_parser = parse_time_control)]
+        time_control: Option<String>,
+        /// draw pieces as letters instead of chess symbols
+        #[arg(long)]
+        ascii: bool,
+        /// draw the board from the other side
+        #[arg(long)]
+        flip: bool,
+    },
+    /// play as a UCI engine over stdin and stdout, for chess GUIs
+    Uci,
+}
+
+fn parse_level(level: &str) -> Result<Level, String> {
+    Level::parse(level).ok_or_else(|| format!("{level} isn't a level"))
+}
+
+fn parse_variant(variant: &str) -> Result<Variant, String> {
+    Variant::parse(variant).ok_or_else(|| format!("there is no variant called {variant}"))
+}
+
+fn parse_color(color: &str) -> Result<Color, String> {
+    Color::parse(color).ok_or_else(|| "white or black".to_string())
+}
+
+fn parse_time_control(time_control: &str) -> Result<String, String> {
+    Clock::parse(time_control)
+        .map(|clock| clock.time_control())
+        .ok_or_else(|| "<initial seconds>+<increment seconds>, e.g. 300+3".to_string())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let options = Options::parse();
+
+    let database = options.database;
+
+    match options.command.unwrap_or(Command::Serve(options.serve)) {
+        Command::Serve(serve_options) => serve(&database, serve_options).await,
+        Command::Migrate => cli::migrate(&database).await,
+        Command::ImportPgn { file } => cli::import_pgn(&database, &file).await,
+        Command::ExportPgn { game_id, .. } => cli::export_pgn(&database, game_id).await,
+        Command::Perft {
+            fen,
+            depth,
+            variant,
+        } => cli::perft(&fen, depth, variant),
+        Command::CreateBot { name } => cli::create_bot(&database, &name).await,
+        Command::Show {
+            game_id,
+            ascii,
+            flip,
+        } => {
+            let glyphs = if ascii {
+                Glyphs::Ascii
+            } else {
+                Glyphs::Unicode
+            };
+            let from = if flip { Color::Black } else { Color::White };
+
+            cli::show(&database, game_id, glyphs, from).await
+        }
+        Command::Play {
+            game_id,
+            server,
+            player,
+            computer,
+            playing_as,
+            time_control,
+            ascii,
+            flip,
+        } => {
+            let screen = tui::Screen {
+                glyphs: if ascii {
+                    Glyphs::Ascii
+                } else {
+                    Glyphs::Unicode
+                },
+                from: if flip {
+                    playing_as.invert()
+                } else {
+                    playing_as
+                },
+            };
+
+            match server {
+                Some(server) => {
+                    let new_game = tui::NewGame {
+                        playing_as,
+                        computer,
+                        time_control,
+                    };
+
+                    tui::play_remote(screen, &server, player, game_id, new_game).await
+                }
+                None => {
+                    let clock = time_control.as_deref().and_then(Clock::parse);
+
+                    tui::play_local(screen, computer, playing_as, clock).await
+                }
+            }
+        }
+        // stdout belongs to the GUI, so nothing else gets to print there
+        Command::Uci => uci::run_engine(),
+    }
+}
+
+async fn serve(database: &str, options: ServeOptions) -> anyhow::Result<()> {
     let env_filter = tracing_subscriber::EnvFilter::builder()
         .with_default_directive(LevelFilter::DEBUG.into())
         .from_env_lossy();
 
     tracing_subscriber::fmt().with_env_filter(env_filter).init();
 
-    let options = Options::parse();
+    let pool = cli::connect(database).await?;
 
-    let opts =
-        sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite://{}", options.database))?
-            .busy_timeout(std::time::Duration::from_secs(5))
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .create_if_missing(true)
-            .foreign_keys(true);
+    let (reviews, games_to_review) = mpsc::unbounded_channel();
 
-    let pool = sqlx::SqlitePool::connect_with(opts).await?;
+    review::requeue(&mut *pool.acquire().await?, &reviews).await?;
 
-    let mut conn = pool.acquire().await?;
+    let state = Arc::new(Mutex::new(AppState {
+        pool,
+        games: HashMap::new(),
+        queue: matchmaking::Queue::default(),
+        engine: options.engine,
+        player_events: broadcast::channel(64).0,
+        reviews,
+    }));
 
-    let mut tx = conn.begin().await?;
+    tokio::spawn(review::work(Arc::clone(&state), games_to_review));
 
-    sqlx::query(
-        "
-    create table if not exists games (
-        id blob primary key,
-        taken text,
-        inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-    )
-    ",
-    )
-    .execute(&mut *tx)
-    .await?;
+    let game_idle_timeout = Duration::from_secs(options.game_idle_timeout);
 
-    sqlx::query(
-        "
-    create table if not exists moves (
-        id blob primary key,
-        game_id blob not null,
-        from_column integer not null,
-        from_row integer not null,
-        to_column integer not null,
-        to_row integer not null,
-        inserted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-        foreign key(game_id) references games(id)
-    )",
-    )
-    .execute(&mut *tx)
-    .await?;
+    tokio::spawn({
+        let state = Arc::clone(&state);
+
+        async move {
+            let mut interval =
+                tokio::time::interval(game_idle_timeout.min(Duration::from_secs(60)));
 
-    tx.commit().await?;
+            loop {
+                interval.tick().await;
+                let mut state = state.lock().await;
+                state.evict_idle_games(game_idle_timeout);
+                state.queue.expire();
+            }
+        }
+    });
 
     let router = Router::new()
-        .route("/", get(|| async { Redirect::to("/games/new") }))
+        .route("/", get(|| async { Redirect::to("/games") }))
+        .route(
+            "/account",
+            get(player::account).post(player::account_update),
+        )
+        .route("/games", get(lobby::games_index))
+        .route("/games/list", get(lobby::games_list))
         .route("/games/new", get(games_new))
+        .route("/editor", get(editor::editor))
+        .route("/analysis", get(analysis::position_analysis))
+        .route("/analysis/engine", get(analysis::position_analysis_engine))
         .route("/games/create", post(games_create))
+        .route(
+            "/games/seek",
+            post(matchmaking::seek_create)
+                .get(matchmaking::seek_poll)
+                .delete(matchmaking::seek_cancel),
+        )
+        .route(
+            "/games/{game_id}/join",
+            get(games_join_page).post(games_join),
+        )
+        .route("/games/{game_id}/resign", post(games_resign))
+        .route("/games/{game_id}/moves", post(games_move))
+        .route("/games/{game_id}/pgn", get(pgn::game_pgn))
         .route("/games/{game_id}/play", get(games_play))
+        .route("/games/{game_id}/events", get(game_events))
+        .route("/games/{game_id}/analysis", get(analysis::analysis))
+        .route(
+            "/games/{game_id}/analysis/moves",
+            post(analysis::analysis_move),
+        )
+        .route(
+            "/games/{game_id}/analysis/promote",
+            post(analysis::analysis_promote),
+        )
+        .route("/games/{game_id}/analysis/pgn", get(analysis::analysis_pgn))
+        .route("/games/{game_id}/replay", get(replay::replay))
+        .route("/games/{game_id}/review", get(review::review_ready))
+        .route("/games/{game_id}/annotations", post(annotation::annotate))
+        .route(
+            "/games/{game_id}/analysis/engine",
+            get(analysis::analysis_engine),
+        )
         .route("/games/{game_id}/play/square_clicked", put(square_clicked))
-        .with_state(Arc::new(Mutex::new(AppState {
-            pool,
-            games: HashMap::new(),
-        })))
-        .layer(tower_http::compression::CompressionLayer::new());
+        .route("/games/{game_id}/play/pocket_clicked", put(pocket_clicked))
+        .nest(
+            "/api/v1",
+            Router::new()
+                .route("/games", post(api::games_create))
+                .route("/games/{game_id}", get(api::games_show))
+                .route("/games/{game_id}/moves", post(api::moves_create))
+                .route("/games/{game_id}/moves/legal", get(api::moves_legal))
+                .route("/games/{game_id}/join", post(bot::join))
+                .route("/games/{game_id}/decline", post(bot::decline))
+                .route("/games/{game_id}/resign", post(games_resign))
+                .route("/games/{game_id}/stream", get(bot::stream_game))
+                .route("/stream/events", get(bot::stream_events))
+                // the handlers are shared with the html side, so their errors are plain text
+                .fallback(api::not_found)
+                .layer(axum::middleware::map_response(api::json_errors)),
+        )
+        .with_state(Arc::clone(&state))
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            player::ensure_player,
+        ))
+        // compressing a stream would hold its lines back until there are enough of them
+        .layer(
+            tower_http::compression::CompressionLayer::new().compress_when(
+                DefaultPredicate::new().and(NotForContentType::const_new(bot::NDJSON)),
+            ),
+        );
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", options.port))
         .await
@@ -532,6 +1911,14 @@ struct AppError(anyhow::Error);
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some(not_found) = self.0.downcast_ref::<GameNotFound>() {
+            return (StatusCode::NOT_FOUND, not_found.to_string()).into_response();
+        }
+
+        if let Some(bad_request) = self.0.downcast_ref::<BadRequest>() {
+            return (StatusCode::BAD_REQUEST, bad_request.to_string()).into_response();
+        }
+
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
@@ -548,3 +1935,33 @@ where
         Self(err.into())
     }
 }
+
+/// something about the request means we won't do what it asks
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn games_have_to_be_kept_for_a_while() {
+        for args in [
+            vec!["chez", "--game-idle-timeout", "0"],
+            vec!["chez", "serve", "--game-idle-timeout", "0"],
+        ] {
+            assert!(Options::try_parse_from(args).is_err());
+        }
+
+        let options = Options::try_parse_from(["chez", "--game-idle-timeout", "1"]).unwrap();
+        assert_eq!(options.serve.game_idle_timeout, 1);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::clock::Clock;
+use crate::game::GameState;
+use crate::piece::Color;
+use crate::player::{self, PlayerId};
+use crate::rating::{self, Category};
+use crate::{AppError, AppState, BadRequest, hx_location};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Form};
+use maud::{Markup, html};
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+use uuid::Uuid;
+
+/// nobody waits in the queue forever
+const SEEK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// the searching page polls every second,
+/// so a seek that hasn't been polled in a while belongs to a closed tab
+const SEEK_ABANDONED: Duration = Duration::from_secs(10);
+
+/// how far apart two ratings can be when a seek is first made
+const INITIAL_RATING_WINDOW: f64 = 100.0;
+
+/// how much wider the window gets for every second spent waiting
+const RATING_WINDOW_GROWTH: f64 = 10.0;
+
+const MAX_RATING_WINDOW: f64 = 1000.0;
+
+/// a player waiting to be paired
+pub struct Seek {
+    player: PlayerId,
+    /// as stored in the db, `None` for untimed games
+    time_control: Option<String>,
+    rated: bool,
+    /// the player's rating in the seek's category
+    rating: f64,
+    created: Instant,
+    last_seen: Instant,
+}
+
+impl Seek {
+    /// how far from the seeker's rating an opponent's rating may be,
+    /// which grows the longer they wait
+    fn rating_window(&self) -> f64 {
+        (INITIAL_RATING_WINDOW + RATING_WINDOW_GROWTH * self.created.elapsed().as_secs_f64())
+            .min(MAX_RATING_WINDOW)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.created.elapsed() > SEEK_TIMEOUT || self.last_seen.elapsed() > SEEK_ABANDONED
+    }
+
+    /// whether the two can be paired, which has to be within both of their windows
+    fn accepts(&self, other: &Seek) -> bool {
+        let difference = (self.rating - other.rating).abs();
+
+        self.player != other.player
+            && self.time_control == other.time_control
+            && self.rated == other.rated
+            && difference <= self.rating_window()
+            && difference <= other.rating_window()
+    }
+}
+
+#[derive(Default)]
+pub struct Queue {
+    /// oldest first, so the players who have waited longest are paired first
+    seeks: Vec<Seek>,
+    /// games made for players who were waiting in the queue,
+    /// waiting for them to poll and be sent there, and when they were made
+    matched: HashMap<PlayerId, (Uuid, Instant)>,
+}
+
+impl Queue {
+    /// drop seeks that timed out or that nobody is waiting on anymore,
+    /// and pairings for players who stopped polling before they were sent to their game
+    pub fn expire(&mut self) {
+        let before = self.seeks.len();
+
+        self.seeks.retain(|seek| !seek.is_expired());
+
+        let expired = before - self.seeks.len();
+
+        if expired > 0 {
+            debug!("expired {expired} seeks");
+        }
+
+        self.matched
+            .retain(|_, (_, matched)| matched.elapsed() <= SEEK_ABANDONED);
+    }
+
+    /// take the first waiting seek that `seek` can be paired with
+    fn take_opponent(&mut self, seek: &Seek) -> Option<Seek> {
+        let i = self
+            .seeks
+            .iter()
+            .position(|waiting| waiting.accepts(seek))?;
+
+        Some(self.seeks.remove(i))
+    }
+
+    fn seek(&mut self, player: PlayerId) -> Option<&mut Seek> {
+        self.seeks.iter_mut().find(|seek| seek.player == player)
+    }
+
+    fn cancel(&mut self, player: PlayerId) {
+        self.seeks.retain(|seek| seek.player != player);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SeekParams {
+    time_control: Option<String>,
+    #[serde(default)]
+    rated: bool,
+}
+
+/// join the queue, or get paired right away with someone already in it
+pub async fn seek_create(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<SeekParams>,
+) -> Result<Response, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    if params.rated && player::name(&mut conn, player).await?.is_none() {
+        return Err(BadRequest("only registered players can play rated games".into()).into());
+    }
+
+    // anything that isn't a time control means an untimed game
+    let clock = params.time_control.as_deref().and_then(Clock::parse);
+
+    let rating = rating::rating(&mut conn, player, Category::of(clock.as_ref()))
+        .await?
+        .unwrap_or_default();
+
+    let seek = Seek {
+        player,
+        time_control: clock.as_ref().map(Clock::time_control),
+        rated: params.rated,
+        rating: rating.rating,
+        created: Instant::now(),
+        last_seen: Instant::now(),
+    };
+
+    state.queue.expire();
+
+    // a player only ever has one seek
+    state.queue.cancel(player);
+
+    let Some(opponent) = state.queue.take_opponent(&seek) else {
+        let out = searching(&seek);
+
+        state.queue.seeks.push(seek);
+
+        return Ok(out.into_response());
+    };
+
+    let (white, black) = if whites_minus_blacks(&mut conn, seek.player).await?
+        > whites_minus_blacks(&mut conn, opponent.player).await?
+    {
+        (opponent.player, seek.player)
+    } else {
+        (seek.player, opponent.player)
+    };
+
+    let (game_id,): (Uuid,) = sqlx::query_as(
+        "
+    insert into games (id, white_player, black_player, time_control, rated)
+    values (?, ?, ?, ?, ?)
+    returning id;
+    ",
+    )
+    .bind(Uuid::new_v4())
+    .bind(white.0.hyphenated())
+    .bind(black.0.hyphenated())
+    .bind(&seek.time_control)
+    .bind(seek.rated)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    debug!("paired {white:?} and {black:?} in game {game_id}");
+
+    let mut game_state = GameState::new();
+
+    game_state.sit(Color::White, white);
+    game_state.sit(Color::Black, black);
+
+    game_state.clock = clock;
+
+    game_state.rated = seek.rated;
+
+    game_state.load_profiles(&mut conn).await?;
+
+    state.games.insert(game_id, game_state);
+
+    state
+        .queue
+        .matched
+        .insert(opponent.player, (game_id, Instant::now()));
+
+    Ok(hx_location(&format!("/games/{game_id}/play")).into_response())
+}
+
+/// the searching page polls this until the player is paired
+pub async fn seek_poll(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Response, AppError> {
+    let mut state = state.lock().await;
+
+    if let Some((game_id, _)) = state.queue.matched.remove(&player) {
+        return Ok(hx_location(&format!("/games/{game_id}/play")).into_response());
+    }
+
+    state.queue.expire();
+
+    match state.queue.seek(player) {
+        Some(seek) => {
+            seek.last_seen = Instant::now();
+
+            Ok(searching(seek).into_response())
+        }
+        None => Ok(html! {
+            div {
+                p { "Nobody was found to play with." }
+                a href="/games/new" class="underline" { "Try again" }
+            }
+        }
+        .into_response()),
+    }
+}
+
+pub async fn seek_cancel(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+) -> impl IntoResponse {
+    state.lock().await.queue.cancel(player);
+
+    [("HX-Location", "/games/new")]
+}
+
+fn searching(seek: &Seek) -> Markup {
+    html! {
+        div
+            id="searching"
+            class="p-4"
+            hx-get="/games/seek"
+            hx-trigger="every 1s"
+            hx-swap="outerHTML"
+        {
+            p {
+                "Looking for someone to play "
+                @if let Some(clock) = seek.time_control.as_deref().and_then(Clock::parse) {
+                    (clock.label())
+                } @else {
+                    "untimed"
+                }
+                @if seek.rated { ", rated" } @else { ", casual" }
+                "…"
+            }
+            p {
+                "Rating " (format!("{:.0}", seek.rating))
+                " ± " (format!("{:.0}", seek.rating_window()))
+                ", waiting " (seek.created.elapsed().as_secs()) "s"
+            }
+            button hx-delete="/games/seek" { "Cancel" }
+        }
+    }
+}
+
+/// how many more games `player` has played as white than as black,
+/// so the one who has had white more often gets black
+async fn whites_minus_blacks(conn: &mut SqliteConnection, player: PlayerId) -> anyhow::Result<i64> {
+    let (balance,): (i64,) = sqlx::query_as(
+        "
+    select
+        coalesce(sum(white_player = ?1), 0) - coalesce(sum(black_player = ?1), 0)
+    from games
+    where white_player = ?1 or black_player = ?1;
+    ",
+    )
+    .bind(player.0.hyphenated())
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(balance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seek(rating: f64, waited: Duration) -> Seek {
+        let created = Instant::now() - waited;
+
+        Seek {
+            player: PlayerId(Uuid::new_v4()),
+            time_control: Some("300+3".to_string()),
+            rated: true,
+            rating,
+            created,
+            last_seen: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn both_windows_have_to_fit() {
+        // the window has grown to 1000 for the one who waited, but is 100 for the newcomer
+        let waiting = seek(1500.0, Duration::from_secs(90));
+        let newcomer = seek(1900.0, Duration::ZERO);
+
+        assert!(!waiting.accepts(&newcomer));
+        assert!(!newcomer.accepts(&waiting));
+
+        let close = seek(1550.0, Duration::ZERO);
+
+        assert!(waiting.accepts(&close));
+        assert!(close.accepts(&waiting));
+    }
+
+    #[test]
+    fn pairings_nobody_picks_up_expire() {
+        let mut queue = Queue::default();
+        let player = PlayerId(Uuid::new_v4());
+
+        queue.matched.insert(
+            player,
+            (Uuid::new_v4(), Instant::now() - SEEK_ABANDONED * 2),
+        );
+
+        queue.expire();
+
+        assert!(queue.matched.is_empty());
+    }
+}
@@ -0,0 +1,1043 @@
+use crate::annotation::{self, Annotation, Nag};
+use crate::board::{Board, Move, STARTING_FEN};
+use crate::clock::Clock;
+use crate::engine::Engine;
+use crate::game::{GameResult, GameState, Outcome, Termination};
+use crate::piece::{Color, PieceKind, Position};
+use crate::variant::Variant;
+use crate::{AppError, AppState};
+use anyhow::{Context, anyhow};
+use axum::extract::{Path, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use sqlx::SqliteConnection;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// a game in Portable Game Notation: its tags, and its moves
+/// in standard algebraic notation
+#[derive(Debug, Default)]
+pub struct Pgn {
+    pub tags: Vec<(String, String)>,
+    pub moves: Vec<String>,
+    /// notes on the moves, by ply
+    pub annotations: BTreeMap<i64, Annotation>,
+    /// "1-0", "0-1", "1/2-1/2" or "*" for a game that isn't over
+    pub result: String,
+}
+
+impl Pgn {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// the moves and result of a game, without the tags that need the db
+    pub fn of(game_state: &GameState) -> Self {
+        let (mut board, mut to_move) = Board::from_fen(&game_state.starting_fen)
+            .unwrap_or_else(|| (Board::new(), Color::White));
+
+        board.variant = game_state.variant;
+
+        let mut moves = vec![];
+
+        for m in &game_state.moves {
+            moves.push(san(&board, to_move, m));
+            board.make_move(m);
+            to_move = to_move.invert();
+        }
+
+        let mut tags = vec![("FEN".into(), game_state.starting_fen.clone())];
+
+        if game_state.variant != Variant::Standard {
+            tags.push(("Variant".into(), game_state.variant.label().into()));
+        }
+
+        Self {
+            tags,
+            moves,
+            annotations: BTreeMap::new(),
+            result: game_state
+                .result
+                .map(|result| result.outcome.as_str().to_string())
+                .unwrap_or_else(|| "*".to_string()),
+        }
+    }
+
+    /// the game replayed move by move, as far as PGN can tell:
+    /// the names of the players, the clocks if there are clock tags,
+    /// and the result with a guess at how it came about
+    pub fn game_state(&self) -> anyhow::Result<GameState> {
+        let mut game_state = GameState::new();
+
+        game_state.starting_fen = self.tag("FEN").unwrap_or(STARTING_FEN).to_string();
+
+        (game_state.board, game_state.to_move) = Board::from_fen(&game_state.starting_fen)
+            .ok_or_else(|| anyhow!("bad FEN {}", game_state.starting_fen))?;
+
+        game_state.variant = match self.tag("Variant") {
+            Some(variant) => {
+                Variant::from_label(variant).ok_or_else(|| anyhow!("chez can't play {variant}"))?
+            }
+            None => Variant::Standard,
+        };
+
+        game_state.board.variant = game_state.variant;
+
+        if game_state.variant == Variant::Chess960 {
+            game_state.chess960_position = Board::chess960_id(&game_state.starting_fen);
+        }
+
+        for san in &self.moves {
+            let m = parse_san(&game_state.board, game_state.to_move, san).ok_or_else(|| {
+                anyhow!(
+                    "{san} is not a move after {}",
+                    moves_so_far(&game_state.moves)
+                )
+            })?;
+
+            game_state.board.make_move(&m);
+            game_state.to_move = game_state.to_move.invert();
+            game_state.ply += 1;
+            game_state.moves.push(m);
+        }
+
+        // "?" is an empty seat
+        game_state.white_profile.name = self
+            .tag("White")
+            .filter(|name| *name != "?")
+            .map(Into::into);
+        game_state.black_profile.name = self
+            .tag("Black")
+            .filter(|name| *name != "?")
+            .map(Into::into);
+
+        let on_board = game_state.result_on_board();
+
+        game_state.result = Outcome::parse(&self.result).map(|outcome| {
+            let termination = if let Some(on_board) = on_board {
+                on_board.termination
+            } else if self.tag("Termination") == Some("time forfeit") {
+                Termination::Timeout
+            } else if outcome == Outcome::Draw {
+                Termination::Agreement
+            } else {
+                Termination::Resignation
+            };
+
+            GameResult {
+                outcome,
+                termination,
+            }
+        });
+
+        game_state.clock = self.tag("TimeControl").and_then(Clock::parse);
+
+        if let Some(clock) = &mut game_state.clock {
+            for (color, tag) in [(Color::White, "WhiteClock"), (Color::Black, "BlackClock")] {
+                if let Some(remaining) = self.tag(tag).and_then(parse_clock_tag) {
+                    clock.set_remaining(color, remaining);
+                }
+            }
+
+            // the same as a game loaded from the db
+            if game_state.ply > 0 && game_state.result.is_none() {
+                clock.start(game_state.to_move, Instant::now());
+            }
+        }
+
+        Ok(game_state)
+    }
+}
+
+impl fmt::Display for Pgn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_tags(f)?;
+
+        writeln!(f, "{}", self.movetext())
+    }
+}
+
+impl Pgn {
+    /// the numbered moves and the result, in lines under 80 characters
+    pub fn movetext(&self) -> String {
+        let (mut number, mut to_move) = self
+            .tag("FEN")
+            .map(move_number)
+            .unwrap_or((1, Color::White));
+
+        let mut tokens = vec![];
+
+        // black's moves need their number at the start and after a comment
+        let mut needs_number = true;
+
+        for (i, san) in self.moves.iter().enumerate() {
+            match to_move {
+                Color::White => tokens.push(format!("{number}.")),
+                Color::Black if needs_number => tokens.push(format!("{number}...")),
+                Color::Black => (),
+            }
+
+            tokens.push(san.clone());
+
+            needs_number = false;
+
+            if let Some(annotation) = self.annotations.get(&(i as i64 + 1)) {
+                if let Some(nag) = annotation.nag {
+                    tokens.push(format!("${}", nag.number()));
+                }
+
+                if let Some(comment) = &annotation.comment {
+                    // a "}" would end the comment early
+                    let comment = comment.replace('}', ")");
+
+                    let mut words: Vec<String> =
+                        comment.split_whitespace().map(str::to_string).collect();
+
+                    if let Some(first) = words.first_mut() {
+                        first.insert(0, '{');
+                    }
+
+                    if let Some(last) = words.last_mut() {
+                        last.push('}');
+                    }
+
+                    tokens.extend(words);
+
+                    needs_number = true;
+                }
+            }
+
+            if to_move == Color::Black {
+                number += 1;
+            }
+
+            to_move = to_move.invert();
+        }
+
+        tokens.push(self.result.clone());
+
+        wrap(tokens)
+    }
+
+    /// the tags, one to a line, and the blank line that ends them
+    pub fn fmt_tags(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.tags {
+            writeln!(
+                f,
+                "[{name} \"{}\"]",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )?;
+        }
+
+        writeln!(f)
+    }
+}
+
+/// movetext tokens joined up into lines under 80 characters
+pub fn wrap(tokens: Vec<String>) -> String {
+    let mut movetext = String::new();
+    let mut line_length = 0;
+
+    for token in tokens {
+        if line_length > 0 && line_length + 1 + token.len() > 79 {
+            movetext.push('\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            movetext.push(' ');
+            line_length += 1;
+        }
+
+        movetext.push_str(&token);
+        line_length += token.len();
+    }
+
+    movetext
+}
+
+/// the move number and who is to move, from the last two fields of a FEN
+pub fn move_number(fen: &str) -> (u32, Color) {
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+
+    let to_move = match fields.get(1) {
+        Some(&"b") => Color::Black,
+        _ => Color::White,
+    };
+
+    let number = fields
+        .get(5)
+        .and_then(|number| number.parse().ok())
+        .unwrap_or(1);
+
+    (number, to_move)
+}
+
+/// every game in a PGN file
+pub fn parse(text: &str) -> anyhow::Result<Vec<Pgn>> {
+    let mut games = vec![];
+    let mut game = Pgn::default();
+    let mut in_movetext = false;
+
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if !in_movetext => {
+                // values can have a "]" in them, like crazyhouse FENs
+                let mut tag = String::new();
+                let mut quoted = false;
+
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' if !quoted => break,
+                        '"' => quoted = !quoted,
+                        '\\' if quoted => {
+                            tag.push(c);
+
+                            if let Some(escaped) = chars.next() {
+                                tag.push(escaped);
+                            }
+
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    tag.push(c);
+                }
+
+                let (name, value) = tag
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| anyhow!("bad tag [{tag}]"))?;
+
+                // only the quotes around the value, an escaped one can end it
+                let value = value.trim();
+                let value = value.strip_prefix('"').unwrap_or(value);
+                let value = value.strip_suffix('"').unwrap_or(value);
+
+                let value = value.replace("\\\"", "\"").replace("\\\\", "\\");
+
+                game.tags.push((name.to_string(), value));
+            }
+            '{' => {
+                let comment: String = chars.by_ref().take_while(|c| *c != '}').collect();
+
+                let comment = comment.split_whitespace().collect::<Vec<_>>().join(" ");
+
+                // comments before the first move are about the game, not a move
+                if !game.moves.is_empty() && !comment.is_empty() {
+                    let annotation = game.annotations.entry(game.moves.len() as i64).or_default();
+
+                    annotation.comment = Some(match annotation.comment.take() {
+                        Some(earlier) => format!("{earlier} {comment}"),
+                        None => comment,
+                    });
+                }
+            }
+            ';' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            // variations are skipped, only the main line is kept
+            '(' => {
+                let mut depth = 1;
+
+                for c in chars.by_ref() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => (),
+                    }
+
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() => (),
+            c => {
+                in_movetext = true;
+
+                let mut token = String::from(c);
+
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"{;()[".contains(*c)) {
+                    token.push(c);
+                }
+
+                match token.as_str() {
+                    "1-0" | "0-1" | "1/2-1/2" | "*" => {
+                        game.result = token;
+                        games.push(std::mem::take(&mut game));
+                        in_movetext = false;
+                    }
+                    // numeric annotation glyphs
+                    token if token.starts_with('$') => {
+                        let nag = token[1..].parse().ok().and_then(Nag::from_number);
+
+                        if let Some(nag) = nag
+                            && !game.moves.is_empty()
+                        {
+                            game.annotations
+                                .entry(game.moves.len() as i64)
+                                .or_default()
+                                .nag = Some(nag);
+                        }
+                    }
+                    token => {
+                        // "12." or "12..." on its own or stuck to the move
+                        let san =
+                            token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+                        // "!" and "?" stuck to the end of the move are glyphs too
+                        let (san, glyph) = san.split_at(san.trim_end_matches(['!', '?']).len());
+
+                        if !san.is_empty() {
+                            game.moves.push(san.to_string());
+
+                            if let Some(nag) = Nag::parse(glyph) {
+                                game.annotations
+                                    .entry(game.moves.len() as i64)
+                                    .or_default()
+                                    .nag = Some(nag);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // a game without a result at the end of the file
+    if in_movetext {
+        game.result = "*".to_string();
+        games.push(game);
+    }
+
+    Ok(games)
+}
+
+/// `m` in standard algebraic notation, e.g. "Nbd7", "exd6", "e8=Q+" or "O-O".
+/// `m` hasn't been played on `board` yet.
+pub fn san(board: &Board, to_move: Color, m: &Move) -> String {
+    let mut san = String::new();
+
+    if let Some(kind) = m.drop {
+        san.push(kind.letter().to_ascii_uppercase());
+        san.push('@');
+        san.push_str(&m.to.name());
+    } else if let Some(piece) = board.get_piece(&m.from)
+        && piece.kind == PieceKind::King
+        && let Some(rook) = board.castling_rook(piece, &m.to)
+    {
+        san.push_str(if rook.column > m.from.column {
+            "O-O"
+        } else {
+            "O-O-O"
+        });
+    } else {
+        let Some(piece) = board.get_piece(&m.from) else {
+            return m.uci();
+        };
+
+        let capture = board.get_piece(&m.to).is_some()
+            || (piece.kind == PieceKind::Pawn && m.from.column != m.to.column);
+
+        if piece.kind == PieceKind::Pawn {
+            if capture {
+                san.push(m.from.name().remove(0));
+            }
+        } else {
+            san.push(piece.kind.letter().to_ascii_uppercase());
+
+            // the other pieces of the same kind that could go to the same square
+            let others: Vec<Position> = board
+                .all_legal_moves(to_move)
+                .into_iter()
+                .filter(|other| other.to == m.to && other.from != m.from)
+                .filter(|other| {
+                    board
+                        .get_piece(&other.from)
+                        .is_some_and(|other| other.kind == piece.kind)
+                })
+                .map(|other| other.from)
+                .collect();
+
+            let from = m.from.name();
+
+            if !others.is_empty() {
+                if others.iter().all(|other| other.column != m.from.column) {
+                    san.push_str(&from[..1]);
+                } else if others.iter().all(|other| other.row != m.from.row) {
+                    san.push_str(&from[1..]);
+                } else {
+                    san.push_str(&from);
+                }
+            }
+        }
+
+        if capture {
+            san.push('x');
+        }
+
+        san.push_str(&m.to.name());
+
+        if let Some(promotion) = m.promotion {
+            san.push('=');
+            san.push(promotion.letter().to_ascii_uppercase());
+        }
+    }
+
+    let mut after = board.clone();
+    after.make_move(m);
+
+    if after.is_in_check(to_move.invert()) {
+        san.push(if after.has_legal_moves(to_move.invert()) {
+            '+'
+        } else {
+            '#'
+        });
+    }
+
+    san
+}
+
+/// the move `san` stands for on `board`, if it is one `to_move` can make
+pub fn parse_san(board: &Board, to_move: Color, san: &str) -> Option<Move> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+
+    let kingside = match san {
+        "O-O" | "0-0" => Some(true),
+        "O-O-O" | "0-0-0" => Some(false),
+        _ => None,
+    };
+
+    // drops, as in crazyhouse. pawn drops can leave out the "P".
+    if let Some((kind, to)) = san.split_once('@') {
+        let kind = match kind.chars().next() {
+            Some(c) => PieceKind::parse(c)?,
+            None => PieceKind::Pawn,
+        };
+
+        let to = Position::parse(to)?;
+
+        return board
+            .legal_drops(to_move, kind)
+            .contains(&to)
+            .then_some(Move::drop(kind, to));
+    }
+
+    if let Some(kingside) = kingside {
+        // in Antichess the king can be taken, and there is nothing to castle with
+        let king = board
+            .get_pieces(to_move)
+            .find(|piece| piece.kind == PieceKind::King)?;
+
+        // however the board writes castling on that side
+        return board
+            .legal_moves(king)
+            .into_iter()
+            .find(|to| {
+                board
+                    .castling_rook(king, to)
+                    .is_some_and(|rook| (rook.column > king.position.column) == kingside)
+            })
+            .map(|to| Move {
+                from: king.position,
+                to,
+                promotion: None,
+                drop: None,
+            });
+    }
+
+    let (san, promotion) = match san.split_once('=') {
+        Some((san, promotion)) => (san, Some(PieceKind::parse(promotion.chars().next()?)?)),
+        None => match san.chars().last() {
+            // some programs leave out the "="
+            Some(c) if "QRBN".contains(c) && san.len() > 2 => {
+                (&san[..san.len() - 1], PieceKind::parse(c))
+            }
+            _ => (san, None),
+        },
+    };
+
+    let (kind, san) = match san.chars().next()? {
+        c @ ('K' | 'Q' | 'R' | 'B' | 'N') => (PieceKind::parse(c)?, &san[1..]),
+        _ => (PieceKind::Pawn, san),
+    };
+
+    let san: String = san.chars().filter(|c| *c != 'x' && *c != '-').collect();
+
+    let to = Position::parse(san.get(san.len().checked_sub(2)?..)?)?;
+
+    let hint = &san[..san.len() - 2];
+
+    let matches_hint = |from: &Position| {
+        hint.chars().all(|c| match c {
+            'a'..='h' => from.column == c as i8 - 'a' as i8,
+            '1'..='8' => from.row == c as i8 - '1' as i8,
+            _ => false,
+        })
+    };
+
+    board
+        .all_legal_moves(to_move)
+        .into_iter()
+        .find(|m| {
+            m.to == to
+                && matches_hint(&m.from)
+                && board
+                    .get_piece(&m.from)
+                    .is_some_and(|piece| piece.kind == kind)
+        })
+        .map(|m| Move {
+            promotion: promotion.or(m.promotion),
+            ..m
+        })
+}
+
+#[derive(sqlx::FromRow)]
+struct GameRow {
+    white: Option<String>,
+    black: Option<String>,
+    white_seated: bool,
+    black_seated: bool,
+    computer: Option<String>,
+    rated: bool,
+    result: Option<String>,
+    termination: Option<String>,
+    time_control: Option<String>,
+    variant: String,
+    starting_fen: String,
+    date: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct MoveRow {
+    from_column: i8,
+    from_row: i8,
+    to_column: i8,
+    to_row: i8,
+    promotion: Option<String>,
+    dropped: Option<String>,
+}
+
+/// a game from the db as PGN
+pub async fn export(conn: &mut SqliteConnection, game_id: Uuid) -> anyhow::Result<Pgn> {
+    let game: GameRow = sqlx::query_as(
+        "
+    select
+        coalesce(white.name, games.white_name) as white,
+        coalesce(black.name, games.black_name) as black,
+        games.white_player is not null as white_seated,
+        games.black_player is not null as black_seated,
+        computer,
+        rated,
+        result,
+        termination,
+        time_control,
+        variant,
+        starting_fen,
+        strftime('%Y.%m.%d', games.inserted_at) as date
+    from games
+    left join users as white on white.id = games.white_player
+    left join users as black on black.id = games.black_player
+    where games.id = ?;
+    ",
+    )
+    .bind(game_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| anyhow!("no game with id {game_id}"))?;
+
+    let moves: Vec<MoveRow> = sqlx::query_as(
+        "
+    select from_column, from_row, to_column, to_row, promotion, dropped
+    from moves
+    where game_id = ?
+    order by ply asc;
+    ",
+    )
+    .bind(game_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let (mut board, mut to_move) = Board::from_fen(&game.starting_fen)
+        .ok_or_else(|| anyhow!("game {game_id} has a bad starting position"))?;
+
+    let variant = Variant::parse(&game.variant).unwrap_or_default();
+
+    board.variant = variant;
+
+    let mut pgn = Pgn {
+        result: game.result.clone().unwrap_or_else(|| "*".to_string()),
+        ..Pgn::default()
+    };
+
+    for row in moves {
+        let m = Move {
+            from: (row.from_column, row.from_row).into(),
+            to: (row.to_column, row.to_row).into(),
+            promotion: row
+                .promotion
+                .and_then(|promotion| promotion.chars().next())
+                .and_then(PieceKind::parse),
+            drop: row
+                .dropped
+                .and_then(|dropped| dropped.chars().next())
+                .and_then(PieceKind::parse),
+        };
+
+        pgn.moves.push(san(&board, to_move, &m));
+
+        board.make_move(&m);
+        to_move = to_move.invert();
+    }
+
+    // the computer sits in whichever seat has no player
+    let computer = game
+        .computer
+        .as_deref()
+        .and_then(Engine::parse)
+        .map(|engine| format!("Computer ({})", engine.label()));
+
+    // "?" is PGN for unknown, which is as much as we know about an empty seat
+    let name = |name: Option<String>, seated: bool| match (name, seated) {
+        (Some(name), _) => name,
+        (None, true) => "Anonymous".to_string(),
+        (None, false) => computer.clone().unwrap_or_else(|| "?".to_string()),
+    };
+
+    let event = if game.rated {
+        "Rated game"
+    } else {
+        "Casual game"
+    };
+
+    pgn.tags = vec![
+        ("Event".into(), event.into()),
+        ("Site".into(), "chez".into()),
+        (
+            "Date".into(),
+            game.date.unwrap_or_else(|| "????.??.??".into()),
+        ),
+        ("Round".into(), "-".into()),
+        ("White".into(), name(game.white, game.white_seated)),
+        ("Black".into(), name(game.black, game.black_seated)),
+        ("Result".into(), pgn.result.clone()),
+        ("GameId".into(), game_id.to_string()),
+        (
+            "TimeControl".into(),
+            game.time_control.unwrap_or_else(|| "-".into()),
+        ),
+    ];
+
+    if let Some(termination) = game.termination.as_deref().and_then(Termination::parse) {
+        let termination = match termination {
+            Termination::Timeout => "time forfeit",
+            _ => "normal",
+        };
+
+        pgn.tags.push(("Termination".into(), termination.into()));
+    }
+
+    if variant != Variant::Standard {
+        pgn.tags.push(("Variant".into(), variant.label().into()));
+    }
+
+    pgn.annotations = annotation::load(conn, game_id).await?;
+
+    if game.starting_fen != STARTING_FEN || variant != Variant::Standard {
+        pgn.tags.push(("SetUp".into(), "1".into()));
+        pgn.tags.push(("FEN".into(), game.starting_fen));
+    }
+
+    Ok(pgn)
+}
+
+/// a game as PGN, for downloading or for the terminal client to follow.
+/// games being played get the time left on each clock as well.
+pub async fn game_pgn(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let game_state = state.game(game_id).await?;
+
+    let clocks = game_state
+        .clock
+        .as_ref()
+        .filter(|_| game_state.result.is_none())
+        .map(|clock| (clock.remaining(Color::White), clock.remaining(Color::Black)));
+
+    let mut pgn = export(&mut conn, game_id).await?;
+
+    if let Some((white, black)) = clocks {
+        pgn.tags.push(("WhiteClock".into(), clock_tag(white)));
+        pgn.tags.push(("BlackClock".into(), clock_tag(black)));
+    }
+
+    Ok((
+        [(CONTENT_TYPE, "application/x-chess-pgn; charset=utf-8")],
+        pgn.to_string(),
+    ))
+}
+
+/// time on a clock as "h:mm:ss"
+fn clock_tag(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn parse_clock_tag(tag: &str) -> Option<Duration> {
+    let mut seconds = 0;
+
+    for part in tag.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// store a game read from PGN, returning its id
+pub async fn import(conn: &mut SqliteConnection, pgn: &Pgn) -> anyhow::Result<Uuid> {
+    let game_state = pgn.game_state()?;
+
+    let outcome = game_state.result.map(|result| result.outcome);
+    let termination = game_state.result.map(|result| result.termination);
+
+    let time_control = pgn
+        .tag("TimeControl")
+        .and_then(Clock::parse)
+        .map(|clock| clock.time_control());
+
+    let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+    let (game_id,): (Uuid,) = sqlx::query_as(
+        "
+    insert into games
+    (id, white_name, black_name, result, termination, time_control,
+    variant, chess960_position, starting_fen, imported)
+    values (?, ?, ?, ?, ?, ?, ?, ?, ?, true)
+    returning id;
+    ",
+    )
+    .bind(Uuid::new_v4())
+    .bind(pgn.tag("White"))
+    .bind(pgn.tag("Black"))
+    .bind(outcome.map(|outcome| outcome.as_str()))
+    .bind(termination.map(|termination| termination.as_str()))
+    .bind(time_control)
+    .bind(game_state.variant.as_str())
+    .bind(game_state.chess960_position)
+    .bind(&game_state.starting_fen)
+    .fetch_one(&mut *tx)
+    .await
+    .context("could not store the game")?;
+
+    for (i, m) in game_state.moves.iter().enumerate() {
+        let ply = i as i64 + 1;
+
+        sqlx::query(
+            "
+    insert into moves
+    (game_id, ply, from_column, from_row, to_column, to_row, promotion, dropped, move_number)
+    values (?, ?, ?, ?, ?, ?, ?, ?, ?);
+    ",
+        )
+        .bind(game_id)
+        .bind(ply)
+        .bind(m.from.column)
+        .bind(m.from.row)
+        .bind(m.to.column)
+        .bind(m.to.row)
+        .bind(m.promotion.map(|kind| kind.letter().to_string()))
+        .bind(m.drop.map(|kind| kind.letter().to_string()))
+        .bind((ply + 1) / 2)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for (ply, annotation) in &pgn.annotations {
+        annotation::save(&mut tx, game_id, *ply, annotation).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(game_id)
+}
+
+/// for error messages
+fn moves_so_far(moves: &[Move]) -> String {
+    if moves.is_empty() {
+        return "the start".to_string();
+    }
+
+    moves.iter().map(Move::uci).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(fen: &str, variant: Variant) -> (Board, Color) {
+        let (mut board, to_move) = Board::from_fen(fen).unwrap();
+        board.variant = variant;
+        (board, to_move)
+    }
+
+    /// every move in the position reads back as itself once written down
+    fn assert_round_trips(fen: &str, variant: Variant) {
+        let (board, to_move) = board(fen, variant);
+
+        for m in board.all_legal_moves(to_move) {
+            let san = san(&board, to_move, &m);
+
+            assert_eq!(parse_san(&board, to_move, &san), Some(m), "{san} in {fen}");
+        }
+    }
+
+    fn written(fen: &str, uci: &str) -> String {
+        let (board, to_move) = board(fen, Variant::Standard);
+
+        san(&board, to_move, &Move::parse_uci(uci).unwrap())
+    }
+
+    #[test]
+    fn every_move_round_trips_through_san() {
+        assert_round_trips(STARTING_FEN, Variant::Standard);
+
+        // castling both ways, rooks and knights that need telling apart,
+        // and pawns that promote by moving and by taking
+        assert_round_trips(
+            "r3k2r/1P4P1/8/3N1N2/8/8/8/R3K2R w KQkq - 0 1",
+            Variant::Standard,
+        );
+    }
+
+    #[test]
+    fn drops_round_trip_through_san() {
+        assert_round_trips(
+            "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R[Nbp] w KQkq - 0 1",
+            Variant::Crazyhouse,
+        );
+
+        let (board, to_move) = board("4k3/8/8/8/8/8/8/4K3[Pn] w - - 0 1", Variant::Crazyhouse);
+
+        assert_eq!(
+            san(
+                &board,
+                to_move,
+                &Move::drop(PieceKind::Pawn, Position::parse("d7").unwrap())
+            ),
+            "P@d7+"
+        );
+        assert_eq!(parse_san(&board, to_move, "@d7"), Move::parse_uci("P@d7"));
+        assert_eq!(parse_san(&board, to_move, "N@e7"), None);
+    }
+
+    #[test]
+    fn antichess_moves_round_trip_without_a_king() {
+        assert_round_trips("8/8/8/3p4/8/8/1P6/N7 w - - 0 1", Variant::Antichess);
+    }
+
+    #[test]
+    fn san_marks_castling_checks_mates_and_which_piece_moved() {
+        let fen = "r3k2r/1P4P1/8/3N1N2/8/8/8/R3K2R w KQkq - 0 1";
+
+        assert_eq!(written(fen, "e1g1"), "O-O");
+        assert_eq!(written(fen, "e1c1"), "O-O-O");
+        assert_eq!(written(fen, "d5e7"), "Nde7");
+        assert_eq!(written(fen, "b7a8q"), "bxa8=Q+");
+        assert_eq!(written(fen, "g7g8n"), "g8=N");
+
+        let fen = "rnbqkbnr/ppppp2p/8/5pp1/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 1";
+
+        assert_eq!(written(fen, "d1h5"), "Qh5#");
+
+        let fen = "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1";
+
+        assert_eq!(written(fen, "e5d6"), "exd6");
+        assert_round_trips(fen, Variant::Standard);
+    }
+
+    #[test]
+    fn pgn_round_trips() {
+        let text = r#"[Event "Casual game"]
+[White "Someone \"quoted\""]
+[Black "?"]
+[FEN "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 2 3"]
+
+3... Nf6 4. Nc3 Bb4 5. Nd5 Nxd5 6. exd5 e4 7. Nd4 Bc5 8. c3 Bxd4 9. cxd4 Qg5
+10. d3 *
+"#;
+
+        let games = parse(text).unwrap();
+        assert_eq!(games.len(), 1);
+
+        let pgn = &games[0];
+
+        assert_eq!(pgn.tag("White"), Some("Someone \"quoted\""));
+        assert_eq!(pgn.moves.len(), 14);
+        assert_eq!(pgn.result, "*");
+
+        let exported = pgn.to_string();
+
+        assert!(exported.lines().all(|line| line.len() < 80), "{exported}");
+        assert!(exported.contains("\n\n3... Nf6 4. Nc3 Bb4"), "{exported}");
+
+        let again = parse(&exported).unwrap();
+
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].tags, pgn.tags);
+        assert_eq!(again[0].moves, pgn.moves);
+        assert_eq!(again[0].result, pgn.result);
+
+        let game_state = again[0].game_state().unwrap();
+
+        assert_eq!(game_state.moves.len(), 14);
+        assert_eq!(game_state.to_move, Color::Black);
+        assert_eq!(game_state.black_profile.name, None);
+        assert_eq!(game_state.result, None);
+        assert_eq!(Pgn::of(&game_state).moves, pgn.moves);
+    }
+
+    #[test]
+    fn annotations_round_trip_through_pgn() {
+        let text = "1. e4 e5 2. Nf3 $1 {the usual} 2... Nc6 3. Bb5?! {a long comment that goes \
+            over more than one line of the file, so the export has to wrap it} a6 *";
+
+        let pgn = &parse(text).unwrap()[0];
+
+        assert_eq!(pgn.moves, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"]);
+        assert_eq!(pgn.annotations[&3].nag, Some(Nag::Good));
+        assert_eq!(pgn.annotations[&3].comment.as_deref(), Some("the usual"));
+        assert_eq!(pgn.annotations[&5].nag, Some(Nag::Dubious));
+
+        let movetext = pgn.movetext();
+
+        assert!(movetext.lines().all(|line| line.len() < 80), "{movetext}");
+        // black's move after a comment needs its number again
+        assert!(movetext.starts_with("1. e4 e5 2. Nf3 $1 {the usual} 2... Nc6 3. Bb5 $6"));
+
+        let again = &parse(&movetext).unwrap()[0];
+
+        assert_eq!(again.moves, pgn.moves);
+        assert_eq!(again.annotations, pgn.annotations);
+    }
+}
@@ -2,9 +2,9 @@ use crate::board::Board;
 use std::collections::HashSet;
 use std::ops::Rem;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub enum Color {
     #[serde(rename = "black")]
     Black,
@@ -13,6 +13,22 @@ pub enum Color {
 }
 
 impl Color {
+    /// the color as forms and the command line have it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Color::Black => "black",
+            Color::White => "white",
+        }
+    }
+
+    pub fn parse(color: &str) -> Option<Self> {
+        match color {
+            "black" => Some(Color::Black),
+            "white" => Some(Color::White),
+            _ => None,
+        }
+    }
+
     pub fn invert(&self) -> Color {
         match self {
             Color::Black => Color::White,
@@ -21,7 +37,7 @@ impl Color {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, Hash)]
 pub struct Position {
     pub row: i8,
     pub column: i8,
@@ -36,6 +52,22 @@ impl Position {
         self.column <= 7 && self.column >= 0 && self.row <= 7 && self.row >= 0
     }
 
+    /// the square in algebraic notation, e.g. "e4"
+    pub fn name(&self) -> String {
+        format!("{}{}", (b'a' + self.column as u8) as char, self.row + 1)
+    }
+
+    /// a square in algebraic notation, e.g. "e4"
+    pub fn parse(name: &str) -> Option<Self> {
+        let &[file, rank] = name.as_bytes() else {
+            return None;
+        };
+
+        let position = Position::new(file as i8 - b'a' as i8, rank as i8 - b'1' as i8);
+
+        position.is_on_board().then_some(position)
+    }
+
     pub fn color(&self) -> Color {
         if (self.column + self.row).rem(2) == 0 {
             Color::Black
@@ -77,7 +109,8 @@ impl From<(i8, i8)> for Position {
 //     }
 // }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
 pub enum PieceKind {
     King,
     Queen,
@@ -87,12 +120,43 @@ pub enum PieceKind {
     Pawn,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+impl PieceKind {
+    /// the lowercase letter FEN, UCI and the db use for the kind of piece
+    pub fn letter(&self) -> char {
+        match self {
+            PieceKind::King => 'k',
+            PieceKind::Queen => 'q',
+            PieceKind::Rook => 'r',
+            PieceKind::Bishop => 'b',
+            PieceKind::Knight => 'n',
+            PieceKind::Pawn => 'p',
+        }
+    }
+
+    /// the kind of piece for a letter, in either case
+    pub fn parse(letter: char) -> Option<Self> {
+        match letter.to_ascii_lowercase() {
+            'k' => Some(PieceKind::King),
+            'q' => Some(PieceKind::Queen),
+            'r' => Some(PieceKind::Rook),
+            'b' => Some(PieceKind::Bishop),
+            'n' => Some(PieceKind::Knight),
+            'p' => Some(PieceKind::Pawn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, Hash)]
 pub struct Piece {
     pub kind: PieceKind,
     pub color: Color,
     pub position: Position,
     pub has_moved: bool,
+    /// whether this was a pawn once, which it goes back to being
+    /// when it's taken in variants with pockets
+    #[serde(skip)]
+    pub promoted: bool,
 }
 
 impl Piece {
@@ -102,6 +166,7 @@ impl Piece {
             color,
             position,
             has_moved: false,
+            promoted: false,
         }
     }
 
@@ -127,6 +192,14 @@ impl Piece {
         }
     }
 
+    /// the letter FEN uses for the piece, uppercase for white
+    pub fn letter(&self) -> char {
+        match self.color {
+            Color::White => self.kind.letter().to_ascii_uppercase(),
+            Color::Black => self.kind.letter(),
+        }
+    }
+
     pub fn repr(&self) -> &str {
         use PieceKind::*;
 
@@ -147,8 +220,6 @@ impl Piece {
     }
 }
 
-// TODO
-// - en passant
 fn pawn_moves(piece: &Piece, board: &Board) -> Vec<Position> {
     let straight_move_1 = match piece.color {
         Color::Black => |position: &Position| (position.column, position.row - 1),
@@ -184,16 +255,30 @@ fn pawn_moves(piece: &Piece, board: &Board) -> Vec<Position> {
         }
     }
 
+    // a pawn that just moved two squares past this one can be taken
+    // on the square it skipped over, but only by the other side
+    let en_passant = board.en_passant().filter(|square| {
+        square.row
+            == match piece.color {
+                Color::Black => 2,
+                Color::White => 5,
+            }
+    });
+
     let diagonal_left = diagonal_take_left(&piece.position).into();
-    if let Some(other_piece) = board.get_piece(&diagonal_left)
-        && other_piece.color != piece.color
+    if board
+        .get_piece(&diagonal_left)
+        .is_some_and(|other_piece| other_piece.color != piece.color)
+        || en_passant == Some(diagonal_left)
     {
         moves.push(diagonal_left);
     }
 
     let diagonal_right = diagonal_take_right(&piece.position).into();
-    if let Some(other_piece) = board.get_piece(&diagonal_right)
-        && other_piece.color != piece.color
+    if board
+        .get_piece(&diagonal_right)
+        .is_some_and(|other_piece| other_piece.color != piece.color)
+        || en_passant == Some(diagonal_right)
     {
         moves.push(diagonal_right);
     }
@@ -201,34 +286,24 @@ fn pawn_moves(piece: &Piece, board: &Board) -> Vec<Position> {
     moves
 }
 
+// the squares a pawn attacks, whether or not anything is standing on them,
+// so a king can't walk or castle onto a square a pawn covers
 fn pawn_attacks(piece: &Piece, board: &Board) -> Vec<Position> {
-    let diagonal_take_left = match piece.color {
-        Color::Black => |position: &Position| (position.column + 1, position.row - 1),
-        Color::White => |position: &Position| (position.column + 1, position.row + 1),
-    };
-
-    let diagonal_take_right = match piece.color {
-        Color::Black => |position: &Position| (position.column - 1, position.row - 1),
-        Color::White => |position: &Position| (position.column - 1, position.row + 1),
+    let forward = match piece.color {
+        Color::Black => -1,
+        Color::White => 1,
     };
 
-    let mut moves = vec![];
-
-    let diagonal_left = diagonal_take_left(&piece.position).into();
-    if let Some(other_piece) = board.get_piece(&diagonal_left)
-        && other_piece.color != piece.color
-    {
-        moves.push(diagonal_left);
-    }
-
-    let diagonal_right = diagonal_take_right(&piece.position).into();
-    if let Some(other_piece) = board.get_piece(&diagonal_right)
-        && other_piece.color != piece.color
-    {
-        moves.push(diagonal_right);
-    }
-
-    moves
+    [-1, 1]
+        .into_iter()
+        .map(|side| Position::new(piece.position.column + side, piece.position.row + forward))
+        .filter(|position| position.is_on_board())
+        .filter(|position| {
+            board
+                .get_piece(position)
+                .is_none_or(|other_piece| other_piece.color != piece.color)
+        })
+        .collect()
 }
 
 fn bishop_moves(piece: &Piece, board: &Board) -> Vec<Position> {
@@ -248,8 +323,6 @@ fn bishop_moves(piece: &Piece, board: &Board) -> Vec<Position> {
     moves
 }
 
-// TODO
-// - castling
 fn rook_moves(piece: &Piece, board: &Board) -> Vec<Position> {
     let mut moves = vec![];
 
@@ -300,8 +373,7 @@ fn king_attacks(piece: &Piece, board: &Board) -> Vec<Position> {
         .collect()
 }
 
-// TODO
-// - castling
+// castling is up to the board, which knows about castling rights
 fn king_moves(piece: &Piece, board: &Board) -> Vec<Position> {
     let same_color_piece_positions: HashSet<_> = board
         .get_pieces(piece.color)
@@ -309,7 +381,13 @@ fn king_moves(piece: &Piece, board: &Board) -> Vec<Position> {
         .map(|piece| piece.position)
         .collect();
 
-    let all_enemy_attacks = board.all_attacks(piece.color.invert());
+    // the king can't walk into check, but where check works differently that's up to the board:
+    // Antichess has no check, and in Atomic a king next to the other king can't be taken
+    let all_enemy_attacks = if board.variant.has_royal_king() && !board.variant.explodes() {
+        board.all_attacks(piece.color.invert())
+    } else {
+        HashSet::new()
+    };
 
     [-1, 0, 1]
         .into_iter()
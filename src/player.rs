@@ -0,0 +1,229 @@
+use crate::bot;
+use crate::rating::{self, Category};
+use crate::{AppError, AppState, layout};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::http::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Form};
+use maud::{Markup, html};
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use uuid::fmt::Hyphenated;
+
+const COOKIE_NAME: &str = "chez_player";
+
+/// whoever is on the other end of the request.
+/// a player is just a random id that lives in a cookie,
+/// until they register a name on the account page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PlayerId(pub Uuid);
+
+/// something that happened to a player rather than to one game,
+/// sent to whoever is listening for them, like bots
+#[derive(Clone, Copy, Debug)]
+pub enum PlayerEvent {
+    /// someone challenged them to a game
+    Challenge(Uuid),
+    /// a game they are playing in started
+    GameStart(Uuid),
+}
+
+/// make sure every request has a `PlayerId` extension,
+/// handing out a new id to anyone who doesn't have one yet.
+/// bots send an API token instead of a cookie.
+pub async fn ensure_player(
+    State(state): State<Arc<Mutex<AppState>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    if let Some(token) = token {
+        let pool = state.lock().await.pool.clone();
+
+        return match bot::token_player(&pool, &token).await {
+            Ok(Some(player)) => {
+                request.extensions_mut().insert(player);
+                next.run(request).await
+            }
+            Ok(None) => (StatusCode::UNAUTHORIZED, "bad API token").into_response(),
+            Err(e) => AppError(e).into_response(),
+        };
+    }
+
+    let existing = request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .and_then(|(_, value)| Uuid::parse_str(value).ok());
+
+    let player_id = existing.unwrap_or_else(Uuid::new_v4);
+
+    request.extensions_mut().insert(PlayerId(player_id));
+
+    let mut response = next.run(request).await;
+
+    if existing.is_none() {
+        response.headers_mut().append(
+            SET_COOKIE,
+            format!("{COOKIE_NAME}={player_id}; Path=/; Max-Age=31536000; HttpOnly; SameSite=Lax")
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    response
+}
+
+/// the name `player` registered with, if they did
+pub async fn name(conn: &mut SqliteConnection, player: PlayerId) -> anyhow::Result<Option<String>> {
+    let name: Option<(String,)> = sqlx::query_as("select name from users where id = ?;")
+        .bind(player.0.hyphenated())
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    Ok(name.map(|(name,)| name))
+}
+
+/// the player who registered `name`
+pub async fn by_name(conn: &mut SqliteConnection, name: &str) -> anyhow::Result<Option<PlayerId>> {
+    let id: Option<(Hyphenated,)> = sqlx::query_as("select id from users where name = ?;")
+        .bind(name.trim())
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    Ok(id.map(|(id,)| PlayerId(id.into_uuid())))
+}
+
+/// names are 3 to 20 letters, digits, - or _
+pub fn valid_name(name: &str) -> bool {
+    (3..=20).contains(&name.chars().count())
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+pub async fn account(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<impl IntoResponse, AppError> {
+    let state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    account_page(&mut conn, player, None).await
+}
+
+#[derive(Deserialize)]
+pub struct AccountParams {
+    name: String,
+}
+
+/// register a name, or change it
+pub async fn account_update(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Extension(player): Extension<PlayerId>,
+    Form(params): Form<AccountParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let name = params.name.trim();
+
+    if !valid_name(name) {
+        return account_page(
+            &mut conn,
+            player,
+            Some("Names are 3 to 20 letters, digits, - or _"),
+        )
+        .await;
+    }
+
+    let registered = sqlx::query(
+        "
+    insert into users (id, name) values (?, ?)
+    on conflict (id) do update set
+        name = excluded.name,
+        updated_at = CURRENT_TIMESTAMP;
+    ",
+    )
+    .bind(player.0.hyphenated())
+    .bind(name)
+    .execute(&mut *conn)
+    .await;
+
+    match registered {
+        Ok(_) => account_page(&mut conn, player, None).await,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            account_page(&mut conn, player, Some("That name is taken")).await
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn account_page(
+    conn: &mut SqliteConnection,
+    player: PlayerId,
+    error: Option<&str>,
+) -> Result<Markup, AppError> {
+    let name = name(conn, player).await?;
+
+    let mut ratings = vec![];
+
+    for category in Category::ALL {
+        if let Some(rating) = rating::rating(conn, player, category).await? {
+            ratings.push((category, rating));
+        }
+    }
+
+    Ok(layout! {
+        html! {
+            div class="max-w-3xl mx-auto p-4" {
+                div class="flex justify-between items-center mb-4" {
+                    h1 class="text-2xl" { "Account" }
+                    a href="/games" class="underline" { "Games" }
+                }
+                @if name.is_none() {
+                    p class="mb-4" { "Pick a name to play rated games." }
+                }
+                form method="post" action="/account" class="mb-4" {
+                    input
+                        type="text"
+                        name="name"
+                        placeholder="name"
+                        value=(name.as_deref().unwrap_or(""));
+                    button { @if name.is_some() { "Rename" } @else { "Register" } }
+                    @if let Some(error) = error {
+                        p class="text-red-600" { (error) }
+                    }
+                }
+                @if !ratings.is_empty() {
+                    h2 class="text-xl" { "Ratings" }
+                    table {
+                        @for (category, rating) in &ratings {
+                            tr {
+                                td class="pr-4" { (category.as_str()) }
+                                td { (rating.label()) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,378 @@
+use crate::clock::Clock;
+use crate::game::Outcome;
+use crate::piece::Color;
+use crate::player::PlayerId;
+use sqlx::SqliteConnection;
+use std::f64::consts::PI;
+use uuid::Uuid;
+
+/// converts between the glicko and glicko-2 scales
+const SCALE: f64 = 173.7178;
+
+/// how much the volatility is allowed to change, 0.3 to 1.2 is reasonable
+const TAU: f64 = 0.5;
+
+const CONVERGENCE_TOLERANCE: f64 = 0.000001;
+
+/// ratings are kept apart by how fast the game is,
+/// estimated from a game of 40 moves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+    /// untimed games
+    Correspondence,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Bullet,
+        Category::Blitz,
+        Category::Rapid,
+        Category::Classical,
+        Category::Correspondence,
+    ];
+
+    pub fn of(clock: Option<&Clock>) -> Self {
+        let Some(clock) = clock else {
+            return Category::Correspondence;
+        };
+
+        let estimated = clock.initial.as_secs() + 40 * clock.increment.as_secs();
+
+        TIMED
+            .into_iter()
+            .find(|(shorter_than, _)| estimated < *shorter_than)
+            .map_or(Category::Classical, |(_, category)| category)
+    }
+
+    /// `Category::of` in SQL, for the time control stored in `column`,
+    /// so ratings can be joined into a query about games
+    pub fn sql_of(column: &str) -> String {
+        let estimated = format!(
+            "(cast(substr({column}, 1, instr({column}, '+') - 1) as integer)
+            + 40 * cast(substr({column}, instr({column}, '+') + 1) as integer))"
+        );
+
+        let mut sql = format!(
+            "case when {column} is null then '{}'",
+            Category::Correspondence.as_str()
+        );
+
+        for (shorter_than, category) in TIMED {
+            sql.push_str(&format!(
+                " when {estimated} < {shorter_than} then '{}'",
+                category.as_str()
+            ));
+        }
+
+        sql.push_str(&format!(" else '{}' end", Category::Classical.as_str()));
+
+        sql
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Bullet => "bullet",
+            Category::Blitz => "blitz",
+            Category::Rapid => "rapid",
+            Category::Classical => "classical",
+            Category::Correspondence => "correspondence",
+        }
+    }
+}
+
+/// the timed categories but classical, by how many seconds
+/// a game of 40 moves has to be estimated to be shorter than
+const TIMED: [(u64, Category); 3] = [
+    (180, Category::Bullet),
+    (480, Category::Blitz),
+    (1500, Category::Rapid),
+];
+
+/// a glicko-2 rating, on the glicko scale
+#[derive(Clone, Copy, Debug, PartialEq, sqlx::FromRow)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: 1500.0,
+            deviation: 350.0,
+            volatility: 0.06,
+        }
+    }
+}
+
+impl Rating {
+    /// the rating after a single game against `opponent`,
+    /// treating the game as its own rating period.
+    /// `score` is 1 for a win, 0.5 for a draw and 0 for a loss.
+    pub fn update(&self, opponent: &Rating, score: f64) -> Rating {
+        let mu = (self.rating - 1500.0) / SCALE;
+        let phi = self.deviation / SCALE;
+        let sigma = self.volatility;
+
+        let opponent_mu = (opponent.rating - 1500.0) / SCALE;
+        let opponent_phi = opponent.deviation / SCALE;
+
+        let g = 1.0 / (1.0 + 3.0 * opponent_phi.powi(2) / PI.powi(2)).sqrt();
+        let expected = 1.0 / (1.0 + (-g * (mu - opponent_mu)).exp());
+
+        let variance = 1.0 / (g.powi(2) * expected * (1.0 - expected));
+        let delta = variance * g * (score - expected);
+
+        let sigma = new_volatility(phi, sigma, variance, delta);
+
+        let phi_star = (phi.powi(2) + sigma.powi(2)).sqrt();
+        let phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / variance).sqrt();
+        let mu = mu + phi.powi(2) * g * (score - expected);
+
+        Rating {
+            rating: mu * SCALE + 1500.0,
+            deviation: (phi * SCALE).clamp(30.0, 350.0),
+            volatility: sigma,
+        }
+    }
+
+    /// not enough games yet to trust the rating
+    pub fn is_provisional(&self) -> bool {
+        self.deviation > 110.0
+    }
+
+    pub fn label(&self) -> String {
+        if self.is_provisional() {
+            format!("{:.0}?", self.rating)
+        } else {
+            format!("{:.0}", self.rating)
+        }
+    }
+}
+
+/// step 5 of the glicko-2 paper, using the illinois algorithm
+fn new_volatility(phi: f64, sigma: f64, variance: f64, delta: f64) -> f64 {
+    let a = (sigma.powi(2)).ln();
+
+    let f = |x: f64| {
+        let ex = x.exp();
+
+        (ex * (delta.powi(2) - phi.powi(2) - variance - ex))
+            / (2.0 * (phi.powi(2) + variance + ex).powi(2))
+            - (x - a) / TAU.powi(2)
+    };
+
+    let mut big_a = a;
+
+    let mut big_b = if delta.powi(2) > phi.powi(2) + variance {
+        (delta.powi(2) - phi.powi(2) - variance).ln()
+    } else {
+        let mut k = 1.0;
+
+        while f(a - k * TAU) < 0.0 {
+            k += 1.0;
+        }
+
+        a - k * TAU
+    };
+
+    let mut f_a = f(big_a);
+    let mut f_b = f(big_b);
+
+    while (big_b - big_a).abs() > CONVERGENCE_TOLERANCE {
+        let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+        let f_c = f(big_c);
+
+        if f_c * f_b <= 0.0 {
+            big_a = big_b;
+            f_a = f_b;
+        } else {
+            f_a /= 2.0;
+        }
+
+        big_b = big_c;
+        f_b = f_c;
+    }
+
+    (big_a / 2.0).exp()
+}
+
+/// a user's current rating in `category`,
+/// or `None` if they haven't played a rated game in it yet
+pub async fn rating(
+    conn: &mut SqliteConnection,
+    player: PlayerId,
+    category: Category,
+) -> anyhow::Result<Option<Rating>> {
+    let rating = sqlx::query_as(
+        "
+    select rating, deviation, volatility
+    from ratings
+    where user_id = ? and category = ?;
+    ",
+    )
+    .bind(player.0.hyphenated())
+    .bind(category.as_str())
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(rating)
+}
+
+/// update both players' ratings after a rated game,
+/// and keep the new ratings in their history
+pub async fn record_game(
+    conn: &mut SqliteConnection,
+    game_id: Uuid,
+    category: Category,
+    white: PlayerId,
+    black: PlayerId,
+    outcome: Outcome,
+) -> anyhow::Result<()> {
+    let white_rating = rating(conn, white, category).await?.unwrap_or_default();
+    let black_rating = rating(conn, black, category).await?.unwrap_or_default();
+
+    let white_score = match outcome {
+        Outcome::Win(Color::White) => 1.0,
+        Outcome::Win(Color::Black) => 0.0,
+        Outcome::Draw => 0.5,
+    };
+
+    for (player, new_rating) in [
+        (white, white_rating.update(&black_rating, white_score)),
+        (black, black_rating.update(&white_rating, 1.0 - white_score)),
+    ] {
+        sqlx::query(
+            "
+    insert into ratings (user_id, category, rating, deviation, volatility, games)
+    values (?, ?, ?, ?, ?, 1)
+    on conflict (user_id, category) do update set
+        rating = excluded.rating,
+        deviation = excluded.deviation,
+        volatility = excluded.volatility,
+        games = games + 1,
+        updated_at = CURRENT_TIMESTAMP;
+    ",
+        )
+        .bind(player.0.hyphenated())
+        .bind(category.as_str())
+        .bind(new_rating.rating)
+        .bind(new_rating.deviation)
+        .bind(new_rating.volatility)
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(
+            "
+    insert into rating_history (user_id, category, game_id, rating, deviation, volatility)
+    values (?, ?, ?, ?, ?, ?);
+    ",
+        )
+        .bind(player.0.hyphenated())
+        .bind(category.as_str())
+        .bind(game_id)
+        .bind(new_rating.rating)
+        .bind(new_rating.deviation)
+        .bind(new_rating.volatility)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Connection;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating,
+            deviation,
+            volatility: 0.06,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{actual} isn't {expected}"
+        );
+    }
+
+    #[test]
+    fn new_players_move_as_glicko_2_says() {
+        let winner = Rating::default().update(&Rating::default(), 1.0);
+        let loser = Rating::default().update(&Rating::default(), 0.0);
+
+        assert_close(winner.rating, 1662.31);
+        assert_close(loser.rating, 1337.69);
+
+        for rating in [winner, loser] {
+            assert_close(rating.deviation, 290.32);
+            assert_close(rating.volatility, 0.06);
+        }
+    }
+
+    #[test]
+    fn beating_a_settled_weaker_player_gains_less() {
+        let after = rating(1500.0, 200.0).update(&rating(1400.0, 30.0), 1.0);
+
+        assert_close(after.rating, 1563.56);
+        assert_close(after.deviation, 175.40);
+    }
+
+    #[test]
+    fn a_draw_between_equals_only_makes_them_surer() {
+        let after = Rating::default().update(&Rating::default(), 0.5);
+
+        assert_close(after.rating, 1500.0);
+        assert!(after.deviation < 350.0);
+    }
+
+    #[test]
+    fn deviation_never_goes_below_the_floor() {
+        let settled = Rating {
+            rating: 1500.0,
+            deviation: 30.0,
+            volatility: 0.0001,
+        };
+
+        let after = settled.update(&rating(1500.0, 30.0), 0.5);
+
+        assert_eq!(after.deviation, 30.0);
+        assert!(!after.is_provisional());
+        assert_eq!(after.label(), "1500");
+        assert_eq!(Rating::default().label(), "1500?");
+    }
+
+    #[tokio::test]
+    async fn categories_in_sql_match_categories_in_rust() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+
+        let time_controls = [
+            "60+0", "120+1", "180+0", "180+2", "300+3", "600+0", "900+10", "1500+0", "1800+0",
+        ];
+
+        for time_control in time_controls.map(Some).into_iter().chain([None]) {
+            let (category,): (String,) = sqlx::query_as(&format!(
+                "with games(time_control) as (select ?) select {} from games;",
+                Category::sql_of("time_control")
+            ))
+            .bind(time_control)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+
+            let expected = Category::of(time_control.and_then(Clock::parse).as_ref());
+
+            assert_eq!(category, expected.as_str(), "{time_control:?}");
+        }
+    }
+}
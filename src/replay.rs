@@ -0,0 +1,290 @@
+use crate::analysis;
+use crate::annotation::{self, Nag};
+use crate::pgn::{self, Pgn};
+use crate::piece::Color;
+use crate::player::PlayerId;
+use crate::review::{self, Judgement, Review, Status};
+use crate::{AppError, AppState, SquareColor, board_grid, layout, read_only_square};
+use axum::Extension;
+use axum::extract::{Path, Query, State};
+use maud::{Markup, PreEscaped, html};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ReplayParams {
+    /// how many half-moves into the game to show, the start of the game if missing
+    ply: Option<usize>,
+}
+
+/// step through a game one half-move at a time,
+/// from its first position to where it is now
+/// how a game's moves are numbered, which games set up in the editor
+/// can start with black to move, or later than move 1
+struct Numbering {
+    first: u32,
+    first_mover: Color,
+}
+
+impl Numbering {
+    fn new(starting_fen: &str) -> Self {
+        let (first, first_mover) = pgn::move_number(starting_fen);
+
+        Self { first, first_mover }
+    }
+
+    /// the number of the `i`th move played, from 0
+    fn of_move(&self, i: usize) -> u32 {
+        match self.first_mover {
+            Color::White => self.first + (i / 2) as u32,
+            Color::Black => self.first + i.div_ceil(2) as u32,
+        }
+    }
+
+    /// the number of the move that led to a position, the one before the first move at the start
+    fn at(&self, ply: usize) -> u32 {
+        match ply.checked_sub(1) {
+            Some(i) => self.of_move(i),
+            None => self.first.saturating_sub(1),
+        }
+    }
+}
+
+pub async fn replay(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+    Query(params): Query<ReplayParams>,
+    Extension(player): Extension<PlayerId>,
+) -> Result<Markup, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let annotations = annotation::load(&mut conn, game_id).await?;
+
+    let game_state = state.game(game_id).await?;
+
+    // every position of the game, the starting one first
+    let mut positions = vec![analysis::replay(
+        game_state.variant,
+        &game_state.starting_fen,
+        &[],
+    )?];
+
+    for m in &game_state.moves {
+        let (mut board, to_move) = positions[positions.len() - 1].clone();
+        board.make_move(m);
+        positions.push((board, to_move.invert()));
+    }
+
+    let finished = game_state.result.is_some();
+
+    let review = if finished {
+        Review::load(&mut conn, game_id, positions[0].1, game_state.moves.len()).await?
+    } else {
+        None
+    };
+
+    // games that ended before there were reviews get theirs once someone looks
+    let review_status = if finished && review.is_none() {
+        review::request(&mut conn, &state.reviews, game_id).await?;
+
+        review::status(&mut conn, game_id).await?
+    } else {
+        None
+    };
+
+    let game_state = state.game(game_id).await?;
+
+    let can_annotate = annotation::can_annotate(game_state, player);
+
+    let moves = &game_state.moves;
+
+    let ply = params.ply.unwrap_or(0).min(moves.len());
+
+    let (board, to_move) = &positions[ply];
+
+    let san = Pgn::of(game_state).moves;
+
+    let last_move = ply.checked_sub(1).map(|i| moves[i]);
+
+    let orientation = game_state.seat(player).unwrap_or(Color::White);
+
+    let href = |ply: usize| format!("/games/{game_id}/replay?ply={ply}");
+
+    let numbering = Numbering::new(&game_state.starting_fen);
+
+    // the buttons along the bottom, each with the key that presses it
+    let steps = [
+        ("First", "Home", 0, ply > 0),
+        ("Previous", "ArrowLeft", ply.saturating_sub(1), ply > 0),
+        ("Next", "ArrowRight", ply + 1, ply < moves.len()),
+        ("Last", "End", moves.len(), ply < moves.len()),
+    ];
+
+    Ok(layout! {
+        html! {
+            div class="p-4 flex justify-between" {
+                a href=(format!("/games/{game_id}/play")) class="underline" { "Back to the game" }
+                span { "Move " (numbering.at(ply)) " of " (numbering.at(moves.len())) }
+            }
+            (board_grid(board, orientation, |position, color, body| {
+                let played = last_move.is_some_and(|m| m.from == *position || m.to == *position);
+                read_only_square(position, if played { SquareColor::Highlighted } else { color }, body)
+            }))
+            div class="p-4 flex gap-4" {
+                @for (label, key, step, enabled) in steps {
+                    @if enabled {
+                        a href=(href(step)) data-replay-key=(key) class="underline" { (label) }
+                    } @else {
+                        span class="text-gray-400" { (label) }
+                    }
+                }
+                span { (match to_move {
+                    Color::White => "white to move",
+                    Color::Black => "black to move",
+                }) }
+            }
+            @if let Some(review) = &review {
+                div class="p-4 flex flex-col gap-2" {
+                    @if let Some(evaluation) = review.evaluation(ply) {
+                        span { "Evaluation " (evaluation.label()) }
+                    }
+                    (review.graph(href, ply))
+                    @for color in [Color::White, Color::Black] {
+                        span {
+                            (match color {
+                                Color::White => "White",
+                                Color::Black => "Black",
+                            })
+                            @if let Some(accuracy) = review.accuracy(color) {
+                                ": " (format!("{accuracy:.0}")) "% accuracy"
+                            }
+                            @for judgement in [Judgement::Inaccuracy, Judgement::Mistake, Judgement::Blunder] {
+                                " · " (review.count(color, judgement)) " " (judgement.label().to_lowercase())
+                                @if review.count(color, judgement) != 1 { "s" }
+                            }
+                        }
+                    }
+                }
+            } @else if review_status == Some(Status::Failed) {
+                div class="p-4" { "The engine couldn't go over this game." }
+            } @else if finished {
+                div
+                    class="p-4"
+                    hx-get=(format!("/games/{game_id}/review"))
+                    hx-trigger="every 2s"
+                    hx-swap="none"
+                {
+                    "The engine is going over the game…"
+                }
+            }
+            div class="p-4 flex flex-wrap gap-x-2" {
+                @for (i, m) in san.iter().enumerate() {
+                    @if positions[i].1 == Color::White {
+                        span { (numbering.of_move(i)) "." }
+                    } @else if i == 0 {
+                        span { (numbering.of_move(i)) "..." }
+                    }
+                    @let annotation = annotations.get(&(i as i64 + 1));
+                    a
+                        href=(href(i + 1))
+                        class=(if i + 1 == ply { "px-1 bg-yellow-200" } else { "px-1 underline" })
+                    {
+                        (m)
+                        @if let Some(nag) = annotation.and_then(|annotation| annotation.nag) {
+                            span title=(nag.label()) { (nag.as_str()) }
+                        }
+                    }
+                    @if let Some(review) = &review {
+                        @let (before, mover) = &positions[i];
+                        (review.mark(before, *mover, i + 1))
+                    }
+                    @if let Some(comment) = annotation.and_then(|annotation| annotation.comment.as_ref()) {
+                        span class="text-sm text-gray-600" { (comment) }
+                    }
+                }
+            }
+            @if can_annotate && ply > 0 {
+                @let annotation = annotations.get(&(ply as i64)).cloned().unwrap_or_default();
+                form
+                    hx-post=(format!("/games/{game_id}/annotations"))
+                    hx-swap="none"
+                    class="p-4 flex flex-col gap-2"
+                {
+                    input type="hidden" name="ply" value=(ply);
+                    div class="flex gap-2" {
+                        "Annotate " (san[ply - 1]) ":"
+                        select name="nag" {
+                            option value="" { "No glyph" }
+                            @for nag in Nag::ALL {
+                                option value=(nag.as_str()) selected[annotation.nag == Some(nag)] {
+                                    (nag.as_str()) " " (nag.label())
+                                }
+                            }
+                        }
+                    }
+                    textarea name="comment" rows="3" placeholder="A comment on this move" {
+                        (annotation.comment.unwrap_or_default())
+                    }
+                    div {
+                        button class="underline" { "Save" }
+                    }
+                }
+            }
+            // the arrow keys, Home and End press the buttons of the same name,
+            // unless they are moving around in the annotation being written
+            script {
+                (PreEscaped("
+                document.addEventListener('keydown', (event) => {
+                    if (event.target.closest('input, textarea, select')) {
+                        return;
+                    }
+                    const step = document.querySelector(`[data-replay-key='${event.key}']`);
+                    if (step) {
+                        event.preventDefault();
+                        window.location = step.href;
+                    }
+                });
+                "))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::STARTING_FEN;
+
+    #[test]
+    fn moves_are_numbered_from_the_start() {
+        let numbering = Numbering::new(STARTING_FEN);
+
+        assert_eq!(
+            (0..5).map(|i| numbering.of_move(i)).collect::<Vec<_>>(),
+            [1, 1, 2, 2, 3]
+        );
+        assert_eq!(
+            (0..4).map(|ply| numbering.at(ply)).collect::<Vec<_>>(),
+            [0, 1, 1, 2]
+        );
+    }
+
+    #[test]
+    fn set_up_positions_keep_their_own_numbers() {
+        // black to move on move 20
+        let numbering = Numbering::new("4k3/8/8/8/8/8/8/4K3 b - - 0 20");
+
+        assert_eq!(
+            (0..4).map(|i| numbering.of_move(i)).collect::<Vec<_>>(),
+            [20, 21, 21, 22]
+        );
+        assert_eq!(
+            (0..4).map(|ply| numbering.at(ply)).collect::<Vec<_>>(),
+            [19, 20, 21, 21]
+        );
+    }
+}
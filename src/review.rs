@@ -0,0 +1,746 @@
+use crate::analysis;
+use crate::board::{Board, Move};
+use crate::engine;
+use crate::pgn;
+use crate::piece::Color;
+use crate::uci::{self, Limit, Score};
+use crate::variant::Variant;
+use crate::{AppError, AppState};
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use maud::{Markup, html};
+use sqlx::SqliteConnection;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// how long either engine looks at each position
+const MOVE_TIME: Duration = Duration::from_millis(250);
+
+/// how deep the built-in engine goes at most, if it has the time
+const MAX_DEPTH: u8 = 8;
+
+/// what a forced mate is worth, so it can be compared with everything else
+const MATE_CENTIPAWNS: i32 = 10_000;
+
+/// what the engine made of a position, from white's point of view
+#[derive(Clone, Copy, Debug)]
+pub struct Evaluation {
+    pub centipawns: i32,
+    /// moves to a forced mate, negative when black mates, 0 when the game is over
+    pub mate: Option<i32>,
+    /// `None` once there are no moves left
+    pub best_move: Option<Move>,
+}
+
+impl Evaluation {
+    /// e.g. "+0.35", "#-2", or the result once the game is over
+    pub fn label(&self) -> String {
+        match self.mate {
+            Some(0) if self.centipawns > 0 => "1-0".to_string(),
+            Some(0) => "0-1".to_string(),
+            Some(moves) => Score::Mate(moves).label(Color::White),
+            None => Score::Centipawns(self.centipawns).label(Color::White),
+        }
+    }
+
+    /// how likely `color` is to win from here, from 0 to 100,
+    /// going by how these centipawns usually turn out
+    pub fn win_percent(&self, color: Color) -> f64 {
+        let centipawns = match color {
+            Color::White => self.centipawns,
+            Color::Black => -self.centipawns,
+        };
+
+        50.0 + 50.0 * (2.0 / (1.0 + (-0.00368208 * centipawns as f64).exp()) - 1.0)
+    }
+}
+
+/// how bad a move was, going by how much it threw away of the mover's chances to win
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Judgement {
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl Judgement {
+    /// for a move that took the mover's chances to win from `before` to `after`, out of 100
+    fn of(before: f64, after: f64) -> Option<Self> {
+        match before - after {
+            lost if lost >= 15.0 => Some(Judgement::Blunder),
+            lost if lost >= 10.0 => Some(Judgement::Mistake),
+            lost if lost >= 5.0 => Some(Judgement::Inaccuracy),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Judgement::Inaccuracy => "Inaccuracy",
+            Judgement::Mistake => "Mistake",
+            Judgement::Blunder => "Blunder",
+        }
+    }
+
+    /// the glyph PGN would use for a move like this
+    pub fn glyph(&self) -> &'static str {
+        match self {
+            Judgement::Inaccuracy => "?!",
+            Judgement::Mistake => "?",
+            Judgement::Blunder => "??",
+        }
+    }
+
+    fn text_color(&self) -> &'static str {
+        match self {
+            Judgement::Inaccuracy => "text-yellow-600",
+            Judgement::Mistake => "text-orange-600",
+            Judgement::Blunder => "text-red-600",
+        }
+    }
+}
+
+/// what the engine made of a finished game, position by position
+pub struct Review {
+    /// by ply, the starting position first
+    evaluations: Vec<Evaluation>,
+    /// who made the first move
+    first_mover: Color,
+}
+
+#[derive(sqlx::FromRow)]
+struct EvaluationRow {
+    centipawns: i32,
+    mate: Option<i32>,
+    best_move: Option<String>,
+}
+
+impl Review {
+    /// the review of a game `plies` long, `None` if it hasn't been reviewed yet
+    pub async fn load(
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        first_mover: Color,
+        plies: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        let rows: Vec<EvaluationRow> = sqlx::query_as(
+            "
+    select centipawns, mate, best_move
+    from evaluations
+    where game_id = ?
+    order by ply asc;
+    ",
+        )
+        .bind(game_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        if rows.len() != plies + 1 {
+            return Ok(None);
+        }
+
+        let evaluations = rows
+            .into_iter()
+            .map(|row| Evaluation {
+                centipawns: row.centipawns,
+                mate: row.mate,
+                best_move: row.best_move.as_deref().and_then(Move::parse_uci),
+            })
+            .collect();
+
+        Ok(Some(Self {
+            evaluations,
+            first_mover,
+        }))
+    }
+
+    /// the evaluation of the position after `ply` half-moves
+    pub fn evaluation(&self, ply: usize) -> Option<&Evaluation> {
+        self.evaluations.get(ply)
+    }
+
+    /// whoever made the move at `ply`, counting from 1
+    fn mover(&self, ply: usize) -> Color {
+        if ply % 2 == 1 {
+            self.first_mover
+        } else {
+            self.first_mover.invert()
+        }
+    }
+
+    /// the mover's chances to win before and after the move at `ply`
+    fn chances(&self, ply: usize) -> Option<(f64, f64)> {
+        let mover = self.mover(ply);
+
+        let before = self.evaluations.get(ply.checked_sub(1)?)?;
+        let after = self.evaluations.get(ply)?;
+
+        Some((before.win_percent(mover), after.win_percent(mover)))
+    }
+
+    /// how bad the move at `ply` was, `None` if it was fine
+    pub fn judgement(&self, ply: usize) -> Option<Judgement> {
+        let (before, after) = self.chances(ply)?;
+
+        Judgement::of(before, after)
+    }
+
+    /// how close `color`'s moves came to the engine's, from 0 to 100,
+    /// with each move scored by how much of their chances to win it kept.
+    /// `None` if they made no moves.
+    pub fn accuracy(&self, color: Color) -> Option<f64> {
+        let accuracies: Vec<f64> = (1..self.evaluations.len())
+            .filter(|ply| self.mover(*ply) == color)
+            .filter_map(|ply| self.chances(ply))
+            .map(|(before, after)| {
+                let lost = (before - after).max(0.0);
+
+                (103.1668 * (-0.04354 * lost).exp() - 3.1669).clamp(0.0, 100.0)
+            })
+            .collect();
+
+        (!accuracies.is_empty()).then(|| accuracies.iter().sum::<f64>() / accuracies.len() as f64)
+    }
+
+    /// how many moves of `color`'s got `judgement`
+    pub fn count(&self, color: Color, judgement: Judgement) -> usize {
+        (1..self.evaluations.len())
+            .filter(|ply| self.mover(*ply) == color && self.judgement(*ply) == Some(judgement))
+            .count()
+    }
+
+    /// white's chances to win over the game, with `ply` marked.
+    /// clicking anywhere goes to the move there.
+    pub fn graph(&self, href: impl Fn(usize) -> String, ply: usize) -> Markup {
+        let width = (self.evaluations.len() - 1).max(1);
+
+        let points: Vec<String> = self
+            .evaluations
+            .iter()
+            .enumerate()
+            .map(|(i, evaluation)| {
+                format!("{i},{:.1}", 100.0 - evaluation.win_percent(Color::White))
+            })
+            .collect();
+
+        let area = format!("0,100 {} {width},100", points.join(" "));
+
+        html! {
+            svg
+                viewBox=(format!("0 0 {width} 100"))
+                preserveAspectRatio="none"
+                class="w-full h-24 bg-gray-700"
+            {
+                polygon points=(area) fill="#f9fafb" {}
+                line x1="0" y1="50" x2=(width) y2="50" stroke="#9ca3af" vector-effect="non-scaling-stroke" {}
+                line x1=(ply) y1="0" x2=(ply) y2="100" stroke="#f472b6" stroke-width="2" vector-effect="non-scaling-stroke" {}
+                @for i in 0..self.evaluations.len() {
+                    a href=(href(i)) {
+                        rect x=(i as f64 - 0.5) y="0" width="1" height="100" fill="transparent" {
+                            title { (self.evaluations[i].label()) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// the mark the move at `ply` gets in the move list, if it was a bad one,
+    /// saying what the engine would have played instead
+    pub fn mark(&self, board: &Board, to_move: Color, ply: usize) -> Markup {
+        let Some(judgement) = self.judgement(ply) else {
+            return html! {};
+        };
+
+        let best = self
+            .evaluations
+            .get(ply - 1)
+            .and_then(|before| before.best_move)
+            .map(|best| format!(", {} was best", pgn::san(board, to_move, &best)))
+            .unwrap_or_default();
+
+        html! {
+            span class=(judgement.text_color()) title=(format!("{}{best}", judgement.label())) {
+                (judgement.glyph())
+            }
+        }
+    }
+}
+
+/// where a game's review is up to, before it has any evaluations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// waiting for the engine, or being gone over by it
+    Pending,
+    /// the engine couldn't go over it, which isn't tried again on its own
+    Failed,
+}
+
+impl Status {
+    /// the status as it is stored in the db
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(Status::Pending),
+            "failed" => Some(Status::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// where `game_id`'s review is up to, `None` if it was never asked for or is done
+pub async fn status(conn: &mut SqliteConnection, game_id: Uuid) -> anyhow::Result<Option<Status>> {
+    let status: Option<(String,)> = sqlx::query_as("select status from reviews where game_id = ?;")
+        .bind(game_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    Ok(status.and_then(|(status,)| Status::parse(&status)))
+}
+
+async fn set_status(
+    conn: &mut SqliteConnection,
+    game_id: Uuid,
+    status: Status,
+    error: Option<&str>,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "
+    insert into reviews (game_id, status, error)
+    values (?, ?, ?)
+    on conflict (game_id) do update set
+        status = excluded.status,
+        error = excluded.error,
+        updated_at = CURRENT_TIMESTAMP;
+    ",
+    )
+    .bind(game_id)
+    .bind(status.as_str())
+    .bind(error)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// queue a review of a finished game that doesn't have one,
+/// unless it is queued already or failed before
+pub async fn request(
+    conn: &mut SqliteConnection,
+    reviews: &UnboundedSender<Uuid>,
+    game_id: Uuid,
+) -> anyhow::Result<()> {
+    if status(conn, game_id).await?.is_none() {
+        set_status(conn, game_id, Status::Pending, None).await?;
+
+        let _ = reviews.send(game_id);
+    }
+
+    Ok(())
+}
+
+/// queue again the reviews that were waiting when the server last stopped
+pub async fn requeue(
+    conn: &mut SqliteConnection,
+    reviews: &UnboundedSender<Uuid>,
+) -> anyhow::Result<()> {
+    let pending: Vec<(Uuid,)> = sqlx::query_as("select game_id from reviews where status = ?;")
+        .bind(Status::Pending.as_str())
+        .fetch_all(&mut *conn)
+        .await?;
+
+    for (game_id,) in pending {
+        let _ = reviews.send(game_id);
+    }
+
+    Ok(())
+}
+
+/// go over finished games one at a time as they come in,
+/// so that reviews don't hold up the games being played.
+/// a review that fails is marked as failed, so nobody waits on it.
+pub async fn work(state: Arc<Mutex<AppState>>, mut games: UnboundedReceiver<Uuid>) {
+    while let Some(game_id) = games.recv().await {
+        let pool = state.lock().await.pool.clone();
+
+        let reviewed = async {
+            let mut conn = pool.acquire().await?;
+
+            set_status(&mut conn, game_id, Status::Pending, None).await?;
+
+            review(&state, game_id).await
+        }
+        .await;
+
+        let recorded = async {
+            let mut conn = pool.acquire().await?;
+
+            match &reviewed {
+                Ok(()) => {
+                    sqlx::query("delete from reviews where game_id = ?;")
+                        .bind(game_id)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                Err(e) => {
+                    error!("could not review game {game_id}: {e}");
+
+                    set_status(&mut conn, game_id, Status::Failed, Some(&e.to_string())).await?;
+                }
+            }
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = recorded {
+            error!("could not record how the review of game {game_id} went: {e}");
+        }
+    }
+}
+
+/// evaluate every position of a finished game and keep the evaluations,
+/// unless that has been done already
+async fn review(state: &Arc<Mutex<AppState>>, game_id: Uuid) -> anyhow::Result<()> {
+    let (pool, engine, variant, starting_fen, moves) = {
+        let mut state = state.lock().await;
+
+        let pool = state.pool.clone();
+        let engine = state.engine.clone();
+
+        let game_state = state.game(game_id).await?;
+
+        if game_state.result.is_none() {
+            return Ok(());
+        }
+
+        (
+            pool,
+            engine,
+            game_state.variant,
+            game_state.starting_fen.clone(),
+            game_state.moves.clone(),
+        )
+    };
+
+    let mut conn = pool.acquire().await?;
+
+    let (_, first_mover) = analysis::replay(variant, &starting_fen, &[])?;
+
+    if Review::load(&mut conn, game_id, first_mover, moves.len())
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+
+    // one engine for the whole game, rather than one for every position
+    let mut session = match engine {
+        Some(engine) => match uci::Session::start(&engine, uci::GRACE).await {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("the engine failed to start, using the built-in one: {e}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut evaluations = vec![];
+
+    for ply in 0..=moves.len() {
+        evaluations.push(evaluate(&mut session, variant, &starting_fen, &moves[..ply]).await?);
+    }
+
+    if let Some(session) = session {
+        session.quit().await;
+    }
+
+    let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+    // whatever was there was for moves the game no longer has
+    sqlx::query("delete from evaluations where game_id = ?;")
+        .bind(game_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for (ply, evaluation) in evaluations.iter().enumerate() {
+        sqlx::query(
+            "
+    insert into evaluations (game_id, ply, centipawns, mate, best_move)
+    values (?, ?, ?, ?, ?);
+    ",
+        )
+        .bind(game_id)
+        .bind(ply as i64)
+        .bind(evaluation.centipawns)
+        .bind(evaluation.mate)
+        .bind(evaluation.best_move.map(|m| m.uci()))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    debug!(
+        "reviewed game {game_id} in {}ms",
+        started.elapsed().as_millis()
+    );
+
+    Ok(())
+}
+
+/// what the engine makes of the position after `moves`:
+/// the UCI engine if there is one, and the built-in one if not or if it fails.
+/// an engine that fails isn't asked again.
+async fn evaluate(
+    session: &mut Option<uci::Session>,
+    variant: Variant,
+    starting_fen: &str,
+    moves: &[Move],
+) -> anyhow::Result<Evaluation> {
+    let (board, to_move) = analysis::replay(variant, starting_fen, moves)?;
+
+    // engines score from the side to move's point of view
+    let sign = match to_move {
+        Color::White => 1,
+        Color::Black => -1,
+    };
+
+    // positions the game can't go on from need no engine
+    let winner = if let Some((winner, _)) = variant.winner(&board) {
+        Some(Some(winner))
+    } else if board.has_legal_moves(to_move) {
+        None
+    } else if board.is_in_check(to_move) {
+        Some(Some(to_move.invert()))
+    } else if variant.stalemate_wins() {
+        Some(Some(to_move))
+    } else {
+        Some(None)
+    };
+
+    if let Some(winner) = winner {
+        return Ok(Evaluation {
+            centipawns: match winner {
+                Some(Color::White) => MATE_CENTIPAWNS,
+                Some(Color::Black) => -MATE_CENTIPAWNS,
+                None => 0,
+            },
+            mate: winner.map(|_| 0),
+            best_move: None,
+        });
+    }
+
+    let from_score = |score: Score, best_move: Option<Move>| match score {
+        Score::Centipawns(centipawns) => Evaluation {
+            centipawns: sign * centipawns.clamp(-MATE_CENTIPAWNS, MATE_CENTIPAWNS),
+            mate: None,
+            best_move,
+        },
+        Score::Mate(moves) => Evaluation {
+            centipawns: sign * moves.signum() * MATE_CENTIPAWNS,
+            mate: Some(sign * moves),
+            best_move,
+        },
+    };
+
+    if let Some(engine) = session {
+        let analysis = engine
+            .search(
+                uci::Position {
+                    starting_fen,
+                    moves,
+                    variant,
+                },
+                Limit::MoveTime(MOVE_TIME),
+                &[],
+            )
+            .await;
+
+        match analysis {
+            Ok(uci::Analysis {
+                score: Some(score),
+                best_move,
+                ..
+            }) => return Ok(from_score(score, best_move)),
+            Ok(_) => error!("the engine gave no score for a position, using the built-in one"),
+            Err(e) => {
+                error!("the engine failed, using the built-in one from here on: {e}");
+
+                // it could still be answering the search that timed out
+                *session = None;
+            }
+        }
+    }
+
+    let (score, best_move) = tokio::task::spawn_blocking(move || {
+        let deadline = Instant::now() + MOVE_TIME;
+
+        let mut score = Score::Centipawns(0);
+
+        let best = engine::iterative_deepening(
+            &board,
+            to_move,
+            MAX_DEPTH,
+            &|| Instant::now() >= deadline,
+            |depth, found, _| {
+                score = match engine::mate_in(found, depth) {
+                    Some(moves) => Score::Mate(moves),
+                    None => Score::Centipawns(found),
+                };
+            },
+        );
+
+        (score, best)
+    })
+    .await?;
+
+    Ok(from_score(score, best_move))
+}
+
+/// whether a finished game's review is ready, for the replay page to wait on.
+/// it reloads itself once it is, or once the review has failed.
+pub async fn review_ready(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(game_id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut state = state.lock().await;
+
+    let mut conn = state.pool.acquire().await?;
+
+    let game_state = state.game(game_id).await?;
+
+    let (_, first_mover) = analysis::replay(game_state.variant, &game_state.starting_fen, &[])?;
+
+    let review = Review::load(&mut conn, game_id, first_mover, game_state.moves.len()).await?;
+
+    let failed = status(&mut conn, game_id).await? == Some(Status::Failed);
+
+    let mut headers = HeaderMap::new();
+
+    if review.is_some() || failed {
+        headers.insert("HX-Refresh", "true".parse().unwrap());
+    }
+
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::STARTING_FEN;
+    use std::path::Path;
+
+    /// a review of a game white started, from white's centipawns after each ply
+    fn review(centipawns: &[i32]) -> Review {
+        Review {
+            evaluations: centipawns
+                .iter()
+                .map(|&centipawns| Evaluation {
+                    centipawns,
+                    mate: None,
+                    best_move: None,
+                })
+                .collect(),
+            first_mover: Color::White,
+        }
+    }
+
+    #[test]
+    fn moves_are_judged_by_the_chances_they_throw_away() {
+        assert_eq!(Judgement::of(50.0, 46.0), None);
+        assert_eq!(Judgement::of(50.0, 45.0), Some(Judgement::Inaccuracy));
+        assert_eq!(Judgement::of(50.0, 40.0), Some(Judgement::Mistake));
+        assert_eq!(Judgement::of(50.0, 35.0), Some(Judgement::Blunder));
+
+        // white lets a little slip, then black gives it all back and more
+        let swing = review(&[0, -60, 400, 400]);
+
+        assert_eq!(swing.judgement(1), Some(Judgement::Inaccuracy));
+        assert_eq!(swing.judgement(2), Some(Judgement::Blunder));
+        assert_eq!(swing.judgement(3), None);
+        assert_eq!(swing.count(Color::White, Judgement::Inaccuracy), 1);
+        assert_eq!(swing.count(Color::Black, Judgement::Blunder), 1);
+        assert_eq!(swing.count(Color::Black, Judgement::Inaccuracy), 0);
+    }
+
+    #[test]
+    fn accuracy_is_how_much_of_their_chances_each_side_kept() {
+        // white's second move throws the game away, black's are all fine
+        let white_blunders = review(&[0, 0, 0, -500]);
+
+        assert!(white_blunders.accuracy(Color::White).unwrap() < 60.0);
+        assert!(white_blunders.accuracy(Color::Black).unwrap() > 99.9);
+
+        // nobody has moved yet
+        assert_eq!(review(&[0]).accuracy(Color::White), None);
+    }
+
+    #[test]
+    fn finished_games_are_labelled_with_the_result() {
+        let over = |centipawns| Evaluation {
+            centipawns,
+            mate: Some(0),
+            best_move: None,
+        };
+
+        assert_eq!(over(MATE_CENTIPAWNS).label(), "1-0");
+        assert_eq!(over(-MATE_CENTIPAWNS).label(), "0-1");
+    }
+
+    async fn session(name: &str) -> Option<uci::Session> {
+        let engine = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/engines")
+            .join(name);
+
+        Some(
+            uci::Session::start(&engine, Duration::from_millis(500))
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn one_engine_goes_over_every_position() {
+        let mut session = session("fake").await;
+        let moves = [Move::parse_uci("e2e4").unwrap()];
+
+        let before = evaluate(&mut session, Variant::Standard, STARTING_FEN, &[])
+            .await
+            .unwrap();
+        let after = evaluate(&mut session, Variant::Standard, STARTING_FEN, &moves)
+            .await
+            .unwrap();
+
+        // the fake engine always sees a mate for the side to move
+        assert_eq!(before.mate, Some(3));
+        assert_eq!(after.mate, Some(-3));
+        assert!(session.is_some());
+    }
+
+    #[tokio::test]
+    async fn an_engine_that_fails_is_left_for_the_built_in_one() {
+        let mut session = session("crashes").await;
+
+        let evaluation = evaluate(&mut session, Variant::Standard, STARTING_FEN, &[])
+            .await
+            .unwrap();
+
+        assert!(session.is_none());
+        assert!(evaluation.best_move.is_some());
+    }
+}
@@ -0,0 +1,618 @@
+use crate::board::{Glyphs, Move};
+use crate::clock::{Clock, format_duration};
+use crate::engine::{self, Computer, Engine, Level};
+use crate::game::{GameResult, GameState, Outcome, Termination};
+use crate::pgn::{self, Pgn};
+use crate::piece::Color;
+use anyhow::{Context, anyhow, bail};
+use reqwest::header::{COOKIE, HeaderMap};
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+use std::io::Write;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+const HELP: &str = "moves like e4, Nf3, O-O or e2e4 · resign · flip · quit";
+
+/// how the board is drawn
+pub struct Screen {
+    pub glyphs: Glyphs,
+    /// whose side of the board is at the bottom
+    pub from: Color,
+}
+
+/// the line of the screen each player is on, above and below the board
+const TOP_ROW: usize = 1;
+const BOTTOM_ROW: usize = 11;
+
+impl Screen {
+    /// clear the terminal and draw the whole game, ending with a prompt
+    fn draw(
+        &self,
+        game_state: &GameState,
+        seat: Option<Color>,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let mut screen = String::from("\x1b[2J\x1b[H");
+
+        screen.push_str(&player_line(game_state, self.from.invert()));
+        screen.push('\n');
+        screen.push_str(&game_state.board.diagram(self.glyphs, self.from).to_string());
+        screen.push_str(&player_line(game_state, self.from));
+        screen.push_str("\n\n");
+        screen.push_str(&Pgn::of(game_state).movetext());
+        screen.push_str("\n\n");
+        screen.push_str(&status(game_state, seat));
+        screen.push('\n');
+        screen.push_str(message);
+        screen.push_str("\n> ");
+
+        let mut stdout = std::io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+
+    /// redraw just the clocks, leaving the cursor where it was
+    /// so a move being typed isn't disturbed
+    fn draw_clocks(&self, game_state: &GameState) -> anyhow::Result<()> {
+        if game_state.clock.is_none() {
+            return Ok(());
+        }
+
+        let mut stdout = std::io::stdout();
+
+        write!(
+            stdout,
+            "\x1b7\x1b[{TOP_ROW};1H\x1b[2K{}\x1b[{BOTTOM_ROW};1H\x1b[2K{}\x1b8",
+            player_line(game_state, self.from.invert()),
+            player_line(game_state, self.from),
+        )?;
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+fn player_line(game_state: &GameState, color: Color) -> String {
+    let name = match game_state.computer {
+        Some(computer) if computer.color == color => {
+            format!("Computer ({})", computer.engine.label())
+        }
+        _ => game_state
+            .profile(color)
+            .name
+            .clone()
+            .unwrap_or_else(|| "waiting".to_string()),
+    };
+
+    let label = match color {
+        Color::White => "White",
+        Color::Black => "Black",
+    };
+
+    match &game_state.clock {
+        Some(clock) => format!(
+            "{label} · {name:<30} {}",
+            format_duration(clock.remaining(color))
+        ),
+        None => format!("{label} · {name}"),
+    }
+}
+
+fn status(game_state: &GameState, seat: Option<Color>) -> String {
+    if let Some(result) = &game_state.result {
+        return result.description();
+    }
+
+    if game_state.profile(Color::White).name.is_none()
+        && game_state
+            .computer
+            .is_none_or(|computer| computer.color != Color::White)
+        || game_state.profile(Color::Black).name.is_none()
+            && game_state
+                .computer
+                .is_none_or(|computer| computer.color != Color::Black)
+    {
+        return "Waiting for an opponent".to_string();
+    }
+
+    if game_state.computer_to_move().is_some() {
+        return "The computer is thinking".to_string();
+    }
+
+    match (seat, game_state.to_move) {
+        (Some(seat), to_move) if seat == to_move => "Your move".to_string(),
+        (_, Color::White) => "White to move".to_string(),
+        (_, Color::Black) => "Black to move".to_string(),
+    }
+}
+
+/// something typed at the prompt
+enum Input {
+    Move(String),
+    Resign,
+    Flip,
+    Quit,
+    Help,
+}
+
+impl Input {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "" => None,
+            "resign" => Some(Input::Resign),
+            "flip" => Some(Input::Flip),
+            "quit" | "q" | "exit" => Some(Input::Quit),
+            "help" | "?" => Some(Input::Help),
+            m => Some(Input::Move(m.to_string())),
+        }
+    }
+}
+
+/// the next thing typed at the prompt, or `None` once stdin is closed
+async fn next_input(lines: &mut Lines<BufReader<Stdin>>) -> anyhow::Result<Option<Input>> {
+    loop {
+        match lines.next_line().await? {
+            Some(line) => {
+                if let Some(input) = Input::parse(&line) {
+                    return Ok(Some(input));
+                }
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+/// play a game in this terminal with the built-in rules,
+/// against the computer or with two people taking turns at the keyboard
+pub async fn play_local(
+    mut screen: Screen,
+    computer: Option<Level>,
+    playing_as: Color,
+    clock: Option<Clock>,
+) -> anyhow::Result<()> {
+    let mut game_state = GameState::new();
+
+    game_state.clock = clock;
+
+    // taking turns, both seats belong to the keyboard
+    let seat = computer.map(|_| playing_as);
+
+    match computer {
+        Some(level) => {
+            game_state.computer = Some(Computer {
+                color: playing_as.invert(),
+                engine: Engine::BuiltIn(level),
+            });
+
+            let you = match playing_as {
+                Color::White => &mut game_state.white_profile,
+                Color::Black => &mut game_state.black_profile,
+            };
+
+            you.name = Some("You".to_string());
+        }
+        None => {
+            game_state.white_profile.name = Some("White".to_string());
+            game_state.black_profile.name = Some("Black".to_string());
+        }
+    }
+
+    let (computer_moves, mut computer_moved) = mpsc::channel(1);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    let mut tick = tokio::time::interval(Duration::from_millis(250));
+
+    let mut message = HELP.to_string();
+
+    loop {
+        if let Some(computer) = game_state.computer_to_move()
+            && !game_state.computer_thinking
+            && let Engine::BuiltIn(level) = computer.engine
+        {
+            game_state.computer_thinking = true;
+
+            let board = game_state.board.clone();
+            let computer_moves = computer_moves.clone();
+
+            tokio::task::spawn_blocking(move || {
+                let m = engine::best_move(&board, computer.color, level.depth());
+                let _ = computer_moves.blocking_send(m);
+            });
+        }
+
+        screen.draw(&game_state, seat, &message)?;
+
+        message.clear();
+
+        // wait for something that changes the game, keeping the clocks going meanwhile
+        loop {
+            tokio::select! {
+                input = next_input(&mut lines) => {
+                    match input? {
+                        None | Some(Input::Quit) => return Ok(()),
+                        Some(Input::Help) => message = HELP.to_string(),
+                        Some(Input::Flip) => screen.from = screen.from.invert(),
+                        Some(Input::Resign) => {
+                            if game_state.result.is_none() {
+                                let loser = seat.unwrap_or(game_state.to_move);
+
+                                finish(&mut game_state, Outcome::Win(loser.invert()), Termination::Resignation);
+                            }
+                        }
+                        Some(Input::Move(written)) => {
+                            if game_state.result.is_some() {
+                                message = "the game is over".to_string();
+                            } else if seat.is_some_and(|seat| seat != game_state.to_move) {
+                                message = "it isn't your move".to_string();
+                            } else {
+                                match game_state.parse_move(&written) {
+                                    Some(m) => play(&mut game_state, m),
+                                    None => message = format!("{written} isn't a legal move"),
+                                }
+                            }
+                        }
+                    }
+
+                    break;
+                }
+                Some(m) = computer_moved.recv() => {
+                    game_state.computer_thinking = false;
+
+                    if let Some(m) = m && game_state.result.is_none() {
+                        play(&mut game_state, m);
+                    }
+
+                    break;
+                }
+                _ = tick.tick() => {
+                    if let Some(color) = game_state.flagged() {
+                        finish(&mut game_state, Outcome::Win(color.invert()), Termination::Timeout);
+
+                        break;
+                    }
+
+                    screen.draw_clocks(&game_state)?;
+                }
+            }
+        }
+    }
+}
+
+/// play a move on the local board, punching the clock
+fn play(game_state: &mut GameState, m: Move) {
+    if let Some(clock) = &mut game_state.clock {
+        clock.punch(game_state.to_move);
+    }
+
+    game_state.board.make_move(&m);
+    game_state.moves.push(m);
+    game_state.ply += 1;
+    game_state.to_move = game_state.to_move.invert();
+
+    if let Some(result) = game_state.result_on_board() {
+        finish(game_state, result.outcome, result.termination);
+    }
+}
+
+fn finish(game_state: &mut GameState, outcome: Outcome, termination: Termination) {
+    if let Some(clock) = &mut game_state.clock {
+        clock.stop();
+    }
+
+    game_state.result = Some(GameResult {
+        outcome,
+        termination,
+    });
+}
+
+/// what to create when no game is given to play on a server
+pub struct NewGame {
+    pub playing_as: Color,
+    pub computer: Option<Level>,
+    /// as stored in the db, e.g. "300+3"
+    pub time_control: Option<String>,
+}
+
+/// who is sitting where, as the server's JSON API reports it
+#[derive(Deserialize)]
+struct Seats {
+    white: Seat,
+    black: Seat,
+    /// only looked at for whether the game is over
+    result: Option<IgnoredAny>,
+    /// the seat of the player asking, if they have one
+    you: Option<Color>,
+}
+
+#[derive(Deserialize)]
+struct Seat {
+    seated: bool,
+}
+
+impl Seats {
+    fn has_open_seat(&self) -> bool {
+        self.result.is_none() && !(self.white.seated && self.black.seated)
+    }
+}
+
+/// a chez server, seen by one player
+struct Server {
+    client: reqwest::Client,
+    url: String,
+    game_id: Uuid,
+}
+
+impl Server {
+    /// the game as the server has it, by way of its PGN
+    async fn game_state(&self) -> anyhow::Result<GameState> {
+        let response = self
+            .client
+            .get(format!("{}/games/{}/pgn", self.url, self.game_id))
+            .send()
+            .await?;
+
+        let text = ok(response).await?;
+
+        pgn::parse(&text)?
+            .first()
+            .ok_or_else(|| anyhow!("the server sent a game without any PGN"))?
+            .game_state()
+    }
+
+    /// the seats as the server has them, which the PGN can't tell apart
+    /// from players who never gave a name
+    async fn seats(&self) -> anyhow::Result<Seats> {
+        let response = self
+            .client
+            .get(format!("{}/api/v1/games/{}", self.url, self.game_id))
+            .send()
+            .await?;
+
+        Ok(serde_json::from_str(&ok(response).await?)?)
+    }
+
+    /// take the open seat, returning the seat the server put us in
+    async fn join(&self) -> anyhow::Result<Option<Color>> {
+        let response = self
+            .client
+            .post(format!("{}/api/v1/games/{}/join", self.url, self.game_id))
+            .send()
+            .await?;
+
+        let seats: Seats = serde_json::from_str(&ok(response).await?)?;
+
+        Ok(seats.you)
+    }
+
+    /// post a form, returning what went wrong if the server refused
+    async fn post(&self, path: &str, form: &[(&str, &str)]) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(format!("{}/games/{}/{path}", self.url, self.game_id))
+            .form(form)
+            .send()
+            .await?;
+
+        ok(response).await?;
+
+        Ok(())
+    }
+}
+
+/// the body of a response, or an error with the body if the request failed
+async fn ok(response: reqwest::Response) -> anyhow::Result<String> {
+    let status = response.status();
+
+    let text = response.text().await?;
+
+    if !status.is_success() {
+        #[derive(Deserialize)]
+        struct ApiError {
+            error: String,
+        }
+
+        // the JSON API wraps its errors, the html side sends them as they are
+        if let Ok(ApiError { error }) = serde_json::from_str(&text) {
+            bail!("{error}");
+        }
+
+        bail!(
+            "{}",
+            if text.is_empty() {
+                status.to_string()
+            } else {
+                text
+            }
+        );
+    }
+
+    Ok(text)
+}
+
+/// play, or watch, a game on a chez server.
+/// joins `game_id` if it has an open seat, or creates `new_game` if there is no `game_id`.
+pub async fn play_remote(
+    mut screen: Screen,
+    url: &str,
+    player: Option<Uuid>,
+    game_id: Option<Uuid>,
+    new_game: NewGame,
+) -> anyhow::Result<()> {
+    let url = url.trim_end_matches('/').to_string();
+
+    let player_id = player.unwrap_or_else(Uuid::new_v4);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(COOKIE, format!("chez_player={player_id}").parse()?);
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+
+    let mut message = format!("you are player {player_id}, pass --player {player_id} to come back");
+
+    let (server, seat) = match game_id {
+        Some(game_id) => {
+            let server = Server {
+                client,
+                url,
+                game_id,
+            };
+
+            let before = server.seats().await?;
+
+            // coming back to a game we are already playing doesn't need a join
+            let seat = match before.you {
+                Some(color) => Some(color),
+                None if before.has_open_seat() => match server.join().await {
+                    Ok(seat) => seat,
+                    Err(e) => {
+                        message = format!("could not join: {e}");
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            (server, seat)
+        }
+        None => {
+            let mut form = vec![
+                ("playing_as", new_game.playing_as.as_str().to_string()),
+                ("time_control", new_game.time_control.unwrap_or_default()),
+            ];
+
+            if let Some(level) = new_game.computer {
+                form.push(("computer", level.as_str().to_string()));
+            }
+
+            let response = client
+                .post(format!("{url}/games/create"))
+                .form(&form)
+                .send()
+                .await
+                .with_context(|| format!("could not reach {url}"))?;
+
+            // htmx is told where the new game is
+            let location = response
+                .headers()
+                .get("HX-Location")
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_string);
+
+            ok(response).await?;
+
+            let game_id = location
+                .as_deref()
+                .and_then(|location| location.split('/').nth(2))
+                .and_then(|game_id| Uuid::parse_str(game_id).ok())
+                .ok_or_else(|| anyhow!("the server didn't say where the new game is"))?;
+
+            if new_game.computer.is_none() {
+                message = format!("send {url}/games/{game_id}/join to your opponent");
+            }
+
+            let server = Server {
+                client,
+                url,
+                game_id,
+            };
+
+            (server, Some(new_game.playing_as))
+        }
+    };
+
+    if let Some(seat) = seat {
+        screen.from = seat;
+    }
+
+    let (changed, mut changes) = mpsc::channel(16);
+
+    // anything happening to the game comes as a server-sent event,
+    // after which the game is fetched again
+    tokio::spawn({
+        let client = server.client.clone();
+        let events = format!("{}/games/{}/events", server.url, server.game_id);
+
+        async move {
+            loop {
+                if let Ok(response) = client.get(&events).send().await
+                    && response.status().is_success()
+                {
+                    let mut stream = response.bytes_stream();
+
+                    while let Some(Ok(chunk)) = stream.next().await {
+                        if chunk.windows(6).any(|window| window == b"event:")
+                            && changed.send(()).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+
+                // something may have happened while we weren't listening
+                if changed.send(()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    let mut tick = tokio::time::interval(Duration::from_millis(250));
+
+    let mut game_state = server.game_state().await?;
+
+    loop {
+        screen.draw(&game_state, seat, &message)?;
+
+        message.clear();
+
+        loop {
+            tokio::select! {
+                input = next_input(&mut lines) => {
+                    let done = match input? {
+                        None | Some(Input::Quit) => return Ok(()),
+                        Some(Input::Help) => {
+                            message = HELP.to_string();
+                            Ok(())
+                        }
+                        Some(Input::Flip) => {
+                            screen.from = screen.from.invert();
+                            Ok(())
+                        }
+                        Some(Input::Resign) => server.post("resign", &[]).await,
+                        Some(Input::Move(written)) => server.post("moves", &[("move", &written)]).await,
+                    };
+
+                    if let Err(e) = done {
+                        message = e.to_string();
+                    }
+
+                    game_state = server.game_state().await?;
+
+                    break;
+                }
+                Some(()) = changes.recv() => {
+                    game_state = server.game_state().await?;
+
+                    break;
+                }
+                _ = tick.tick() => {
+                    screen.draw_clocks(&game_state)?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,774 @@
+use crate::board::{Board, Move};
+use crate::engine;
+use crate::piece::Color;
+use crate::variant::Variant;
+use anyhow::{Context, anyhow};
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::debug;
+
+/// how long an engine gets to start up and answer,
+/// on top of whatever time it was told to think for
+pub const GRACE: Duration = Duration::from_secs(10);
+
+/// how long the engine thinks when there are no clocks to go by
+pub const MOVE_TIME: Duration = Duration::from_secs(1);
+
+/// the position to search: where the game started and the moves since
+pub struct Position<'a> {
+    pub starting_fen: &'a str,
+    pub moves: &'a [Move],
+    pub variant: Variant,
+}
+
+/// how long the engine may think
+pub enum Limit {
+    MoveTime(Duration),
+    /// think as if playing with these clocks
+    Clock {
+        white: Duration,
+        black: Duration,
+        increment: Duration,
+    },
+}
+
+impl Limit {
+    /// the longest the engine could reasonably take
+    fn budget(&self) -> Duration {
+        match self {
+            Limit::MoveTime(move_time) => *move_time,
+            Limit::Clock { white, black, .. } => *white.max(black),
+        }
+    }
+}
+
+/// an evaluation, from the point of view of the side to move
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Score {
+    Centipawns(i32),
+    /// mate in this many moves, negative if the side to move is getting mated
+    Mate(i32),
+}
+
+impl Score {
+    /// the score from white's point of view, e.g. "+0.35" or "#-2"
+    pub fn label(&self, to_move: Color) -> String {
+        let sign = match to_move {
+            Color::White => 1,
+            Color::Black => -1,
+        };
+
+        match self {
+            Score::Centipawns(cp) => format!("{:+.2}", (sign * cp) as f64 / 100.0),
+            Score::Mate(moves) => format!("#{}", sign * moves),
+        }
+    }
+}
+
+/// what the engine found
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// `None` if there are no moves to make
+    pub best_move: Option<Move>,
+    pub score: Option<Score>,
+    pub depth: Option<u32>,
+    /// the line the engine expects to be played
+    pub pv: Vec<Move>,
+}
+
+/// start the engine at `engine`, have it search `position`,
+/// and shut it down again.
+/// `searchmoves` restricts which moves the engine considers, if not empty.
+pub async fn search(
+    engine: &Path,
+    position: Position<'_>,
+    limit: Limit,
+    searchmoves: &[Move],
+) -> anyhow::Result<Analysis> {
+    let mut session = Session::start(engine, GRACE).await?;
+
+    let analysis = session.search(position, limit, searchmoves).await;
+
+    session.quit().await;
+
+    analysis
+}
+
+/// an engine that has been started and answered the handshake,
+/// ready to search as many positions as it is given
+pub struct Session {
+    process: Process,
+    engine: String,
+    /// how long the engine gets to answer, on top of the time it is told to think for
+    grace: Duration,
+}
+
+impl Session {
+    pub async fn start(engine: &Path, grace: Duration) -> anyhow::Result<Self> {
+        let mut process = Process::spawn(engine)?;
+
+        let engine = engine.display().to_string();
+
+        tokio::time::timeout(grace, process.handshake())
+            .await
+            .with_context(|| format!("{engine} took longer than {grace:?} to start"))??;
+
+        Ok(Self {
+            process,
+            engine,
+            grace,
+        })
+    }
+
+    pub async fn search(
+        &mut self,
+        position: Position<'_>,
+        limit: Limit,
+        searchmoves: &[Move],
+    ) -> anyhow::Result<Analysis> {
+        let timeout = limit.budget() + self.grace;
+
+        tokio::time::timeout(timeout, self.process.go(position, limit, searchmoves))
+            .await
+            .with_context(|| format!("{} took longer than {timeout:?}", self.engine))?
+    }
+
+    /// the engine is killed when the process is dropped, if it doesn't quit
+    pub async fn quit(mut self) {
+        let _ = self.process.send("quit").await;
+    }
+}
+
+struct Process {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Process {
+    fn spawn(engine: &Path) -> anyhow::Result<Self> {
+        let mut child = Command::new(engine)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("could not start {}", engine.display()))?;
+
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
+
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        })
+    }
+
+    async fn send(&mut self, command: &str) -> anyhow::Result<()> {
+        debug!("uci > {command}");
+
+        self.stdin.write_all(command.as_bytes()).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let line = self
+            .stdout
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("the engine quit unexpectedly"))?;
+
+        debug!("uci < {line}");
+
+        Ok(line)
+    }
+
+    /// read until a line starting with `token`
+    async fn wait_for(&mut self, token: &str) -> anyhow::Result<()> {
+        loop {
+            let line = self.read_line().await?;
+
+            if line.split_whitespace().next() == Some(token) {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handshake(&mut self) -> anyhow::Result<()> {
+        self.send("uci").await?;
+        self.wait_for("uciok").await?;
+
+        self.send("isready").await?;
+        self.wait_for("readyok").await
+    }
+
+    async fn go(
+        &mut self,
+        position: Position<'_>,
+        limit: Limit,
+        searchmoves: &[Move],
+    ) -> anyhow::Result<Analysis> {
+        // Chess960 castling is written as the king taking its own rook,
+        // which engines only understand once they're told to expect it
+        if position.variant == Variant::Chess960 {
+            self.send("setoption name UCI_Chess960 value true").await?;
+        }
+
+        if let Some(variant) = position.variant.uci_name() {
+            self.send(&format!("setoption name UCI_Variant value {variant}"))
+                .await?;
+        }
+
+        let mut command = format!("position fen {}", position.starting_fen);
+
+        if !position.moves.is_empty() {
+            command.push_str(" moves");
+
+            for m in position.moves {
+                command.push(' ');
+                command.push_str(&m.uci());
+            }
+        }
+
+        self.send(&command).await?;
+
+        let mut command = match limit {
+            Limit::MoveTime(move_time) => format!("go movetime {}", move_time.as_millis()),
+            Limit::Clock {
+                white,
+                black,
+                increment,
+            } => format!(
+                "go wtime {} btime {} winc {} binc {}",
+                white.as_millis(),
+                black.as_millis(),
+                increment.as_millis(),
+                increment.as_millis()
+            ),
+        };
+
+        if !searchmoves.is_empty() {
+            command.push_str(" searchmoves");
+
+            for m in searchmoves {
+                command.push(' ');
+                command.push_str(&m.uci());
+            }
+        }
+
+        self.send(&command).await?;
+
+        let mut analysis = Analysis::default();
+
+        loop {
+            let line = self.read_line().await?;
+
+            let mut tokens = line.split_whitespace();
+
+            match tokens.next() {
+                Some("info") => parse_info(&mut analysis, tokens),
+                Some("bestmove") => {
+                    // "(none)" or "0000" when there is nothing to play
+                    analysis.best_move = tokens.next().and_then(Move::parse_uci);
+
+                    return Ok(analysis);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// pick the score, depth and line out of an `info` line.
+/// lines about other things, like the current move, are ignored.
+fn parse_info<'a>(analysis: &mut Analysis, mut tokens: impl Iterator<Item = &'a str>) {
+    // only the best line matters
+    let mut depth = None;
+    let mut score = None;
+    let mut pv = None;
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "multipv" if tokens.next() != Some("1") => return,
+            "depth" => depth = tokens.next().and_then(|depth| depth.parse().ok()),
+            "score" => {
+                score = match (tokens.next(), tokens.next().and_then(|n| n.parse().ok())) {
+                    (Some("cp"), Some(cp)) => Some(Score::Centipawns(cp)),
+                    (Some("mate"), Some(moves)) => Some(Score::Mate(moves)),
+                    _ => None,
+                }
+            }
+            // the line is always last
+            "pv" => {
+                pv = Some(tokens.by_ref().map_while(Move::parse_uci).collect());
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(score) = score {
+        analysis.score = Some(score);
+        analysis.depth = depth.or(analysis.depth);
+    }
+
+    if let Some(pv) = pv {
+        analysis.pv = pv;
+    }
+}
+
+/// how deep `go` searches when it isn't told how long it may take
+const DEFAULT_DEPTH: u8 = 4;
+
+/// as deep as a search can go when it is only stopped by time
+const MAX_DEPTH: u8 = 64;
+
+/// be chez's own engine for a UCI GUI, reading commands from stdin
+/// and answering on stdout until told to quit
+pub fn run_engine() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let mut to_move = Color::White;
+    let mut variant = Variant::Standard;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut searching: Option<thread::JoinHandle<()>> = None;
+
+    for line in std::io::stdin().lines() {
+        let line = line?;
+
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("uci") => {
+                say(&format!("id name chez {}", env!("CARGO_PKG_VERSION")));
+                say("id author the chez developers");
+                say("option name UCI_Chess960 type check default false");
+                say(&format!(
+                    "option name UCI_Variant type combo default chess var chess{}",
+                    Variant::ALL
+                        .iter()
+                        .filter_map(Variant::uci_name)
+                        .map(|name| format!(" var {name}"))
+                        .collect::<String>()
+                ));
+                say("uciok");
+            }
+            Some("isready") => say("readyok"),
+            Some("ucinewgame") => {
+                board = Board::new();
+                to_move = Color::White;
+            }
+            Some("setoption") => variant = set_option(tokens, variant),
+            Some("position") => match parse_position(tokens, variant) {
+                Some(position) => (board, to_move) = position,
+                None => info_string(&format!("could not read the position in \"{line}\"")),
+            },
+            Some("go") => {
+                // a new search only starts once the last one is done
+                stop.store(true, Ordering::Relaxed);
+                if let Some(search) = searching.take() {
+                    let _ = search.join();
+                }
+                stop.store(false, Ordering::Relaxed);
+
+                let (max_depth, deadline) = parse_go(tokens, to_move);
+
+                let board = board.clone();
+                let stop = Arc::clone(&stop);
+
+                searching = Some(thread::spawn(move || {
+                    let should_stop = || {
+                        stop.load(Ordering::Relaxed)
+                            || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                    };
+
+                    let started = Instant::now();
+
+                    let best = engine::iterative_deepening(
+                        &board,
+                        to_move,
+                        max_depth,
+                        &should_stop,
+                        |depth, score, best| {
+                            let score = match engine::mate_in(score, depth) {
+                                Some(moves) => format!("mate {moves}"),
+                                None => format!("cp {score}"),
+                            };
+
+                            say(&format!(
+                                "info depth {depth} score {score} time {} pv {}",
+                                started.elapsed().as_millis(),
+                                best.uci()
+                            ));
+                        },
+                    );
+
+                    match best {
+                        Some(best) => say(&format!("bestmove {}", best.uci())),
+                        None => say("bestmove 0000"),
+                    }
+                }));
+            }
+            Some("stop") => stop.store(true, Ordering::Relaxed),
+            Some("quit") => break,
+            // anything else, like `debug`, has nothing to do
+            _ => (),
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+
+    if let Some(search) = searching {
+        let _ = search.join();
+    }
+
+    Ok(())
+}
+
+/// the variant after `setoption name <name> value <value>`.
+/// GUIs send every option they know about, so turning Chess960 off
+/// only matters in Chess960, and variants chez doesn't play are ignored.
+fn set_option<'a>(tokens: impl Iterator<Item = &'a str>, variant: Variant) -> Variant {
+    let option: Vec<&str> = tokens.collect();
+
+    match option[..] {
+        ["name", "UCI_Chess960", "value", "true"] => Variant::Chess960,
+        ["name", "UCI_Chess960", "value", _] if variant == Variant::Chess960 => Variant::Standard,
+        ["name", "UCI_Variant", "value", "chess"] if variant != Variant::Chess960 => {
+            Variant::Standard
+        }
+        ["name", "UCI_Variant", "value", value] => Variant::ALL
+            .into_iter()
+            .find(|variant| variant.uci_name() == Some(value))
+            .unwrap_or(variant),
+        _ => variant,
+    }
+}
+
+/// `position [startpos | fen <fen>] [moves <move>...]`
+fn parse_position<'a>(
+    mut tokens: impl Iterator<Item = &'a str>,
+    variant: Variant,
+) -> Option<(Board, Color)> {
+    let (mut board, mut to_move) = match tokens.next()? {
+        "startpos" => {
+            // skip "moves"
+            tokens.next();
+            (Board::new(), Color::White)
+        }
+        "fen" => {
+            let fen: Vec<&str> = tokens
+                .by_ref()
+                .take_while(|token| *token != "moves")
+                .collect();
+            Board::from_fen(&fen.join(" "))?
+        }
+        _ => return None,
+    };
+
+    board.variant = variant;
+
+    for m in tokens {
+        board.make_move(&Move::parse_uci(m)?);
+        to_move = to_move.invert();
+    }
+
+    Some((board, to_move))
+}
+
+/// how deep to search and when to stop, from the arguments to `go`
+fn parse_go<'a>(
+    mut tokens: impl Iterator<Item = &'a str>,
+    to_move: Color,
+) -> (u8, Option<Instant>) {
+    let mut depth = None;
+    let mut move_time = None;
+    let mut remaining = None;
+    let mut increment = Duration::ZERO;
+    let mut infinite = false;
+
+    let millis = |token: Option<&str>| {
+        token
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis)
+    };
+
+    while let Some(token) = tokens.next() {
+        match (token, to_move) {
+            ("depth", _) => depth = tokens.next().and_then(|depth| depth.parse().ok()),
+            ("movetime", _) => move_time = millis(tokens.next()),
+            ("wtime", Color::White) | ("btime", Color::Black) => remaining = millis(tokens.next()),
+            ("winc", Color::White) | ("binc", Color::Black) => {
+                increment = millis(tokens.next()).unwrap_or_default()
+            }
+            ("infinite", _) => infinite = true,
+            _ => (),
+        }
+    }
+
+    // spend a little of what's left on every move, and never all of it
+    let think_for = move_time
+        .or(remaining.map(|remaining| (remaining / 30 + increment / 2).min(remaining / 2)));
+
+    match (depth, think_for) {
+        (Some(depth), think_for) => (depth, think_for.map(|think_for| Instant::now() + think_for)),
+        (None, Some(think_for)) => (MAX_DEPTH, Some(Instant::now() + think_for)),
+        (None, None) if infinite => (MAX_DEPTH, None),
+        (None, None) => (DEFAULT_DEPTH, None),
+    }
+}
+
+/// answer the GUI. every line is flushed right away since the GUI is waiting on it.
+fn say(line: &str) {
+    let mut stdout = std::io::stdout().lock();
+
+    let _ = writeln!(stdout, "{line}");
+    let _ = stdout.flush();
+}
+
+fn info_string(message: &str) {
+    say(&format!("info string {message}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::STARTING_FEN;
+    use std::path::PathBuf;
+
+    /// the fake engines answer right away, or never
+    const TEST_GRACE: Duration = Duration::from_millis(500);
+
+    /// one of the scripts in tests/engines
+    fn engine(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/engines")
+            .join(name)
+    }
+
+    async fn search_start(name: &str, searchmoves: &[Move]) -> anyhow::Result<Analysis> {
+        let mut session = Session::start(&engine(name), TEST_GRACE).await?;
+
+        let analysis = session
+            .search(
+                Position {
+                    starting_fen: STARTING_FEN,
+                    moves: &[],
+                    variant: Variant::Standard,
+                },
+                Limit::MoveTime(Duration::from_millis(10)),
+                searchmoves,
+            )
+            .await;
+
+        session.quit().await;
+
+        analysis
+    }
+
+    fn moves(uci: &[&str]) -> Vec<Move> {
+        uci.iter().map(|m| Move::parse_uci(m).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn search_keeps_the_last_word_on_the_best_line() {
+        let analysis = search_start("fake", &[]).await.unwrap();
+
+        assert_eq!(analysis.best_move, Move::parse_uci("e2e4"));
+        assert_eq!(analysis.score, Some(Score::Mate(3)));
+        assert_eq!(analysis.depth, Some(2));
+        assert_eq!(analysis.pv, moves(&["e2e4", "e7e5"]));
+    }
+
+    #[tokio::test]
+    async fn search_passes_on_the_moves_to_search() {
+        let analysis = search_start("fake", &moves(&["d2d4"])).await.unwrap();
+
+        assert_eq!(analysis.best_move, Move::parse_uci("d2d4"));
+        assert_eq!(analysis.score, Some(Score::Centipawns(-5)));
+    }
+
+    #[tokio::test]
+    async fn a_session_searches_more_than_once() {
+        let mut session = Session::start(&engine("fake"), TEST_GRACE).await.unwrap();
+
+        for moves in [vec![], moves(&["e2e4"])] {
+            let analysis = session
+                .search(
+                    Position {
+                        starting_fen: STARTING_FEN,
+                        moves: &moves,
+                        variant: Variant::Standard,
+                    },
+                    Limit::MoveTime(Duration::from_millis(10)),
+                    &[],
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(analysis.best_move, Move::parse_uci("e2e4"));
+        }
+
+        session.quit().await;
+    }
+
+    #[tokio::test]
+    async fn an_engine_that_never_answers_times_out() {
+        let error = search_start("hangs", &[]).await.unwrap_err();
+
+        assert!(error.to_string().contains("took longer than"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn an_engine_that_crashes_is_an_error() {
+        let error = search_start("crashes", &[]).await.unwrap_err();
+
+        assert!(error.to_string().contains("quit unexpectedly"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn an_engine_that_isnt_there_is_an_error() {
+        let error = search(
+            &engine("missing"),
+            Position {
+                starting_fen: STARTING_FEN,
+                moves: &[],
+                variant: Variant::Standard,
+            },
+            Limit::MoveTime(Duration::from_millis(10)),
+            &[],
+        )
+        .await
+        .unwrap_err();
+
+        assert!(error.to_string().contains("could not start"), "{error}");
+    }
+
+    fn after(options: &[&str], variant: Variant) -> Variant {
+        options.iter().fold(variant, |variant, option| {
+            set_option(option.split_whitespace(), variant)
+        })
+    }
+
+    #[test]
+    fn options_only_change_the_variant_they_are_about() {
+        assert_eq!(
+            after(
+                &[
+                    "name UCI_Variant value crazyhouse",
+                    "name UCI_Chess960 value false"
+                ],
+                Variant::Standard
+            ),
+            Variant::Crazyhouse
+        );
+        assert_eq!(
+            after(&["name UCI_Chess960 value true"], Variant::Standard),
+            Variant::Chess960
+        );
+        assert_eq!(
+            after(&["name UCI_Chess960 value false"], Variant::Chess960),
+            Variant::Standard
+        );
+        assert_eq!(
+            after(&["name UCI_Variant value chess"], Variant::Atomic),
+            Variant::Standard
+        );
+        assert_eq!(
+            after(&["name UCI_Variant value chess"], Variant::Chess960),
+            Variant::Chess960
+        );
+        assert_eq!(
+            after(&["name UCI_Variant value giveaway"], Variant::Atomic),
+            Variant::Atomic
+        );
+        assert_eq!(
+            after(&["name Hash value 16"], Variant::ThreeCheck),
+            Variant::ThreeCheck
+        );
+    }
+
+    #[test]
+    fn positions_are_read_from_a_fen_and_the_moves_after_it() {
+        let (board, to_move) = parse_position(
+            "startpos moves e2e4 e7e5 g1f3".split_whitespace(),
+            Variant::Standard,
+        )
+        .unwrap();
+
+        assert_eq!(
+            board.fen(to_move),
+            Board::from_fen("rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2")
+                .map(|(board, to_move)| board.fen(to_move))
+                .unwrap()
+        );
+
+        let (board, to_move) = parse_position(
+            "fen 4k3/8/8/8/8/8/8/4K3 w - - 0 1 moves e1e2".split_whitespace(),
+            Variant::KingOfTheHill,
+        )
+        .unwrap();
+
+        assert_eq!(to_move, Color::Black);
+        assert_eq!(board.variant, Variant::KingOfTheHill);
+
+        assert!(parse_position("fen".split_whitespace(), Variant::Standard).is_none());
+        assert!(
+            parse_position("startpos moves e2".split_whitespace(), Variant::Standard).is_none()
+        );
+    }
+
+    #[test]
+    fn go_thinks_for_as_long_as_it_is_told() {
+        assert_eq!(
+            parse_go("depth 3".split_whitespace(), Color::White),
+            (3, None)
+        );
+        assert_eq!(
+            parse_go("infinite".split_whitespace(), Color::White),
+            (MAX_DEPTH, None)
+        );
+        assert_eq!(
+            parse_go("".split_whitespace(), Color::White),
+            (DEFAULT_DEPTH, None)
+        );
+
+        let think_for = |go: &str, to_move| {
+            let before = Instant::now();
+            let (depth, deadline) = parse_go(go.split_whitespace(), to_move);
+
+            assert_eq!(depth, MAX_DEPTH);
+
+            deadline.unwrap() - before
+        };
+
+        let close = |actual: Duration, expected: u64| {
+            let expected = Duration::from_millis(expected);
+
+            assert!(
+                actual >= expected && actual < expected + Duration::from_millis(50),
+                "{actual:?} isn't {expected:?}"
+            );
+        };
+
+        close(think_for("movetime 500", Color::White), 500);
+
+        // a thirtieth of what's left and half the increment, for whoever is to move
+        let clocks = "wtime 60000 btime 3000 winc 2000 binc 0";
+        close(think_for(clocks, Color::White), 3000);
+        close(think_for(clocks, Color::Black), 100);
+
+        // never more than half of what's left
+        close(think_for("wtime 1000 winc 5000", Color::White), 500);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::board::{Board, PROMOTIONS};
+use crate::game::Termination;
+use crate::piece::Color;
+use crate::piece::PieceKind::{self, King};
+use crate::piece::Position;
+use serde::{Deserialize, Serialize};
+
+/// the rules a game is played by.
+/// the board asks its variant whenever a rule could differ from standard chess,
+/// and the game asks it whether someone has won in a way only the variant knows about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Standard,
+    /// Fischer Random: the back rank is shuffled, and castling puts
+    /// the king and rook on their usual squares wherever they started
+    Chess960,
+    /// a king reaching one of the four centre squares wins
+    KingOfTheHill,
+    /// giving check for the third time wins
+    ThreeCheck,
+    /// taken pieces go to the taker's pocket,
+    /// and can be dropped back on the board instead of moving
+    Crazyhouse,
+    /// a capture blows up the capturing piece and every piece but pawns
+    /// around it, and blowing up the other king wins
+    Atomic,
+    /// captures are compulsory, the king is just another piece,
+    /// and losing every piece wins
+    Antichess,
+}
+
+/// the squares a king wins on in King of the Hill: d4, e4, d5 and e5
+const HILL: [(i8, i8); 4] = [(3, 3), (4, 3), (3, 4), (4, 4)];
+
+/// how many checks win in Three-check
+const CHECKS_TO_WIN: u8 = 3;
+
+impl Variant {
+    pub const ALL: [Variant; 7] = [
+        Variant::Standard,
+        Variant::Chess960,
+        Variant::KingOfTheHill,
+        Variant::ThreeCheck,
+        Variant::Crazyhouse,
+        Variant::Atomic,
+        Variant::Antichess,
+    ];
+
+    /// the variant as it is stored in the db
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Standard => "standard",
+            Variant::Chess960 => "chess960",
+            Variant::KingOfTheHill => "kingofthehill",
+            Variant::ThreeCheck => "threecheck",
+            Variant::Crazyhouse => "crazyhouse",
+            Variant::Atomic => "atomic",
+            Variant::Antichess => "antichess",
+        }
+    }
+
+    pub fn parse(variant: &str) -> Option<Self> {
+        match variant {
+            "standard" => Some(Variant::Standard),
+            "chess960" => Some(Variant::Chess960),
+            "kingofthehill" => Some(Variant::KingOfTheHill),
+            "threecheck" => Some(Variant::ThreeCheck),
+            "crazyhouse" => Some(Variant::Crazyhouse),
+            "atomic" => Some(Variant::Atomic),
+            "antichess" => Some(Variant::Antichess),
+            _ => None,
+        }
+    }
+
+    /// the variant as people write it, and as PGN's Variant tag has it
+    pub fn label(&self) -> &'static str {
+        match self {
+            Variant::Standard => "Standard",
+            Variant::Chess960 => "Chess960",
+            Variant::KingOfTheHill => "King of the Hill",
+            Variant::ThreeCheck => "Three-check",
+            Variant::Crazyhouse => "Crazyhouse",
+            Variant::Atomic => "Atomic",
+            Variant::Antichess => "Antichess",
+        }
+    }
+
+    /// the variant from a PGN Variant tag.
+    /// some sites call standard chess from a set up position "From Position".
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label.to_lowercase().replace([' ', '-'], "").as_str() {
+            "standard" | "fromposition" => Some(Variant::Standard),
+            "chess960" | "fischerandom" | "fischerrandom" => Some(Variant::Chess960),
+            "kingofthehill" | "koth" => Some(Variant::KingOfTheHill),
+            "threecheck" | "3check" => Some(Variant::ThreeCheck),
+            "crazyhouse" | "zh" => Some(Variant::Crazyhouse),
+            "atomic" => Some(Variant::Atomic),
+            "antichess" | "giveaway" => Some(Variant::Antichess),
+            _ => None,
+        }
+    }
+
+    /// what engines that play variants, like Fairy-Stockfish, call it.
+    /// `None` for the variants every engine plays.
+    pub fn uci_name(&self) -> Option<&'static str> {
+        match self {
+            Variant::Standard | Variant::Chess960 => None,
+            Variant::KingOfTheHill => Some("kingofthehill"),
+            Variant::ThreeCheck => Some("3check"),
+            Variant::Crazyhouse => Some("crazyhouse"),
+            Variant::Atomic => Some("atomic"),
+            Variant::Antichess => Some("antichess"),
+        }
+    }
+
+    /// whether castling is written as the king taking its own rook,
+    /// since in Chess960 the king might move one square or none at all
+    pub fn castles_onto_rook(&self) -> bool {
+        *self == Variant::Chess960
+    }
+
+    /// whether the board has to keep count of checks
+    pub fn counts_checks(&self) -> bool {
+        *self == Variant::ThreeCheck
+    }
+
+    /// whether taken pieces can be dropped back on the board
+    pub fn has_pockets(&self) -> bool {
+        *self == Variant::Crazyhouse
+    }
+
+    /// whether captures blow up everything but pawns around them
+    pub fn explodes(&self) -> bool {
+        *self == Variant::Atomic
+    }
+
+    /// whether the king can be checked, and castle.
+    /// without that it's a piece like any other.
+    pub fn has_royal_king(&self) -> bool {
+        *self != Variant::Antichess
+    }
+
+    /// what a pawn reaching the last row can become, the usual choice first.
+    /// without a royal king, a king is a piece like any other to promote to.
+    pub fn promotions(&self) -> Vec<PieceKind> {
+        let mut promotions = PROMOTIONS.to_vec();
+
+        if !self.has_royal_king() {
+            promotions.push(King);
+        }
+
+        promotions
+    }
+
+    /// whether a player who can capture has to
+    pub fn must_capture(&self) -> bool {
+        *self == Variant::Antichess
+    }
+
+    /// whether a player with no moves left wins rather than draws
+    pub fn stalemate_wins(&self) -> bool {
+        *self == Variant::Antichess
+    }
+
+    /// who has won by the variant's own rules, and how,
+    /// on top of checkmate which wins in every variant
+    pub fn winner(&self, board: &Board) -> Option<(Color, Termination)> {
+        match self {
+            Variant::Standard | Variant::Chess960 | Variant::Crazyhouse => None,
+            // a move that blows up your own king isn't legal,
+            // so only one king can be missing
+            Variant::Atomic => [Color::White, Color::Black]
+                .into_iter()
+                .find(|color| {
+                    !board
+                        .get_pieces(color.invert())
+                        .any(|piece| piece.kind == King)
+                })
+                .map(|color| (color, Termination::Explosion)),
+            Variant::Antichess => [Color::White, Color::Black]
+                .into_iter()
+                .find(|color| board.get_pieces(*color).next().is_none())
+                .map(|color| (color, Termination::NoPiecesLeft)),
+            Variant::KingOfTheHill => [Color::White, Color::Black]
+                .into_iter()
+                .find(|color| {
+                    board.get_pieces(*color).any(|piece| {
+                        piece.kind == King && HILL.map(Position::from).contains(&piece.position)
+                    })
+                })
+                .map(|color| (color, Termination::KingOfTheHill)),
+            Variant::ThreeCheck => [Color::White, Color::Black]
+                .into_iter()
+                .find(|color| board.checks(*color) >= CHECKS_TO_WIN)
+                .map(|color| (color, Termination::ThreeChecks)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Move;
+    use crate::piece::PieceKind::Pawn;
+
+    fn board(fen: &str, variant: Variant) -> Board {
+        let (mut board, _) = Board::from_fen(fen).unwrap();
+        board.variant = variant;
+        board
+    }
+
+    fn play(board: &mut Board, moves: &[&str]) {
+        for m in moves {
+            board.make_move(&Move::parse_uci(m).unwrap());
+        }
+    }
+
+    fn legal_moves(board: &Board, color: Color) -> Vec<String> {
+        let mut moves: Vec<String> = board.all_legal_moves(color).iter().map(Move::uci).collect();
+        moves.sort();
+        moves
+    }
+
+    #[test]
+    fn standard_rules_have_no_winner_of_their_own() {
+        for variant in [Variant::Standard, Variant::Chess960, Variant::Crazyhouse] {
+            let mut board = board("4k3/8/8/8/8/4K3/8/8 w - - 0 1", variant);
+            play(&mut board, &["e3e4"]);
+
+            assert_eq!(variant.winner(&board), None, "{variant:?}");
+        }
+    }
+
+    #[test]
+    fn king_of_the_hill_is_won_in_the_centre() {
+        let mut board = board("4k3/8/8/8/8/4K3/8/8 w - - 0 1", Variant::KingOfTheHill);
+
+        assert_eq!(Variant::KingOfTheHill.winner(&board), None);
+
+        play(&mut board, &["e3e4"]);
+
+        assert_eq!(
+            Variant::KingOfTheHill.winner(&board),
+            Some((Color::White, Termination::KingOfTheHill))
+        );
+    }
+
+    #[test]
+    fn three_checks_win() {
+        let mut board = board("4k3/8/8/8/8/8/8/4K2R w K - 0 1", Variant::ThreeCheck);
+
+        play(&mut board, &["h1h8", "e8e7", "h8h7", "e7e6"]);
+
+        assert_eq!(board.checks(Color::White), 2);
+        assert_eq!(Variant::ThreeCheck.winner(&board), None);
+
+        play(&mut board, &["h7h6"]);
+
+        assert_eq!(
+            Variant::ThreeCheck.winner(&board),
+            Some((Color::White, Termination::ThreeChecks))
+        );
+    }
+
+    #[test]
+    fn checks_only_count_in_three_check() {
+        let mut board = board("4k3/8/8/8/8/8/8/4K2R w K - 0 1", Variant::Standard);
+
+        play(&mut board, &["h1h8"]);
+
+        assert_eq!(board.checks(Color::White), 0);
+    }
+
+    #[test]
+    fn crazyhouse_captures_can_be_dropped() {
+        let mut board = board("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", Variant::Crazyhouse);
+
+        play(&mut board, &["e4d5"]);
+
+        assert_eq!(board.pocket(Color::White), [Pawn]);
+        assert!(board.pocket(Color::Black).is_empty());
+
+        let drops: Vec<String> = board
+            .all_legal_moves(Color::White)
+            .iter()
+            .filter(|m| m.drop.is_some())
+            .map(Move::uci)
+            .collect();
+
+        // every empty square but the first and last rows
+        assert_eq!(drops.len(), 6 * 8 - 1);
+        assert!(drops.contains(&"P@e4".to_string()));
+        assert!(!drops.iter().any(|m| m.ends_with('1') || m.ends_with('8')));
+
+        play(&mut board, &["e8e7", "P@e4"]);
+
+        assert!(board.pocket(Color::White).is_empty());
+    }
+
+    #[test]
+    fn atomic_captures_blow_up_the_king_next_to_them() {
+        let mut board = board("3qk3/8/8/8/8/8/8/3QK3 w - - 0 1", Variant::Atomic);
+
+        play(&mut board, &["d1d8"]);
+
+        assert_eq!(
+            Variant::Atomic.winner(&board),
+            Some((Color::White, Termination::Explosion))
+        );
+
+        // the queen went up with the king
+        assert!(
+            board
+                .get_pieces(Color::White)
+                .all(|piece| piece.kind == King)
+        );
+    }
+
+    #[test]
+    fn atomic_kings_cannot_capture() {
+        let fen = "4k3/8/8/8/8/8/4p3/4K3 w - - 0 1";
+
+        assert!(legal_moves(&board(fen, Variant::Standard), Color::White).contains(&"e1e2".into()));
+        assert!(!legal_moves(&board(fen, Variant::Atomic), Color::White).contains(&"e1e2".into()));
+    }
+
+    #[test]
+    fn antichess_captures_are_compulsory_and_losing_everything_wins() {
+        let mut board = board("8/8/8/8/8/8/3p4/4K3 w - - 0 1", Variant::Antichess);
+
+        // the king is a piece like any other, and has to take
+        assert_eq!(legal_moves(&board, Color::White), ["e1d2"]);
+
+        play(&mut board, &["e1d2"]);
+
+        assert_eq!(
+            Variant::Antichess.winner(&board),
+            Some((Color::Black, Termination::NoPiecesLeft))
+        );
+    }
+
+    #[test]
+    fn antichess_pawns_can_become_kings() {
+        let promotions = |variant| {
+            let board = board("8/P7/8/8/8/8/8/7k w - - 0 1", variant);
+
+            legal_moves(&board, Color::White)
+        };
+
+        assert_eq!(
+            promotions(Variant::Antichess),
+            ["a7a8b", "a7a8k", "a7a8n", "a7a8q", "a7a8r"]
+        );
+        assert!(!promotions(Variant::Standard).contains(&"a7a8k".to_string()));
+        assert!(!promotions(Variant::Atomic).contains(&"a7a8k".to_string()));
+    }
+
+    #[test]
+    fn losers_is_not_antichess() {
+        assert_eq!(Variant::from_label("Antichess"), Some(Variant::Antichess));
+        assert_eq!(Variant::from_label("Giveaway"), Some(Variant::Antichess));
+        assert_eq!(Variant::from_label("Losers"), None);
+    }
+}
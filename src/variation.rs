@@ -0,0 +1,496 @@
+use crate::analysis;
+use crate::board::{Board, Move};
+use crate::pgn::{self, Pgn};
+use crate::piece::{Color, PieceKind};
+use crate::player::PlayerId;
+use crate::variant::Variant;
+use sqlx::SqliteConnection;
+use std::fmt;
+use uuid::Uuid;
+
+/// a move somewhere in the tree
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub id: i64,
+    /// the move this one answers, `None` for a first move
+    pub parent: Option<i64>,
+    pub m: Move,
+    /// where this move comes among the moves from the same position,
+    /// the main line lowest
+    rank: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct VariationRow {
+    id: i64,
+    parent_id: Option<i64>,
+    rank: i64,
+    from_column: i8,
+    from_row: i8,
+    to_column: i8,
+    to_row: i8,
+    promotion: Option<String>,
+    dropped: Option<String>,
+}
+
+/// every line a player has tried out from a game,
+/// the main line and the sidelines that branch off it
+#[derive(Debug, Default)]
+pub struct Tree {
+    nodes: Vec<Node>,
+}
+
+/// a piece of the move list: a move, or where a sideline starts or ends
+pub enum Token {
+    Move {
+        id: i64,
+        /// "12." before white's moves, and "12..." before black's
+        /// where they need one to make sense
+        number: Option<String>,
+        san: String,
+    },
+    Open,
+    Close,
+}
+
+impl Tree {
+    /// the lines `player` has tried out from a game.
+    /// the first time they look, the moves played become their main line.
+    pub async fn load(
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        player: PlayerId,
+        played: &[Move],
+    ) -> anyhow::Result<Self> {
+        let tree = Self::fetch(conn, game_id, player).await?;
+
+        if !tree.nodes.is_empty() || played.is_empty() {
+            return Ok(tree);
+        }
+
+        let mut parent = None;
+
+        for m in played {
+            parent = Some(insert(conn, game_id, player, parent, 0, m).await?);
+        }
+
+        Self::fetch(conn, game_id, player).await
+    }
+
+    async fn fetch(
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        player: PlayerId,
+    ) -> anyhow::Result<Self> {
+        let rows: Vec<VariationRow> = sqlx::query_as(
+            "
+    select id, parent_id, rank, from_column, from_row, to_column, to_row, promotion, dropped
+    from variations
+    where game_id = ? and player_id = ?
+    order by rank asc, id asc;
+    ",
+        )
+        .bind(game_id)
+        .bind(player.0.hyphenated())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let nodes = rows
+            .into_iter()
+            .map(|row| Node {
+                id: row.id,
+                parent: row.parent_id,
+                rank: row.rank,
+                m: Move {
+                    from: (row.from_column, row.from_row).into(),
+                    to: (row.to_column, row.to_row).into(),
+                    promotion: row
+                        .promotion
+                        .and_then(|promotion| promotion.chars().next())
+                        .and_then(PieceKind::parse),
+                    drop: row
+                        .dropped
+                        .and_then(|dropped| dropped.chars().next())
+                        .and_then(PieceKind::parse),
+                },
+            })
+            .collect();
+
+        Ok(Self { nodes })
+    }
+
+    pub fn get(&self, id: i64) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// the moves from the position after `parent`, the main line first
+    pub fn children(&self, parent: Option<i64>) -> Vec<&Node> {
+        // the nodes are already in order of rank
+        self.nodes
+            .iter()
+            .filter(|node| node.parent == parent)
+            .collect()
+    }
+
+    /// the moves from the start up to and including `id`
+    pub fn line(&self, id: Option<i64>) -> Vec<Move> {
+        let mut line = vec![];
+
+        let mut node = id.and_then(|id| self.get(id));
+
+        while let Some(current) = node {
+            line.push(current.m);
+            node = current.parent.and_then(|parent| self.get(parent));
+        }
+
+        line.reverse();
+
+        line
+    }
+
+    /// the last move of the main line, `None` if there are no moves at all
+    pub fn main_line_end(&self) -> Option<i64> {
+        let mut end = None;
+
+        while let Some(next) = self.children(end).first() {
+            end = Some(next.id);
+        }
+
+        end
+    }
+
+    /// whether every move up to and including `id` is the main line
+    pub fn is_main_line(&self, id: i64) -> bool {
+        let mut node = self.get(id);
+
+        while let Some(current) = node {
+            if self.children(current.parent).first().map(|first| first.id) != Some(current.id) {
+                return false;
+            }
+
+            node = current.parent.and_then(|parent| self.get(parent));
+        }
+
+        true
+    }
+
+    /// add `m` after `parent` as the last sideline, returning its id.
+    /// a move that is already there is not added again.
+    pub async fn add(
+        &self,
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        player: PlayerId,
+        parent: Option<i64>,
+        m: &Move,
+    ) -> anyhow::Result<i64> {
+        let siblings = self.children(parent);
+
+        if let Some(existing) = siblings.iter().find(|sibling| sibling.m == *m) {
+            return Ok(existing.id);
+        }
+
+        let rank = siblings.last().map_or(0, |last| last.rank + 1);
+
+        insert(conn, game_id, player, parent, rank, m).await
+    }
+
+    /// make the line up to `id` the main line,
+    /// by putting each of its moves ahead of the others from the same position
+    pub async fn promote(
+        &self,
+        conn: &mut SqliteConnection,
+        game_id: Uuid,
+        player: PlayerId,
+        id: i64,
+    ) -> anyhow::Result<()> {
+        let mut node = self.get(id);
+
+        while let Some(current) = node {
+            if let Some(first) = self.children(current.parent).first()
+                && first.id != current.id
+            {
+                sqlx::query(
+                    "update variations set rank = ? where id = ? and game_id = ? and player_id = ?;",
+                )
+                .bind(first.rank - 1)
+                .bind(current.id)
+                .bind(game_id)
+                .bind(player.0.hyphenated())
+                .execute(&mut *conn)
+                .await?;
+            }
+
+            node = current.parent.and_then(|parent| self.get(parent));
+        }
+
+        Ok(())
+    }
+
+    /// the whole tree as a move list,
+    /// with each sideline right after the main line move it replaces
+    pub fn tokens(&self, variant: Variant, starting_fen: &str) -> anyhow::Result<Vec<Token>> {
+        let (board, to_move) = analysis::replay(variant, starting_fen, &[])?;
+
+        let (number, _) = pgn::move_number(starting_fen);
+
+        let mut tokens = vec![];
+
+        self.walk(&board, to_move, None, number, true, &mut tokens);
+
+        Ok(tokens)
+    }
+
+    /// the moves from `board`, the position after `parent`
+    fn walk(
+        &self,
+        board: &Board,
+        to_move: Color,
+        parent: Option<i64>,
+        number: u32,
+        needs_number: bool,
+        tokens: &mut Vec<Token>,
+    ) {
+        let children = self.children(parent);
+
+        let Some((main, sidelines)) = children.split_first() else {
+            return;
+        };
+
+        let token = |node: &Node, needs_number: bool| Token::Move {
+            id: node.id,
+            number: match to_move {
+                Color::White => Some(format!("{number}.")),
+                Color::Black if needs_number => Some(format!("{number}...")),
+                Color::Black => None,
+            },
+            san: pgn::san(board, to_move, &node.m),
+        };
+
+        let next_number = match to_move {
+            Color::White => number,
+            Color::Black => number + 1,
+        };
+
+        let after = |node: &Node| {
+            let mut board = board.clone();
+            board.make_move(&node.m);
+            board
+        };
+
+        tokens.push(token(main, needs_number));
+
+        for sideline in sidelines {
+            tokens.push(Token::Open);
+            tokens.push(token(sideline, true));
+
+            self.walk(
+                &after(sideline),
+                to_move.invert(),
+                Some(sideline.id),
+                next_number,
+                false,
+                tokens,
+            );
+
+            tokens.push(Token::Close);
+        }
+
+        // after a sideline, the main line picks up again with its move number
+        self.walk(
+            &after(main),
+            to_move.invert(),
+            Some(main.id),
+            next_number,
+            !sidelines.is_empty(),
+            tokens,
+        );
+    }
+
+    /// the tree as PGN movetext, with the sidelines in parentheses
+    pub fn movetext(
+        &self,
+        variant: Variant,
+        starting_fen: &str,
+        result: &str,
+    ) -> anyhow::Result<String> {
+        let mut words: Vec<String> = vec![];
+
+        let mut opening = false;
+
+        for token in self.tokens(variant, starting_fen)? {
+            match token {
+                Token::Move { number, san, .. } => {
+                    let paren = if opening { "(" } else { "" };
+
+                    match number {
+                        Some(number) => {
+                            words.push(format!("{paren}{number}"));
+                            words.push(san);
+                        }
+                        None => words.push(format!("{paren}{san}")),
+                    }
+
+                    opening = false;
+                }
+                Token::Open => opening = true,
+                Token::Close => {
+                    if let Some(word) = words.last_mut() {
+                        word.push(')');
+                    }
+                }
+            }
+        }
+
+        words.push(result.to_string());
+
+        Ok(pgn::wrap(words))
+    }
+}
+
+async fn insert(
+    conn: &mut SqliteConnection,
+    game_id: Uuid,
+    player: PlayerId,
+    parent: Option<i64>,
+    rank: i64,
+    m: &Move,
+) -> anyhow::Result<i64> {
+    let (id,): (i64,) = sqlx::query_as(
+        "
+    insert into variations
+    (game_id, player_id, parent_id, rank, from_column, from_row, to_column, to_row, promotion, dropped)
+    values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    returning id;
+    ",
+    )
+    .bind(game_id)
+    .bind(player.0.hyphenated())
+    .bind(parent)
+    .bind(rank)
+    .bind(m.from.column)
+    .bind(m.from.row)
+    .bind(m.to.column)
+    .bind(m.to.row)
+    .bind(m.promotion.map(|kind| kind.letter().to_string()))
+    .bind(m.drop.map(|kind| kind.letter().to_string()))
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(id)
+}
+
+/// a game's PGN with the lines tried out from it in place of the moves played
+pub struct AnalysisPgn {
+    pub pgn: Pgn,
+    pub movetext: String,
+}
+
+impl fmt::Display for AnalysisPgn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.pgn.fmt_tags(f)?;
+
+        writeln!(f, "{}", self.movetext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::STARTING_FEN;
+    use sqlx::Connection;
+
+    fn moves(uci: &[&str]) -> Vec<Move> {
+        uci.iter().map(|m| Move::parse_uci(m).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn sidelines_are_added_written_and_promoted() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&mut conn).await.unwrap();
+
+        let game_id = Uuid::new_v4();
+        let player = PlayerId(Uuid::new_v4());
+
+        sqlx::query("insert into games (id) values (?);")
+            .bind(game_id)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let played = moves(&["e2e4", "e7e5", "g1f3"]);
+
+        let tree = Tree::load(&mut conn, game_id, player, &played)
+            .await
+            .unwrap();
+        let e4 = tree.children(None)[0].id;
+        let main_end = tree.main_line_end().unwrap();
+
+        assert_eq!(tree.line(Some(main_end)), played);
+
+        let c5 = Move::parse_uci("c7c5").unwrap();
+        let nf3 = Move::parse_uci("g1f3").unwrap();
+
+        let sideline = tree
+            .add(&mut conn, game_id, player, Some(e4), &c5)
+            .await
+            .unwrap();
+        let tree = Tree::load(&mut conn, game_id, player, &played)
+            .await
+            .unwrap();
+
+        // the same move from the same position is the same node
+        assert_eq!(
+            tree.add(&mut conn, game_id, player, Some(e4), &c5)
+                .await
+                .unwrap(),
+            sideline
+        );
+
+        let sideline_end = tree
+            .add(&mut conn, game_id, player, Some(sideline), &nf3)
+            .await
+            .unwrap();
+        let tree = Tree::load(&mut conn, game_id, player, &played)
+            .await
+            .unwrap();
+
+        assert!(tree.is_main_line(main_end));
+        assert!(!tree.is_main_line(sideline_end));
+        assert_eq!(
+            tree.movetext(Variant::Standard, STARTING_FEN, "*").unwrap(),
+            "1. e4 e5 (1... c5 2. Nf3) 2. Nf3 *"
+        );
+
+        tree.promote(&mut conn, game_id, player, sideline_end)
+            .await
+            .unwrap();
+        let tree = Tree::load(&mut conn, game_id, player, &played)
+            .await
+            .unwrap();
+
+        assert_eq!(tree.main_line_end(), Some(sideline_end));
+        assert_eq!(
+            tree.movetext(Variant::Standard, STARTING_FEN, "*").unwrap(),
+            "1. e4 c5 (1... e5 2. Nf3) 2. Nf3 *"
+        );
+    }
+
+    #[test]
+    fn black_to_move_starts_with_an_ellipsis() {
+        let tree = Tree {
+            nodes: vec![Node {
+                id: 1,
+                parent: None,
+                m: Move::parse_uci("e8d8").unwrap(),
+                rank: 0,
+            }],
+        };
+
+        assert_eq!(
+            tree.movetext(Variant::Standard, "4k3/8/8/8/8/8/8/4K3 b - - 0 30", "*")
+                .unwrap(),
+            "30... Kd8 *"
+        );
+    }
+}